@@ -0,0 +1,26 @@
+-- Balances are micro-dollars, which overflow an integer at $2147
+ALTER TABLE users ALTER COLUMN balance TYPE BIGINT;
+
+-- Every change to a balance. users.balance is the running sum and is only ever updated in the
+-- same transaction as an insert here.
+CREATE TABLE balance_transactions (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    api_key_id INTEGER REFERENCES api_keys(id) ON DELETE SET NULL,
+    amount BIGINT NOT NULL,
+    kind VARCHAR NOT NULL CHECK (kind IN ('charge', 'topup', 'refund', 'adjustment')),
+    -- OneLLM request id for charges, Stripe checkout session id for topups
+    request_id VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX balance_transactions_user_created ON balance_transactions (user_id, created_at);
+
+-- Makes replayed Stripe webhooks no-ops
+CREATE UNIQUE INDEX balance_transactions_kind_request
+    ON balance_transactions (kind, request_id)
+    WHERE request_id IS NOT NULL;
+
+-- Opening entries so the ledger adds up to the balances that existed before it
+INSERT INTO balance_transactions (user_id, amount, kind)
+SELECT id, balance, 'adjustment' FROM users WHERE balance <> 0;
@@ -0,0 +1,12 @@
+-- Balance reserved for requests still in flight. users.held is the sum of the user's rows in
+-- balance_holds, so balance - held is what new requests can still reserve.
+ALTER TABLE users ADD COLUMN held BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE balance_holds (
+    request_id VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    amount BIGINT NOT NULL CHECK (amount >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX balance_holds_created ON balance_holds (created_at);
@@ -0,0 +1,21 @@
+-- One row per API request that reached a provider, successful or not
+CREATE TABLE usage_events (
+    id BIGSERIAL PRIMARY KEY,
+    request_id VARCHAR NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    api_key_id INTEGER REFERENCES api_keys(id) ON DELETE SET NULL,
+    model VARCHAR NOT NULL,
+    provider VARCHAR NOT NULL,
+    input_tokens INTEGER NOT NULL DEFAULT 0,
+    output_tokens INTEGER NOT NULL DEFAULT 0,
+    -- Micro-dollars charged, matching the balance_transactions row of the request
+    cost BIGINT NOT NULL DEFAULT 0,
+    latency_ms INTEGER NOT NULL,
+    finish_reason VARCHAR,
+    -- HTTP status returned to the caller
+    status SMALLINT NOT NULL,
+    error VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX usage_events_user_created ON usage_events (user_id, created_at);
@@ -0,0 +1,12 @@
+-- Optional caps on what a single key may spend, in micro-dollars per UTC day and month, and the
+-- OneLLM names of the models it may use. NULL means unrestricted.
+ALTER TABLE api_keys
+    ADD COLUMN daily_limit BIGINT CHECK (daily_limit >= 0),
+    ADD COLUMN monthly_limit BIGINT CHECK (monthly_limit >= 0),
+    ADD COLUMN allowed_models VARCHAR[];
+
+-- So what a key has in flight counts towards its caps
+ALTER TABLE balance_holds
+    ADD COLUMN api_key_id INTEGER REFERENCES api_keys(id) ON DELETE CASCADE;
+
+CREATE INDEX usage_events_key_created ON usage_events (api_key_id, created_at);
@@ -0,0 +1,14 @@
+-- Keys are stored as the SHA-256 of the full key. Keys issued from now on look like
+-- oa-live-<public_id>-<secret> and are found by public_id; older ones have no public_id and
+-- are found by their hash.
+ALTER TABLE api_keys
+    ADD COLUMN public_id VARCHAR UNIQUE,
+    ADD COLUMN key_hash VARCHAR;
+
+UPDATE api_keys SET key_hash = encode(sha256(convert_to(key, 'UTF8')), 'hex');
+
+ALTER TABLE api_keys
+    ALTER COLUMN key_hash SET NOT NULL,
+    DROP COLUMN key;
+
+CREATE UNIQUE INDEX api_keys_key_hash ON api_keys (key_hash);
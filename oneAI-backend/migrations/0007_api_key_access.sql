@@ -0,0 +1,12 @@
+ALTER TABLE api_keys
+    ADD COLUMN expires_at TIMESTAMPTZ,
+    ADD COLUMN last_used_at TIMESTAMPTZ,
+    -- NULL for full access, otherwise e.g. {chat,usage:read,provider:openai}
+    ADD COLUMN scopes VARCHAR[],
+    -- The key replaced by the last rotation, still accepted until previous_expires_at
+    ADD COLUMN previous_public_id VARCHAR,
+    ADD COLUMN previous_key_hash VARCHAR,
+    ADD COLUMN previous_expires_at TIMESTAMPTZ;
+
+CREATE INDEX api_keys_previous_public_id ON api_keys (previous_public_id);
+CREATE INDEX api_keys_previous_key_hash ON api_keys (previous_key_hash);
@@ -0,0 +1,69 @@
+-- Balances and API keys belong to organizations. Every user has a personal organization, which
+-- is where balances and keys that existed before are moved.
+CREATE TABLE organizations (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    balance BIGINT NOT NULL DEFAULT 0,
+    held BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Only used while migrating
+    personal_user_id INTEGER
+);
+
+CREATE TABLE organization_members (
+    organization_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role VARCHAR NOT NULL CHECK (role IN ('owner', 'admin', 'member', 'billing')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (organization_id, user_id)
+);
+
+CREATE INDEX organization_members_user ON organization_members (user_id);
+
+-- Invited emails become members once they accept
+CREATE TABLE organization_invites (
+    organization_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    email VARCHAR NOT NULL,
+    role VARCHAR NOT NULL CHECK (role IN ('admin', 'member', 'billing')),
+    invited_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (organization_id, email)
+);
+
+INSERT INTO organizations (name, balance, held, personal_user_id)
+SELECT email, balance, held, id FROM users;
+
+INSERT INTO organization_members (organization_id, user_id, role)
+SELECT id, personal_user_id, 'owner' FROM organizations;
+
+ALTER TABLE users ADD COLUMN personal_organization_id INTEGER REFERENCES organizations(id);
+UPDATE users u SET personal_organization_id = o.id
+FROM organizations o WHERE o.personal_user_id = u.id;
+
+-- api_keys.user_id, balance_transactions.user_id and balance_holds.user_id now record who
+-- created the key or made the change
+ALTER TABLE api_keys
+    ADD COLUMN organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE;
+UPDATE api_keys a SET organization_id = u.personal_organization_id
+FROM users u WHERE u.id = a.user_id;
+ALTER TABLE api_keys ALTER COLUMN organization_id SET NOT NULL;
+
+ALTER TABLE balance_transactions
+    ADD COLUMN organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE;
+UPDATE balance_transactions t SET organization_id = u.personal_organization_id
+FROM users u WHERE u.id = t.user_id;
+ALTER TABLE balance_transactions ALTER COLUMN organization_id SET NOT NULL;
+CREATE INDEX balance_transactions_organization_created
+    ON balance_transactions (organization_id, created_at);
+
+ALTER TABLE balance_holds
+    ADD COLUMN organization_id INTEGER REFERENCES organizations(id) ON DELETE CASCADE;
+UPDATE balance_holds h SET organization_id = u.personal_organization_id
+FROM users u WHERE u.id = h.user_id;
+ALTER TABLE balance_holds ALTER COLUMN organization_id SET NOT NULL;
+
+ALTER TABLE users
+    ALTER COLUMN personal_organization_id SET NOT NULL,
+    DROP COLUMN balance,
+    DROP COLUMN held;
+ALTER TABLE organizations DROP COLUMN personal_user_id;
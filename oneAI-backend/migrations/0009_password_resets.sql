@@ -0,0 +1,9 @@
+-- Single-use tokens mailed by the forgot-password flow. Only their SHA-256 is stored.
+CREATE TABLE password_resets (
+    token_hash VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX password_resets_user ON password_resets (user_id);
@@ -0,0 +1,15 @@
+-- A session is one login. Its access token (`token`) is short-lived and replaced together
+-- with the refresh token each time the session is refreshed.
+ALTER TABLE sessions
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING created_at AT TIME ZONE 'UTC',
+    ALTER COLUMN expires_at TYPE TIMESTAMPTZ USING expires_at AT TIME ZONE 'UTC',
+    ADD COLUMN last_seen_at TIMESTAMPTZ,
+    ADD COLUMN user_agent VARCHAR,
+    ADD COLUMN ip VARCHAR,
+    ADD COLUMN refresh_token_hash VARCHAR UNIQUE,
+    -- The refresh token replaced by the last refresh, presenting it again revokes the session
+    ADD COLUMN previous_refresh_token_hash VARCHAR,
+    ADD COLUMN refresh_expires_at TIMESTAMPTZ;
+
+CREATE INDEX sessions_user ON sessions (user_id);
+CREATE INDEX sessions_previous_refresh_token_hash ON sessions (previous_refresh_token_hash);
@@ -0,0 +1,15 @@
+-- Authenticator app second factor. The secret is encrypted with AES-256-GCM (nonce followed by
+-- ciphertext) and only required at login once `totp_enabled_at` is set.
+ALTER TABLE users
+    ADD COLUMN totp_secret BYTEA,
+    ADD COLUMN totp_enabled_at TIMESTAMPTZ,
+    -- 30 second step of the last code accepted, so a code can't be used twice
+    ADD COLUMN totp_last_step BIGINT;
+
+-- Single-use codes for when the authenticator is lost. Only their SHA-256 is stored.
+CREATE TABLE totp_recovery_codes (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, code_hash)
+);
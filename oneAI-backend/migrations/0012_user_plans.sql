@@ -0,0 +1,3 @@
+-- Picks the rate limits of the user's API keys from `limits.plans` in the config. Plans the
+-- config doesn't define fall back to `free`.
+ALTER TABLE users ADD COLUMN plan VARCHAR NOT NULL DEFAULT 'free';
@@ -0,0 +1,6 @@
+-- Deleting a key used to delete the holds of its requests in flight, leaving
+-- organizations.held raised for good. They are now settled or released as usual.
+ALTER TABLE balance_holds
+    DROP CONSTRAINT balance_holds_api_key_id_fkey,
+    ADD CONSTRAINT balance_holds_api_key_id_fkey
+        FOREIGN KEY (api_key_id) REFERENCES api_keys(id) ON DELETE SET NULL;
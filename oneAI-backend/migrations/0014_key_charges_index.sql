@@ -0,0 +1,3 @@
+-- Key budgets sum the charges of the key since the start of the month
+CREATE INDEX balance_transactions_key_created ON balance_transactions (api_key_id, created_at)
+    WHERE kind = 'charge';
@@ -1,19 +1,33 @@
 use password_auth::{generate_hash, verify_password};
-use rand::Rng;
+use rand::{Rng, distr::Alphanumeric};
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use std::error::Error;
 
-use crate::utils::*;
+use crate::{auth::twofa, utils::*};
 
-pub async fn login(pool: Option<PgPool>, email: String, password: String) -> Option<User> {
+/// Checks the password and, for users who turned it on, the two-factor `code`. Whether a
+/// code is missing or wrong is only told once the password was right.
+pub async fn login(
+    pool: &PgPool,
+    redis: &mut ConnectionManager,
+    email: String,
+    password: String,
+    code: Option<&str>,
+    totp_key: Option<&str>,
+) -> Result<User, Box<dyn Error>> {
     let user = match User::get_row(pool, email).await {
         Ok(a) => a,
-        Err(_) => return None,
+        Err(_) => return Err("Could not log user in".into()),
     };
 
-    match verify_password(password, user.password.as_str()) {
-        Ok(_) => Some(user),
-        Err(_) => None,
+    if verify_password(password, user.password.as_str()).is_err() {
+        return Err("Could not log user in".into());
     }
+    twofa::verify_second_factor(pool, redis, totp_key, &user, code).await?;
+
+    Ok(user)
 }
 
 pub async fn signup(email: String, password: String) -> Option<User> {
@@ -21,39 +35,36 @@ pub async fn signup(email: String, password: String) -> Option<User> {
         id: 0,
         email,
         password,
-        balance: 0,
         verified: false,
+        // Created along with the user by `new_user`
+        personal_organization_id: 0,
     };
     hash_user(&mut user);
 
     Some(user)
 }
 
-pub async fn update_bal(pool: Option<PgPool>, email: String, change: i32) -> Option<User> {
-    let user = match User::get_row(pool.clone(), email).await {
-        Ok(a) => a,
-        Err(_) => {
-            return None;
-        }
-    };
+impl ApiKey {
+    /// Whether the key may do `scope`. Keys without scopes may do everything.
+    pub fn allows(&self, scope: Scope) -> bool {
+        let Some(scopes) = &self.access.scopes else {
+            return true;
+        };
 
-    match user
-        .update_db(
-            pool,
-            TableFields::Balance,
-            &(user.balance as i32 + change).to_string(),
-        )
-        .await
-    {
-        Ok(_) => Some(user),
-        Err(_) => None,
+        match scope {
+            // Provider scopes only narrow down keys that have at least one
+            Scope::Provider(_) => {
+                scopes.contains(&scope) || !scopes.iter().any(|s| matches!(s, Scope::Provider(_)))
+            }
+            _ => scopes.contains(&scope),
+        }
     }
 }
 
 impl HiddenUser {
-    pub async fn from_user(user: &mut User) -> Self {
+    /// `balance` is the one of the user's personal organization.
+    pub async fn from_user(user: &mut User, balance: i64) -> Self {
         let email = user.clone().email;
-        let balance = user.balance;
         drop(user.to_owned());
 
         Self { email, balance }
@@ -64,11 +75,60 @@ pub fn hash_user(user: &mut User) {
     user.password = generate_hash(&user.password)
 }
 
-pub fn generate_api() -> String {
-    let mut res = String::new();
-    for _ in 0..20 {
-        res.push_str((rand::rng().random_range(1..10) as u8).to_string().as_str());
-    }
+/// Prefix of every key issued in the current format.
+const API_KEY_PREFIX: &str = "oa-live-";
+
+/// A newly generated API key. `key` is shown to the user once, only `public_id` and the hash
+/// of `key` are stored.
+pub struct NewApiKey {
+    pub key: String,
+    pub public_id: String,
+}
+
+/// Generates `oa-live-<public_id>-<secret>`, with a 12 character identifier and a 32
+/// character (about 190 bit) secret.
+pub fn generate_api() -> NewApiKey {
+    let public_id = random_string(12).to_lowercase();
+    let key = format!("{}{}-{}", API_KEY_PREFIX, public_id, random_string(32));
+
+    NewApiKey { key, public_id }
+}
+
+/// A random 32 character token, used for password reset links and session refresh tokens.
+/// Stored hashed like API keys.
+pub fn generate_token() -> String {
+    random_string(32)
+}
+
+fn random_string(len: usize) -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex SHA-256 of an API key or a token from `generate_token`, as stored in
+/// `api_keys.key_hash`, `password_resets.token_hash` and `sessions.refresh_token_hash`. They
+/// are all random enough that no salt or slow hash is needed.
+pub fn hash_api(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The identifier of a key in the current format, `None` for malformed keys and keys issued
+/// before it.
+pub fn api_public_id(key: &str) -> Option<&str> {
+    let (public_id, secret) = key.strip_prefix(API_KEY_PREFIX)?.split_once('-')?;
+    let valid = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric());
+
+    (valid(public_id) && valid(secret)).then_some(public_id)
+}
 
-    format!("oa-{}", res)
+/// Compares two hashes without leaking where they differ through timing.
+pub fn hashes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
 }
@@ -1,93 +1,469 @@
-use lettre::{
-    Message, SmtpTransport, Transport,
-    message::{Mailbox, header::ContentType},
-    transport::smtp::authentication::Credentials,
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use rand::{Rng, distr::Alphanumeric};
+use redis::{AsyncCommands, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{
+    error::Error,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use redis::AsyncCommands;
-use std::error::Error;
 
 use totp_rs::{Algorithm, Secret, TOTP};
 
+use crate::{
+    auth::basicauth::{hash_api, hashes_match},
+    mail::{self, Email, Mailer},
+    utils::User,
+};
+
+/// Seconds each authenticator code is valid for, the default of every app.
+const TOTP_STEP: u64 = 30;
+/// Codes from one step before or after are accepted too, for clocks that drift.
+const TOTP_SKEW: u8 = 1;
+/// How many recovery codes are issued at once.
+const RECOVERY_CODES: usize = 10;
+/// Seconds an emailed verification code is valid for.
+const VERIFY_CODE_TTL: u64 = 15 * 60;
+/// Seconds before another verification code can be sent to the same email.
+const VERIFY_RESEND_COOLDOWN: u64 = 60;
+/// Wrong verification codes accepted per email within `VERIFY_CODE_TTL`.
+const VERIFY_MAX_ATTEMPTS: u64 = 5;
+/// Wrong two-factor codes accepted per user before they are locked out.
+const TOTP_MAX_ATTEMPTS: u64 = 5;
+/// Seconds a user stays locked out after `TOTP_MAX_ATTEMPTS` wrong two-factor codes, counted
+/// from the first.
+const TOTP_LOCKOUT: u64 = 15 * 60;
+/// Seconds before another password reset can be asked for the same email or from the same
+/// address.
+const RESET_COOLDOWN: u64 = 60;
+
+/// Emails a new code confirming the address belongs to the user. Replaces a code sent before,
+/// but only once every `VERIFY_RESEND_COOLDOWN`.
 pub async fn send_verify(
-    redis: &mut redis::aio::MultiplexedConnection,
+    redis: &mut ConnectionManager,
+    mailer: &dyn Mailer,
     email: &str,
 ) -> Result<(), Box<dyn Error>> {
-    dotenv::dotenv().ok();
+    if !start_cooldown(
+        redis,
+        &verify_key("cooldown", email),
+        VERIFY_RESEND_COOLDOWN,
+    )
+    .await?
+    {
+        return Err("A code was just sent, please wait a minute before asking for another.".into());
+    }
 
-    let secret = Secret::generate_secret().to_bytes().unwrap();
-    let encoded = Secret::Raw(secret.clone()).to_encoded();
+    let code = format!("{:06}", rand::rng().random_range(0..1_000_000));
+    let _: () = redis
+        .set_ex(verify_key("code", email), hash_api(&code), VERIFY_CODE_TTL)
+        .await?;
 
-    let secret_str = match encoded {
-        Secret::Encoded(s) => s,
-        Secret::Raw(_) => panic!("Expected an encoded secret, but got raw"),
-    };
-    let _: () = redis.set(email, secret_str).await?;
+    // Only the message is kept, a boxed error can't be held across the await below
+    let sent = mailer
+        .send(&Email {
+            to: email.to_string(),
+            subject: "Verification for OneLLM".to_string(),
+            html: mail::render(
+                include_str!("verify.html"),
+                &[
+                    ("CODE", &code),
+                    ("MINUTES", &(VERIFY_CODE_TTL / 60).to_string()),
+                ],
+            ),
+        })
+        .await
+        .map_err(|e| e.to_string());
+    if sent.is_err() {
+        // Nothing arrived, so asking again right away is fine
+        let _: () = redis.del(verify_key("cooldown", email)).await?;
+    }
 
-    let totp = TOTP::new(
-        Algorithm::SHA1,
-        6,  // 6 digits
-        1,  // step size (usually 1)
-        60, // time period in seconds
-        secret,
-        Some("OneLLM".to_string()), // issuer
-        email.to_string(),          // account name
+    Ok(sent?)
+}
+
+/// Whether a password reset may be sent to `email` for a client at `ip`. Neither can ask for
+/// another one until `RESET_COOLDOWN` has passed.
+pub async fn reset_allowed(
+    redis: &mut ConnectionManager,
+    email: &str,
+    ip: IpAddr,
+) -> Result<bool, redis::RedisError> {
+    Ok(
+        start_cooldown(redis, &format!("reset:cooldown:ip:{}", ip), RESET_COOLDOWN).await?
+            && start_cooldown(
+                redis,
+                &format!("reset:cooldown:email:{}", email.to_lowercase()),
+                RESET_COOLDOWN,
+            )
+            .await?,
     )
-    .unwrap();
-
-    let code = totp.generate_current().unwrap();
-
-    let body = include_str!("verify.html")
-        .replace("{{ YEAR }}", "2025")
-        .replace("{{ CODE }}", &code);
-    let email = Message::builder()
-        .from(Mailbox::new(
-            Some("OneLLM".to_owned()),
-            "OneLLM.dev@gmail.com".parse().unwrap(),
-        ))
-        .to(Mailbox::new(None, email.parse().unwrap()))
-        .subject("Verification for OneLLM")
-        .header(ContentType::TEXT_HTML)
-        .body(body)
-        .unwrap();
-
-    let creds = Credentials::new("OneLLM.dev@gmail.com".to_owned(), std::env::var("GMAIL")?);
-
-    // Open a remote connection to gmail
-    let mailer = SmtpTransport::relay("smtp.gmail.com")
-        .unwrap()
-        .credentials(creds)
-        .build();
-
-    // Send the email
-    match mailer.send(&email) {
-        Ok(_) => Ok(()),
-        Err(e) => panic!("Could not send email: {e:?}"),
-    }
 }
 
+/// Sets `key` for `secs` unless it is already set. Returns whether it was, i.e. whether the
+/// cooldown it stands for had passed.
+async fn start_cooldown(
+    redis: &mut ConnectionManager,
+    key: &str,
+    secs: u64,
+) -> Result<bool, redis::RedisError> {
+    let set: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(secs)
+        .query_async(redis)
+        .await?;
+    Ok(set.is_some())
+}
+
+/// Mails `link`, which carries a single-use password reset token.
+pub async fn send_password_reset(
+    mailer: &dyn Mailer,
+    email: &str,
+    link: &str,
+) -> Result<(), Box<dyn Error>> {
+    mailer
+        .send(&Email {
+            to: email.to_string(),
+            subject: "Reset your OneLLM password".to_string(),
+            html: mail::render(include_str!("reset.html"), &[("LINK", link)]),
+        })
+        .await
+}
+
+/// Checks a code sent by `send_verify`. A correct code can only be used once. After
+/// `VERIFY_MAX_ATTEMPTS` wrong ones the email is locked out until `VERIFY_CODE_TTL` has passed
+/// since the first, sending a new code doesn't reset that.
 pub async fn verify_code(
-    redis: &mut redis::aio::MultiplexedConnection,
+    redis: &mut ConnectionManager,
     email: &str,
     user_code: &str,
 ) -> Result<bool, Box<dyn Error>> {
-    let secret_base32: String = match redis.get(email).await.ok() {
-        Some(a) => a,
-        None => return Err("No secret found for provided user".into()),
+    let code_key = verify_key("code", email);
+    let attempts_key = verify_key("attempts", email);
+
+    let attempts: u64 = redis.incr(&attempts_key, 1).await?;
+    if attempts == 1 {
+        let _: () = redis.expire(&attempts_key, VERIFY_CODE_TTL as i64).await?;
+    }
+    if attempts > VERIFY_MAX_ATTEMPTS {
+        let _: () = redis.del(&code_key).await?;
+        return Err("Too many wrong codes, please try again later.".into());
+    }
+
+    let stored: Option<String> = redis.get(&code_key).await?;
+    let Some(stored) = stored else {
+        return Err("No code was sent to this email or it expired.".into());
+    };
+
+    if !hashes_match(&hash_api(user_code.trim()), &stored) {
+        return Ok(false);
+    }
+
+    let _: () = redis.del(&[&code_key, &attempts_key]).await?;
+    Ok(true)
+}
+
+fn verify_key(kind: &str, email: &str) -> String {
+    format!("verify:{}:{}", kind, email.to_lowercase())
+}
+
+/// What an authenticator app needs to be set up. Nothing is required at login until the
+/// enrolment is confirmed with `confirm_totp`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TotpEnrollment {
+    /// Base32, for typing into the app by hand.
+    pub secret: String,
+    pub otpauth_url: String,
+    /// `otpauth_url` as a QR code, a base64 encoded PNG.
+    pub qr_png: String,
+}
+
+/// Generates a new TOTP secret for the user, replacing one that wasn't confirmed yet.
+pub async fn start_totp(
+    pool: &PgPool,
+    totp_key: Option<&str>,
+    user: &User,
+) -> Result<TotpEnrollment, Box<dyn Error>> {
+    let cipher = cipher(totp_key)?;
+    let secret = Secret::generate_secret().to_bytes()?;
+    let totp = authenticator(secret.clone(), &user.email)?;
+    let encrypted = encrypt(&cipher, user.id, &secret)?;
+
+    let stored = sqlx::query(
+        "UPDATE users SET totp_secret = $1, totp_last_step = NULL \
+         WHERE id = $2 AND totp_enabled_at IS NULL",
+    )
+    .bind(encrypted)
+    .bind(user.id)
+    .execute(pool)
+    .await?;
+    if stored.rows_affected() == 0 {
+        return Err("Two-factor authentication is already enabled".into());
+    }
+
+    Ok(TotpEnrollment {
+        secret: totp.get_secret_base32(),
+        otpauth_url: totp.get_url(),
+        qr_png: totp.get_qr_base64()?,
+    })
+}
+
+/// Turns two-factor authentication on once the user proves their app works, and returns their
+/// recovery codes.
+pub async fn confirm_totp(
+    pool: &PgPool,
+    totp_key: Option<&str>,
+    user: &User,
+    code: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let (secret, enabled) = totp_state(pool, user.id).await?;
+    if enabled {
+        return Err("Two-factor authentication is already enabled".into());
+    }
+    let secret = secret.ok_or("Two-factor authentication was not set up")?;
+    let totp = authenticator(decrypt(&cipher(totp_key)?, user.id, &secret)?, &user.email)?;
+    let step = matching_step(&totp, code).ok_or("Invalid two-factor code")?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE users SET totp_enabled_at = NOW(), totp_last_step = $1 WHERE id = $2")
+        .bind(step as i64)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+    let codes = replace_recovery_codes(&mut tx, user.id).await?;
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+/// Turns two-factor authentication off, `code` being a current code or a recovery code.
+pub async fn disable_totp(
+    pool: &PgPool,
+    redis: &mut ConnectionManager,
+    totp_key: Option<&str>,
+    user: &User,
+    code: &str,
+) -> Result<(), Box<dyn Error>> {
+    require_enabled(pool, user.id).await?;
+    verify_second_factor(pool, redis, totp_key, user, Some(code)).await?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL \
+         WHERE id = $1",
+    )
+    .bind(user.id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1")
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Replaces the user's recovery codes, for when they ran out or were lost.
+pub async fn new_recovery_codes(
+    pool: &PgPool,
+    redis: &mut ConnectionManager,
+    totp_key: Option<&str>,
+    user: &User,
+    code: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    require_enabled(pool, user.id).await?;
+    verify_second_factor(pool, redis, totp_key, user, Some(code)).await?;
+
+    let mut tx = pool.begin().await?;
+    let codes = replace_recovery_codes(&mut tx, user.id).await?;
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+/// Checks the second factor of a login. Passes for users without two-factor authentication,
+/// otherwise `code` must be a code from their app that wasn't used yet, or one of their
+/// recovery codes, which is then used up. After `TOTP_MAX_ATTEMPTS` wrong codes the user is
+/// locked out until `TOTP_LOCKOUT` has passed since the first.
+pub async fn verify_second_factor(
+    pool: &PgPool,
+    redis: &mut ConnectionManager,
+    totp_key: Option<&str>,
+    user: &User,
+    code: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let (secret, enabled) = totp_state(pool, user.id).await?;
+    let Some(secret) = secret.filter(|_| enabled) else {
+        return Ok(());
     };
+    let code = code
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .ok_or("A two-factor code is required")?;
+
+    let attempts_key = format!("totp:attempts:{}", user.id);
+    let attempts: u64 = redis.incr(&attempts_key, 1).await?;
+    if attempts == 1 {
+        let _: () = redis.expire(&attempts_key, TOTP_LOCKOUT as i64).await?;
+    }
+    if attempts > TOTP_MAX_ATTEMPTS {
+        return Err("Too many wrong codes, please try again later.".into());
+    }
 
-    let secret = Secret::Encoded(secret_base32).to_raw()?;
+    let totp = authenticator(decrypt(&cipher(totp_key)?, user.id, &secret)?, &user.email)?;
+    if let Some(step) = matching_step(&totp, code) {
+        let accepted = sqlx::query(
+            "UPDATE users SET totp_last_step = $1 \
+             WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+        )
+        .bind(step as i64)
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+        if accepted.rows_affected() == 1 {
+            let _: () = redis.del(&attempts_key).await?;
+            return Ok(());
+        }
+    }
+
+    let recovered =
+        sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1 AND code_hash = $2")
+            .bind(user.id)
+            .bind(hash_api(&normalize_recovery_code(code)))
+            .execute(pool)
+            .await?;
+    if recovered.rows_affected() == 1 {
+        let _: () = redis.del(&attempts_key).await?;
+        return Ok(());
+    }
+
+    Err("Invalid two-factor code".into())
+}
+
+/// The encrypted secret and whether it was confirmed.
+async fn totp_state(pool: &PgPool, user_id: i32) -> Result<(Option<Vec<u8>>, bool), sqlx::Error> {
+    sqlx::query_as("SELECT totp_secret, totp_enabled_at IS NOT NULL FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+}
+
+async fn require_enabled(pool: &PgPool, user_id: i32) -> Result<(), Box<dyn Error>> {
+    match totp_state(pool, user_id).await? {
+        (_, true) => Ok(()),
+        _ => Err("Two-factor authentication is not enabled".into()),
+    }
+}
+
+async fn replace_recovery_codes(
+    tx: &mut sqlx::PgConnection,
+    user_id: i32,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
 
-    let totp = TOTP::new(
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let code = generate_recovery_code();
+        sqlx::query("INSERT INTO totp_recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(hash_api(&normalize_recovery_code(&code)))
+            .execute(&mut *tx)
+            .await?;
+        codes.push(code);
+    }
+
+    Ok(codes)
+}
+
+/// `xxxx-xxxx-xxxx-xxxx`, lowercase letters and digits (about 80 bits).
+fn generate_recovery_code() -> String {
+    let chars: Vec<char> = rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+
+    chars
+        .chunks(4)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Recovery codes are accepted with or without dashes and in any case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn authenticator(secret: Vec<u8>, email: &str) -> Result<TOTP, Box<dyn Error>> {
+    Ok(TOTP::new(
         Algorithm::SHA1,
         6,
-        1,
-        60,
-        secret.to_bytes().unwrap(),
+        TOTP_SKEW,
+        TOTP_STEP,
+        secret,
         Some("OneLLM".to_string()),
         email.to_string(),
-    )
-    .unwrap();
+    )?)
+}
+
+/// The step `code` belongs to, if it's valid now give or take `TOTP_SKEW` steps.
+fn matching_step(totp: &TOTP, code: &str) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let skew = TOTP_SKEW as u64 * TOTP_STEP;
+
+    (now.saturating_sub(skew)..=now + skew)
+        .step_by(TOTP_STEP as usize)
+        .find(|time| hashes_match(&totp.generate(*time), code))
+        .map(|time| time / TOTP_STEP)
+}
+
+fn cipher(totp_key: Option<&str>) -> Result<Aes256Gcm, Box<dyn Error>> {
+    let key = totp_key.ok_or("Two-factor authentication is not configured on this server")?;
+    // The length was checked when the config was loaded
+    Aes256Gcm::new_from_slice(&hex::decode(key)?).map_err(|_| "Invalid auth.totp_key".into())
+}
+
+/// Encrypts a TOTP secret, bound to the user so it can't be copied to another account.
+fn encrypt(cipher: &Aes256Gcm, user_id: i32, secret: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut nonce = [0u8; 12];
+    rand::rng().fill(&mut nonce);
+    let payload = Payload {
+        msg: secret,
+        aad: &user_id.to_be_bytes(),
+    };
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| "Could not encrypt the TOTP secret")?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(cipher: &Aes256Gcm, user_id: i32, stored: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if stored.len() < 12 {
+        return Err("Stored TOTP secret is corrupted".into());
+    }
+    let (nonce, ciphertext) = stored.split_at(12);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &user_id.to_be_bytes(),
+    };
 
-    let is_valid = totp.check_current(user_code).unwrap_or(false);
-    Ok(is_valid)
+    cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| "Could not decrypt the TOTP secret, was auth.totp_key changed?".into())
 }
@@ -0,0 +1,563 @@
+//! `/v1/messages`, accepting and returning the Anthropic Messages wire format for every model
+//! so Anthropic SDKs can be pointed at OneLLM.
+use std::convert::Infallible;
+
+use axum::{
+    Json,
+    extract::State,
+    http::HeaderMap,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::{Stream, StreamExt, stream};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::mpsc;
+
+use crate::{
+    compat::response_id,
+    error::OneLlmError,
+    pricing::Model,
+    requests::{
+        parseapi::{
+            APIInput, ContentPart, Function, MediaSource, Message, MessageContent, Tool,
+            ToolChoice, ToolMode,
+        },
+        requests::Completion,
+        responseparser::{
+            anthropic::{ClaudeContent, ClaudeMessageResponse, ClaudeUsage},
+            common::{LlmUnifiedResponse, ToolCall},
+        },
+        stream::StreamEvent,
+    },
+    server::authenticate,
+    state::AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub max_tokens: u32,
+    pub messages: Vec<AnthropicMessage>,
+    pub system: Option<AnthropicContent>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    pub stream: Option<bool>,
+    pub metadata: Option<AnthropicMetadata>,
+    pub tools: Option<Vec<AnthropicTool>>,
+    pub tool_choice: Option<AnthropicToolChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: AnthropicContent,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<AnthropicBlock>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicBlock {
+    pub r#type: String,
+    pub text: Option<String>,
+    // image, document
+    pub source: Option<MediaSource>,
+    // tool_use
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub input: Option<Value>,
+    // tool_result
+    pub tool_use_id: Option<String>,
+    pub content: Option<AnthropicContent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicTool {
+    pub name: String,
+    pub description: Option<String>,
+    pub input_schema: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicToolChoice {
+    pub r#type: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicMetadata {
+    pub user_id: Option<String>,
+}
+
+impl AnthropicContent {
+    fn into_text(self) -> Result<String, String> {
+        match self {
+            AnthropicContent::Text(text) => Ok(text),
+            AnthropicContent::Blocks(blocks) => {
+                let mut text = Vec::new();
+                for block in blocks {
+                    match (block.r#type.as_str(), block.text) {
+                        ("text", Some(t)) => text.push(t),
+                        (other, _) => {
+                            return Err(format!("Unsupported content block type '{}'", other));
+                        }
+                    }
+                }
+                Ok(text.join("\n"))
+            }
+        }
+    }
+}
+
+impl AnthropicMessage {
+    /// Splits a message into ours. `tool_result` blocks each become a `tool` message, placed
+    /// before whatever else the user wrote since they answer the previous assistant turn.
+    fn into_messages(self) -> Result<Vec<Message>, String> {
+        let blocks = match self.content {
+            AnthropicContent::Text(text) => {
+                return Ok(vec![Message {
+                    role: self.role,
+                    content: MessageContent::Text(text),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                }]);
+            }
+            AnthropicContent::Blocks(blocks) => blocks,
+        };
+
+        let mut messages = Vec::new();
+        let mut parts = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in blocks {
+            match block.r#type.as_str() {
+                "text" => parts.push(ContentPart::Text {
+                    text: block.text.unwrap_or_default(),
+                }),
+                "image" => parts.push(ContentPart::Image {
+                    source: block.source.ok_or("image block is missing 'source'")?,
+                }),
+                "document" => parts.push(ContentPart::Document {
+                    source: block.source.ok_or("document block is missing 'source'")?,
+                }),
+                "tool_use" => tool_calls.push(ToolCall {
+                    id: block.id.ok_or("tool_use block is missing 'id'")?,
+                    name: block.name.ok_or("tool_use block is missing 'name'")?,
+                    arguments: block.input.unwrap_or_else(|| json!({})),
+                }),
+                "tool_result" => messages.push(Message {
+                    role: "tool".into(),
+                    content: match block.content {
+                        Some(content) => MessageContent::Text(content.into_text()?),
+                        None => MessageContent::default(),
+                    },
+                    tool_calls: Vec::new(),
+                    tool_call_id: Some(
+                        block
+                            .tool_use_id
+                            .ok_or("tool_result block is missing 'tool_use_id'")?,
+                    ),
+                }),
+                other => return Err(format!("Unsupported content block type '{}'", other)),
+            }
+        }
+
+        if !parts.is_empty() || !tool_calls.is_empty() || messages.is_empty() {
+            messages.push(Message {
+                role: self.role,
+                content: MessageContent::Parts(parts),
+                tool_calls,
+                tool_call_id: None,
+            });
+        }
+
+        Ok(messages)
+    }
+}
+
+impl MessagesRequest {
+    pub fn into_api_input(self, model: Model) -> Result<APIInput, String> {
+        let mut messages = Vec::new();
+        for msg in self.messages {
+            messages.extend(msg.into_messages()?);
+        }
+
+        // Sent as a leading system message, which each provider's translation moves to
+        // wherever that provider expects its system prompt
+        if let Some(system) = self.system {
+            messages.insert(
+                0,
+                Message {
+                    role: "system".into(),
+                    content: MessageContent::Text(system.into_text()?),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                },
+            );
+        }
+
+        let tools = self.tools.map(|tools| {
+            tools
+                .into_iter()
+                .map(|tool| Tool {
+                    r#type: "function".into(),
+                    function: Function {
+                        name: tool.name,
+                        description: tool.description.unwrap_or_default(),
+                        parameters: tool.input_schema,
+                    },
+                })
+                .collect()
+        });
+
+        let tool_choice = match self.tool_choice {
+            None => None,
+            Some(choice) => Some(match choice.r#type.as_str() {
+                "auto" => ToolChoice::Mode(ToolMode::Auto),
+                "none" => ToolChoice::Mode(ToolMode::None),
+                "any" => ToolChoice::Mode(ToolMode::Required),
+                "tool" => ToolChoice::Tool {
+                    name: choice
+                        .name
+                        .ok_or("tool_choice of type 'tool' needs a 'name'")?,
+                },
+                other => return Err(format!("Unsupported tool_choice type '{}'", other)),
+            }),
+        };
+
+        Ok(APIInput {
+            endpoint: None,
+            model,
+            temperature: self.temperature,
+            stream: self.stream,
+            messages,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p.unwrap_or(1.0),
+            stop_sequences: self.stop_sequences,
+            tools,
+            contents: None,
+            safety_settings: None,
+            generation_config: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            n: None,
+            response_format: None,
+            seed: None,
+            tool_choice,
+            user: self.metadata.and_then(|m| m.user_id),
+            logprobs: None,
+            top_logprobs: None,
+            system: None,
+            top_k: self.top_k,
+        })
+    }
+}
+
+/// Maps the finish reasons of the other providers onto Anthropic's `stop_reason` values.
+pub fn stop_reason(reason: &str) -> String {
+    match reason {
+        "stop" | "STOP" | "end_turn" => "end_turn",
+        "length" | "model_length" | "MAX_TOKENS" | "max_tokens" => "max_tokens",
+        "tool_calls" | "tool_use" => "tool_use",
+        "stop_sequence" => "stop_sequence",
+        "content_filter" | "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" => {
+            "refusal"
+        }
+        other => other,
+    }
+    .to_string()
+}
+
+fn error_response(err: OneLlmError) -> Response {
+    let error_type = match err {
+        OneLlmError::InvalidRequest(_) => "invalid_request_error",
+        OneLlmError::Auth(_) => "authentication_error",
+        OneLlmError::RateLimited(_) => "rate_limit_error",
+        OneLlmError::InsufficientBalance | OneLlmError::BudgetExceeded => "billing_error",
+        OneLlmError::Forbidden(_) => "permission_error",
+        _ => "api_error",
+    };
+
+    (
+        err.status(),
+        err.headers(),
+        Json(json!({
+            "type": "error",
+            "error": {
+                "type": error_type,
+                "message": err.to_string(),
+            }
+        })),
+    )
+        .into_response()
+}
+
+fn message_response(res: LlmUnifiedResponse, model: String) -> ClaudeMessageResponse {
+    let usage = res.usage.unwrap_or_default();
+
+    let mut content = Vec::new();
+    if !res.content.is_empty() || res.tool_calls.is_empty() {
+        content.push(ClaudeContent::Text { text: res.content });
+    }
+    for call in res.tool_calls {
+        content.push(ClaudeContent::ToolUse {
+            id: call.id,
+            name: call.name,
+            input: call.arguments,
+        });
+    }
+
+    ClaudeMessageResponse {
+        content,
+        id: response_id("msg_"),
+        model,
+        role: "assistant".into(),
+        stop_reason: res.finish_reason.as_deref().map(stop_reason),
+        stop_sequence: None,
+        message_type: "message".into(),
+        usage: ClaudeUsage {
+            input_tokens: usage.input_tokens.unwrap_or(0),
+            output_tokens: usage.output_tokens.unwrap_or(0),
+        },
+    }
+}
+
+/// An SSE event by its name and JSON data.
+pub type NamedEvent = (&'static str, Value);
+
+fn event(name: &'static str, data: Value) -> NamedEvent {
+    (name, data)
+}
+
+/// The content block currently open in a streamed response.
+#[derive(PartialEq)]
+enum OpenBlock {
+    Text,
+    /// A tool call, by the index the provider gave it.
+    Tool(u32),
+}
+
+pub struct EventState {
+    rx: mpsc::Receiver<StreamEvent>,
+    id: String,
+    model: String,
+    started: bool,
+    stop_reason: Option<String>,
+    open: Option<OpenBlock>,
+    /// Index of the next content block to start.
+    next_index: u32,
+}
+
+impl EventState {
+    pub fn new(rx: mpsc::Receiver<StreamEvent>, model: String) -> Self {
+        EventState {
+            rx,
+            id: response_id("msg_"),
+            model,
+            started: false,
+            stop_reason: None,
+            open: None,
+            next_index: 0,
+        }
+    }
+
+    fn close_block(&mut self, events: &mut Vec<NamedEvent>) {
+        if self.open.take().is_some() {
+            events.push(event(
+                "content_block_stop",
+                json!({ "type": "content_block_stop", "index": self.next_index - 1 }),
+            ));
+        }
+    }
+
+    /// Closes the open block, if any, and starts `content_block` as the next one.
+    fn start_block(
+        &mut self,
+        events: &mut Vec<NamedEvent>,
+        block: OpenBlock,
+        content_block: Value,
+    ) {
+        self.close_block(events);
+        events.push(event(
+            "content_block_start",
+            json!({
+                "type": "content_block_start",
+                "index": self.next_index,
+                "content_block": content_block,
+            }),
+        ));
+        self.open = Some(block);
+        self.next_index += 1;
+    }
+
+    fn block_delta(&self, events: &mut Vec<NamedEvent>, delta: Value) {
+        events.push(event(
+            "content_block_delta",
+            json!({
+                "type": "content_block_delta",
+                "index": self.next_index - 1,
+                "delta": delta,
+            }),
+        ));
+    }
+}
+
+/// Replays our unified chunks as Anthropic's event sequence: `message_start`, a text block for
+/// text and a `tool_use` block per tool call, then `message_delta` with the stop reason and
+/// usage once the final usage chunk arrives.
+pub fn message_events(state: EventState) -> impl Stream<Item = NamedEvent> {
+    stream::unfold(state, |mut state| async move {
+        let mut events = Vec::new();
+
+        if !state.started {
+            state.started = true;
+            events.push(event(
+                "message_start",
+                json!({
+                    "type": "message_start",
+                    "message": {
+                        "id": state.id,
+                        "type": "message",
+                        "role": "assistant",
+                        "content": [],
+                        "model": state.model,
+                        "stop_reason": null,
+                        "stop_sequence": null,
+                        "usage": { "input_tokens": 0, "output_tokens": 0 },
+                    }
+                }),
+            ));
+            return Some((events, state));
+        }
+
+        match state.rx.recv().await? {
+            StreamEvent::Chunk(chunk) => {
+                if let Some(reason) = chunk.finish_reason {
+                    state.stop_reason = Some(stop_reason(&reason));
+                }
+
+                if let Some(delta) = chunk.delta {
+                    if state.open != Some(OpenBlock::Text) {
+                        state.start_block(
+                            &mut events,
+                            OpenBlock::Text,
+                            json!({ "type": "text", "text": "" }),
+                        );
+                    }
+                    state.block_delta(&mut events, json!({ "type": "text_delta", "text": delta }));
+                }
+
+                for call in chunk.tool_calls {
+                    if call.id.is_some() || state.open != Some(OpenBlock::Tool(call.index)) {
+                        state.start_block(
+                            &mut events,
+                            OpenBlock::Tool(call.index),
+                            json!({
+                                "type": "tool_use",
+                                "id": call.id,
+                                "name": call.name,
+                                "input": {},
+                            }),
+                        );
+                    }
+                    if let Some(arguments) = call.arguments {
+                        state.block_delta(
+                            &mut events,
+                            json!({ "type": "input_json_delta", "partial_json": arguments }),
+                        );
+                    }
+                }
+
+                if let Some(usage) = chunk.usage {
+                    state.close_block(&mut events);
+                    events.push(event(
+                        "message_delta",
+                        json!({
+                            "type": "message_delta",
+                            "delta": {
+                                "stop_reason": state.stop_reason,
+                                "stop_sequence": null,
+                            },
+                            "usage": {
+                                "input_tokens": usage.input_tokens.unwrap_or(0),
+                                "output_tokens": usage.output_tokens.unwrap_or(0),
+                            },
+                        }),
+                    ));
+                    events.push(event("message_stop", json!({ "type": "message_stop" })));
+                }
+            }
+            StreamEvent::Error(e) => {
+                events.push(event(
+                    "error",
+                    json!({
+                        "type": "error",
+                        "error": { "type": "api_error", "message": e },
+                    }),
+                ));
+            }
+        }
+
+        Some((events, state))
+    })
+    .flat_map(stream::iter)
+}
+
+pub async fn messages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MessagesRequest>,
+) -> Response {
+    let (user, key) = match authenticate(&state, &headers).await {
+        Ok(caller) => caller,
+        Err(e) => return error_response(e),
+    };
+
+    let model = match Model::from_name(&request.model) {
+        Some(m) => m,
+        None => {
+            return error_response(OneLlmError::InvalidRequest(format!(
+                "model: {} does not exist",
+                request.model
+            )));
+        }
+    };
+
+    let model_name = request.model.clone();
+    let input = match request.into_api_input(model) {
+        Ok(input) => input,
+        Err(e) => return error_response(OneLlmError::InvalidRequest(e)),
+    };
+
+    if input.stream.unwrap_or(false) {
+        let (rx, limits) = match input.stream(&state, &user, &key).await {
+            Ok(stream) => stream,
+            Err(e) => return error_response(e),
+        };
+
+        let events = message_events(EventState::new(rx, model_name)).map(|(name, data)| {
+            Ok::<_, Infallible>(Event::default().event(name).data(data.to_string()))
+        });
+
+        return (
+            limits.headers(),
+            Sse::new(events).keep_alive(KeepAlive::default()),
+        )
+            .into_response();
+    }
+
+    match input.get(&state, &user, &key).await {
+        Ok((res, limits)) => {
+            (limits.headers(), Json(message_response(res, model_name))).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
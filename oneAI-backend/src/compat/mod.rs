@@ -0,0 +1,15 @@
+pub mod anthropic;
+pub mod openai;
+
+use rand::{Rng, distr::Alphanumeric};
+
+/// Random id in the style providers use for their responses, e.g. `chatcmpl-...`.
+pub fn response_id(prefix: &str) -> String {
+    let suffix: String = rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+
+    format!("{}{}", prefix, suffix)
+}
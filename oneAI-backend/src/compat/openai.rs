@@ -0,0 +1,492 @@
+//! `/v1/chat/completions`, accepting and returning the OpenAI chat-completions wire format so
+//! OpenAI SDKs can use OneLLM by changing only their base URL.
+use std::{
+    convert::Infallible,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::State,
+    http::HeaderMap,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::{Stream, StreamExt, stream};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::{
+    compat::response_id,
+    error::OneLlmError,
+    pricing::Model,
+    requests::{
+        parseapi::{
+            APIInput, ContentPart, MediaSource, Message, MessageContent, ResponseFormat, Tool,
+            ToolChoice, ToolMode,
+        },
+        requests::Completion,
+        responseparser::{
+            common::{LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta},
+            openai::{
+                OpenAIDelta, OpenAIFunctionCall, OpenAIFunctionDelta, OpenAIMessage, OpenAIOutput,
+                OpenAIResponse, OpenAIStreamChoice, OpenAIStreamChunk, OpenAIToolCall,
+                OpenAIToolCallDelta, OpenAIUsage,
+            },
+        },
+        stream::StreamEvent,
+    },
+    server::authenticate,
+    state::AppState,
+};
+
+/// Used when the request sets neither `max_tokens` nor `max_completion_tokens`.
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub max_tokens: Option<u32>,
+    pub max_completion_tokens: Option<u32>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub stop: Option<StopSequences>,
+    pub stream: Option<bool>,
+    pub stream_options: Option<StreamOptions>,
+    pub n: Option<u32>,
+    pub seed: Option<u32>,
+    pub user: Option<String>,
+    pub frequency_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub response_format: Option<ResponseFormat>,
+    pub tools: Option<Vec<Tool>>,
+    pub tool_choice: Option<OpenAIToolChoice>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: Option<ChatContent>,
+    pub tool_calls: Option<Vec<OpenAIToolCall>>,
+    pub tool_call_id: Option<String>,
+}
+
+/// `"auto"`, `"none"`, `"required"` or `{"type": "function", "function": {"name": ...}}`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OpenAIToolChoice {
+    Mode(ToolMode),
+    Function { function: ToolChoiceFunction },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToolChoiceFunction {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ChatContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatContentPart {
+    pub r#type: String,
+    pub text: Option<String>,
+    pub image_url: Option<ChatImageUrl>,
+    pub file: Option<ChatFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatImageUrl {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatFile {
+    pub file_data: Option<String>,
+    pub file_id: Option<String>,
+}
+
+impl ChatContentPart {
+    fn into_part(self) -> Result<ContentPart, String> {
+        match (self.r#type.as_str(), self.text, self.image_url, self.file) {
+            ("text", Some(text), _, _) => Ok(ContentPart::Text { text }),
+            ("image_url", _, Some(image), _) => Ok(ContentPart::Image {
+                source: MediaSource::from_url(image.url),
+            }),
+            ("file", _, _, Some(file)) => match file.file_data {
+                Some(data) => Ok(ContentPart::Document {
+                    source: MediaSource::from_url(data),
+                }),
+                None if file.file_id.is_some() => {
+                    Err("Uploaded files are not supported, send the file as file_data".into())
+                }
+                None => Err("file content part is missing 'file_data'".into()),
+            },
+            (other, ..) => Err(format!("Unsupported content part type '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamOptions {
+    pub include_usage: Option<bool>,
+}
+
+impl ChatCompletionRequest {
+    pub fn into_api_input(self, model: Model) -> Result<APIInput, String> {
+        let mut messages = Vec::new();
+        for msg in self.messages {
+            let content = match msg.content {
+                None => MessageContent::default(),
+                Some(ChatContent::Text(text)) => MessageContent::Text(text),
+                Some(ChatContent::Parts(parts)) => MessageContent::Parts(
+                    parts
+                        .into_iter()
+                        .map(ChatContentPart::into_part)
+                        .collect::<Result<_, _>>()?,
+                ),
+            };
+            messages.push(Message {
+                role: msg.role,
+                content,
+                tool_calls: msg
+                    .tool_calls
+                    .unwrap_or_default()
+                    .iter()
+                    .map(ToolCall::from)
+                    .collect(),
+                tool_call_id: msg.tool_call_id,
+            });
+        }
+
+        let stop_sequences = self.stop.map(|stop| match stop {
+            StopSequences::One(s) => vec![s],
+            StopSequences::Many(v) => v,
+        });
+
+        Ok(APIInput {
+            endpoint: None,
+            model,
+            temperature: self.temperature,
+            stream: self.stream,
+            messages,
+            max_tokens: self
+                .max_completion_tokens
+                .or(self.max_tokens)
+                .unwrap_or(DEFAULT_MAX_TOKENS),
+            top_p: self.top_p.unwrap_or(1.0),
+            stop_sequences,
+            tools: self.tools,
+            contents: None,
+            safety_settings: None,
+            generation_config: None,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            n: self.n,
+            response_format: self.response_format,
+            seed: self.seed,
+            tool_choice: self.tool_choice.map(|choice| match choice {
+                OpenAIToolChoice::Mode(mode) => ToolChoice::Mode(mode),
+                OpenAIToolChoice::Function { function } => ToolChoice::Tool {
+                    name: function.name,
+                },
+            }),
+            user: self.user,
+            logprobs: self.logprobs,
+            top_logprobs: self.top_logprobs,
+            system: None,
+            top_k: None,
+        })
+    }
+}
+
+/// Maps the finish reasons of the other providers onto OpenAI's vocabulary.
+pub fn finish_reason(reason: &str) -> String {
+    match reason {
+        "end_turn" | "stop_sequence" | "STOP" | "stop" => "stop",
+        "max_tokens" | "MAX_TOKENS" | "length" | "model_length" => "length",
+        "tool_use" | "tool_calls" => "tool_calls",
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "content_filter" => {
+            "content_filter"
+        }
+        other => other,
+    }
+    .to_string()
+}
+
+fn error_response(err: OneLlmError) -> Response {
+    let error_type = match err {
+        OneLlmError::InvalidRequest(_) => "invalid_request_error",
+        OneLlmError::Auth(_) => "authentication_error",
+        OneLlmError::RateLimited(_) => "rate_limit_error",
+        OneLlmError::InsufficientBalance | OneLlmError::BudgetExceeded => "insufficient_quota",
+        OneLlmError::Forbidden(_) => "permission_error",
+        _ => "api_error",
+    };
+
+    (
+        err.status(),
+        err.headers(),
+        Json(json!({
+            "error": {
+                "message": err.to_string(),
+                "type": error_type,
+                "code": err.code(),
+            }
+        })),
+    )
+        .into_response()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn openai_usage(usage: Option<LlmUsage>) -> OpenAIUsage {
+    let usage = usage.unwrap_or_default();
+    let prompt_tokens = usage.input_tokens.unwrap_or(0);
+    let completion_tokens = usage.output_tokens.unwrap_or(0);
+
+    OpenAIUsage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: usage
+            .total_tokens
+            .unwrap_or(prompt_tokens + completion_tokens),
+        prompt_tokens_details: Default::default(),
+        completion_tokens_details: Default::default(),
+    }
+}
+
+fn completion_response(res: LlmUnifiedResponse, model: String) -> OpenAIResponse {
+    let tool_calls: Vec<OpenAIToolCall> = res
+        .tool_calls
+        .iter()
+        .map(|call| OpenAIToolCall {
+            id: call.id.clone(),
+            r#type: "function".into(),
+            function: OpenAIFunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments_json(),
+            },
+        })
+        .collect();
+
+    OpenAIResponse {
+        id: response_id("chatcmpl-"),
+        object: "chat.completion".into(),
+        created_at: unix_now(),
+        model,
+        choices: vec![OpenAIOutput {
+            index: 0,
+            finish_reason: res.finish_reason.as_deref().map(finish_reason),
+            message: OpenAIMessage {
+                role: "assistant".into(),
+                content: Some(res.content).filter(|c| !c.is_empty() || tool_calls.is_empty()),
+                refusal: None,
+                function_call: None,
+                tool_calls: Some(tool_calls).filter(|calls| !calls.is_empty()),
+                parsed: None,
+                annotations: Vec::new(),
+            },
+        }],
+        usage: openai_usage(res.usage),
+        service_tier: None,
+        system_fingerprint: None,
+    }
+}
+
+struct ChunkState {
+    rx: mpsc::Receiver<StreamEvent>,
+    id: String,
+    created: u64,
+    model: String,
+    include_usage: bool,
+    sent_role: bool,
+    /// Provider indices of the tool calls seen so far. OpenAI numbers calls from 0, while
+    /// e.g. Claude counts its text blocks as well.
+    tool_indices: Vec<u32>,
+}
+
+impl ChunkState {
+    fn tool_call_delta(&mut self, call: ToolCallDelta) -> OpenAIToolCallDelta {
+        let index = match self.tool_indices.iter().position(|i| *i == call.index) {
+            Some(i) => i,
+            None => {
+                self.tool_indices.push(call.index);
+                self.tool_indices.len() - 1
+            }
+        };
+
+        OpenAIToolCallDelta {
+            index: index as u32,
+            r#type: call.id.as_ref().map(|_| "function".to_string()),
+            id: call.id,
+            function: OpenAIFunctionDelta {
+                name: call.name,
+                arguments: call.arguments,
+            },
+        }
+    }
+
+    fn chunk(
+        &self,
+        delta: OpenAIDelta,
+        finish_reason: Option<String>,
+        usage: Option<OpenAIUsage>,
+    ) -> OpenAIStreamChunk {
+        OpenAIStreamChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".into(),
+            created: self.created,
+            model: self.model.clone(),
+            // The usage chunk is the only one without a choice, as in OpenAI's own streams
+            choices: if usage.is_some() {
+                Vec::new()
+            } else {
+                vec![OpenAIStreamChoice {
+                    index: 0,
+                    finish_reason,
+                    delta,
+                }]
+            },
+            usage,
+        }
+    }
+}
+
+fn chunk_events(state: ChunkState) -> impl Stream<Item = Result<Event, Infallible>> {
+    let events = stream::unfold(state, |mut state| async move {
+        loop {
+            let chunk = match state.rx.recv().await? {
+                StreamEvent::Chunk(chunk) => chunk,
+                StreamEvent::Error(e) => {
+                    let event = Event::default()
+                        .json_data(json!({ "error": { "message": e, "type": "api_error" } }))
+                        .unwrap_or_default();
+                    return Some((Ok(event), state));
+                }
+            };
+
+            let out = if let Some(usage) = chunk.usage {
+                if !state.include_usage {
+                    continue;
+                }
+                state.chunk(
+                    OpenAIDelta {
+                        role: None,
+                        content: None,
+                        tool_calls: None,
+                    },
+                    None,
+                    Some(openai_usage(Some(usage))),
+                )
+            } else {
+                let role = (!state.sent_role).then(|| "assistant".to_string());
+                state.sent_role = true;
+                let tool_calls: Vec<OpenAIToolCallDelta> = chunk
+                    .tool_calls
+                    .into_iter()
+                    .map(|call| state.tool_call_delta(call))
+                    .collect();
+                state.chunk(
+                    OpenAIDelta {
+                        role,
+                        content: chunk.delta,
+                        tool_calls: Some(tool_calls).filter(|calls| !calls.is_empty()),
+                    },
+                    chunk.finish_reason.as_deref().map(finish_reason),
+                    None,
+                )
+            };
+
+            let event = Event::default().json_data(out).unwrap_or_default();
+            return Some((Ok(event), state));
+        }
+    });
+
+    events.chain(stream::once(async { Ok(Event::default().data("[DONE]")) }))
+}
+
+pub async fn chat_completions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Response {
+    let (user, key) = match authenticate(&state, &headers).await {
+        Ok(caller) => caller,
+        Err(e) => return error_response(e),
+    };
+
+    let model = match Model::from_name(&request.model) {
+        Some(m) => m,
+        None => {
+            return error_response(OneLlmError::InvalidRequest(format!(
+                "The model '{}' does not exist",
+                request.model
+            )));
+        }
+    };
+
+    let model_name = request.model.clone();
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .and_then(|o| o.include_usage)
+        .unwrap_or(false);
+
+    let input = match request.into_api_input(model) {
+        Ok(input) => input,
+        Err(e) => return error_response(OneLlmError::InvalidRequest(e)),
+    };
+
+    if input.stream.unwrap_or(false) {
+        let (rx, limits) = match input.stream(&state, &user, &key).await {
+            Ok(stream) => stream,
+            Err(e) => return error_response(e),
+        };
+
+        let state = ChunkState {
+            rx,
+            id: response_id("chatcmpl-"),
+            created: unix_now(),
+            model: model_name,
+            include_usage,
+            sent_role: false,
+            tool_indices: Vec::new(),
+        };
+
+        return (
+            limits.headers(),
+            Sse::new(chunk_events(state)).keep_alive(KeepAlive::default()),
+        )
+            .into_response();
+    }
+
+    match input.get(&state, &user, &key).await {
+        Ok((res, limits)) => {
+            (limits.headers(), Json(completion_response(res, model_name))).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
@@ -0,0 +1,357 @@
+use std::{collections::HashMap, error::Error, fmt, path::Path};
+
+use http::HeaderValue;
+use lettre::message::Mailbox;
+use serde::Deserialize;
+
+use crate::requests::{
+    provider::{enabled_providers, get_provider},
+    requests::AIProvider,
+};
+
+/// Settings read once at startup and shared through `AppState`. Values come from a TOML file
+/// (`config.toml`, or the path in `ONELLM_CONFIG`) and can be overridden by the environment
+/// variables the server has always used, e.g. `POSTGRES` or `OPENAI`. See
+/// `config.example.toml` for every setting.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub limits: LimitsConfig,
+    pub mail: MailConfig,
+    pub smtp: SmtpConfig,
+    pub stripe: StripeConfig,
+    /// Keyed by the lowercase name of the provider's key variable, e.g. `openai` or `claude`.
+    pub providers: HashMap<String, ProviderConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// Origins allowed to call the API from a browser, `*` for any.
+    pub cors_origins: Vec<String>,
+    /// Where the website is reachable, used in links sent by email.
+    pub public_url: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub postgres: String,
+    pub redis: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub jwt_secret: String,
+    /// 32 bytes in hex encrypting the users' TOTP secrets. Two-factor authentication can't be
+    /// enabled without it, everything else works.
+    pub totp_key: Option<String>,
+}
+
+/// The plan of users whose plan isn't in `limits.plans`, which must always define it.
+pub const DEFAULT_PLAN: &str = "free";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub max_api_keys: i64,
+    /// Balance below which requests are refused, in micro-dollars.
+    pub min_balance: i64,
+    /// Rate limits of each plan, keyed by the `plan` of the users.
+    pub plans: HashMap<String, PlanLimits>,
+}
+
+/// Rate limits applying to each API key of a user on the plan.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct PlanLimits {
+    pub requests_per_minute: u32,
+    /// Prompt and completion tokens together.
+    pub tokens_per_minute: u64,
+    /// Requests to the same model that can run at once.
+    pub concurrent_per_model: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    /// `smtp` to send through `[smtp]`, `file` to write one `.eml` file per email to
+    /// `spool_dir`, or `stdout`.
+    pub transport: String,
+    /// Sender of every email, e.g. `OneLLM <OneLLM.dev@gmail.com>`.
+    pub from: String,
+    /// Where the `file` transport writes emails.
+    pub spool_dir: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub relay: String,
+    /// Defaults to 465, with TLS from the start.
+    pub port: Option<u16>,
+    pub username: String,
+    /// Emails can't be sent without it, everything else works.
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct StripeConfig {
+    /// Signing secret of the webhook, payments are rejected without it.
+    pub webhook_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProviderConfig {
+    pub key: String,
+    /// Defaults to the provider's public API.
+    pub base_url: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:3000".to_string(),
+            cors_origins: vec!["*".to_string()],
+            public_url: "http://localhost:3000".to_string(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_api_keys: 10,
+            min_balance: 1_000_000,
+            plans: HashMap::from([
+                (
+                    DEFAULT_PLAN.to_string(),
+                    PlanLimits {
+                        requests_per_minute: 40,
+                        tokens_per_minute: 200_000,
+                        concurrent_per_model: 5,
+                    },
+                ),
+                (
+                    "pro".to_string(),
+                    PlanLimits {
+                        requests_per_minute: 600,
+                        tokens_per_minute: 2_000_000,
+                        concurrent_per_model: 50,
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// The limits of `plan`, or of `DEFAULT_PLAN` if the config doesn't define it.
+    pub fn plan(&self, plan: &str) -> PlanLimits {
+        self.plans
+            .get(plan)
+            .or_else(|| self.plans.get(DEFAULT_PLAN))
+            .copied()
+            .expect("the default plan is checked when the config is loaded")
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: "smtp".to_string(),
+            from: "OneLLM <OneLLM.dev@gmail.com>".to_string(),
+            spool_dir: "mail".to_string(),
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            relay: "smtp.gmail.com".to_string(),
+            port: None,
+            username: "OneLLM.dev@gmail.com".to_string(),
+            password: None,
+        }
+    }
+}
+
+/// Every problem found while loading the configuration, reported together so they can all be
+/// fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        if std::env::var("CI").is_err() {
+            dotenv::dotenv().ok();
+        }
+
+        let mut config = match std::env::var("ONELLM_CONFIG") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new("config.toml").exists() => {
+                Self::from_file(Path::new("config.toml"))?
+            }
+            Err(_) => Self::default(),
+        };
+
+        config.apply_env();
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+
+        toml::from_str(&text)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e).into())
+    }
+
+    fn apply_env(&mut self) {
+        env_override(&mut self.server.listen, "ONELLM_LISTEN");
+        if let Ok(origins) = std::env::var("ONELLM_CORS_ORIGINS") {
+            self.server.cors_origins = origins.split(',').map(|o| o.trim().to_string()).collect();
+        }
+        env_override(&mut self.server.public_url, "ONELLM_PUBLIC_URL");
+        env_override(&mut self.database.postgres, "POSTGRES");
+        env_override(&mut self.database.redis, "REDIS");
+        env_override(&mut self.auth.jwt_secret, "JWT_SECRET");
+        if let Ok(key) = std::env::var("TOTP_KEY") {
+            self.auth.totp_key = Some(key);
+        }
+        env_override(&mut self.mail.transport, "ONELLM_MAIL_TRANSPORT");
+        if let Ok(password) = std::env::var("GMAIL") {
+            self.smtp.password = Some(password);
+        }
+        if let Ok(secret) = std::env::var("WHSTRIPE") {
+            self.stripe.webhook_secret = Some(secret);
+        }
+
+        for provider in enabled_providers() {
+            let entry = self
+                .providers
+                .entry(provider.key_var().to_lowercase())
+                .or_default();
+            env_override(&mut entry.key, provider.key_var());
+            if let Ok(url) = std::env::var(format!("{}_BASE_URL", provider.key_var())) {
+                entry.base_url = Some(url);
+            }
+        }
+    }
+
+    /// Checks every required value is present and fills in provider defaults.
+    pub fn validate(&mut self) -> Result<(), ConfigError> {
+        // An empty optional secret, as in the example config, means it isn't set
+        self.smtp.password = self.smtp.password.take().filter(|p| !p.is_empty());
+        self.stripe.webhook_secret = self.stripe.webhook_secret.take().filter(|s| !s.is_empty());
+        self.auth.totp_key = self.auth.totp_key.take().filter(|k| !k.is_empty());
+
+        self.server.public_url = self.server.public_url.trim_end_matches('/').to_string();
+
+        let mut problems = Vec::new();
+        let mut require = |value: &str, name: &str, var: &str| {
+            if value.trim().is_empty() {
+                problems.push(format!("{} is not set (or set {})", name, var));
+            }
+        };
+
+        require(&self.database.postgres, "database.postgres", "POSTGRES");
+        require(&self.database.redis, "database.redis", "REDIS");
+        require(&self.auth.jwt_secret, "auth.jwt_secret", "JWT_SECRET");
+
+        let mut providers: Vec<_> = enabled_providers().collect();
+        providers.sort_by_key(|p| p.key_var());
+        for provider in providers {
+            let name = provider.key_var().to_lowercase();
+            let entry = self.providers.entry(name.clone()).or_default();
+            require(
+                &entry.key,
+                &format!("providers.{}.key", name),
+                provider.key_var(),
+            );
+
+            let url = entry
+                .base_url
+                .get_or_insert_with(|| provider.default_base_url().to_string());
+            *url = url.trim_end_matches('/').to_string();
+        }
+
+        if let Some(key) = &self.auth.totp_key
+            && hex::decode(key).map_or(true, |k| k.len() != 32)
+        {
+            problems.push("auth.totp_key must be 64 hex characters".to_string());
+        }
+        if !["smtp", "file", "stdout"].contains(&self.mail.transport.as_str()) {
+            problems.push("mail.transport must be smtp, file or stdout".to_string());
+        }
+        if self.mail.from.parse::<Mailbox>().is_err() {
+            problems.push(format!(
+                "mail.from is not a valid sender '{}'",
+                self.mail.from
+            ));
+        }
+        if !self.limits.plans.contains_key(DEFAULT_PLAN) {
+            problems.push(format!("limits.plans.{} is not set", DEFAULT_PLAN));
+        }
+        let mut plans: Vec<_> = self.limits.plans.iter().collect();
+        plans.sort_by_key(|(name, _)| name.as_str());
+        for (name, plan) in plans {
+            if plan.requests_per_minute == 0
+                || plan.tokens_per_minute == 0
+                || plan.concurrent_per_model == 0
+            {
+                problems.push(format!(
+                    "limits.plans.{} must allow at least 1 of each",
+                    name
+                ));
+            }
+        }
+        for origin in &self.server.cors_origins {
+            if origin != "*" && HeaderValue::from_str(origin).is_err() {
+                problems.push(format!(
+                    "server.cors_origins has an invalid origin '{}'",
+                    origin
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    /// The key and base URL for `kind`. Present for every enabled provider once loaded.
+    pub fn provider(&self, kind: AIProvider) -> Option<&ProviderConfig> {
+        let provider = get_provider(kind).ok()?;
+        self.providers.get(&provider.key_var().to_lowercase())
+    }
+}
+
+fn env_override(value: &mut String, var: &str) {
+    if let Ok(v) = std::env::var(var) {
+        *value = v;
+    }
+}
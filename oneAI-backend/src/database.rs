@@ -1,11 +1,19 @@
-use chrono::{Duration, Utc};
-use dotenv::dotenv;
-use jsonwebtoken::{EncodingKey, Header, encode};
-use sqlx::{PgPool, Row};
+use chrono::{DateTime, Duration, Utc};
+use password_auth::generate_hash;
+use sqlx::{PgPool, Row, postgres::PgRow};
 use std::error::Error;
 
-use crate::auth::basicauth::generate_api;
-use crate::{auth, utils::*};
+use crate::auth::basicauth::{api_public_id, generate_api, generate_token, hash_api, hashes_match};
+use crate::{
+    auth,
+    organization::{self, Role},
+    pricing::Model,
+    session, usage,
+    utils::*,
+};
+
+/// How long a password reset link stays valid.
+const PASSWORD_RESET_TTL: Duration = Duration::hours(1);
 
 #[derive(Debug)]
 struct MissingUser(String);
@@ -17,91 +25,41 @@ impl std::fmt::Display for MissingUser {
     }
 }
 
-pub async fn init_pool() -> Result<PgPool, Box<dyn Error>> {
-    if std::env::var("CI").is_err() {
-        dotenv().ok();
-    }
-
-    let url = std::env::var("POSTGRES")?;
-    let pool = sqlx::postgres::PgPool::connect(&url).await?;
+pub async fn init_pool(url: &str) -> Result<PgPool, Box<dyn Error>> {
+    let pool = sqlx::postgres::PgPool::connect(url).await?;
 
     Ok(pool)
 }
 
 impl User {
-    pub async fn from_token(
-        pool: Option<PgPool>,
-        token: String,
-    ) -> Result<HiddenUser, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
+    pub async fn from_token(pool: &PgPool, token: String) -> Result<HiddenUser, Box<dyn Error>> {
         let row = sqlx::query(
             r#"
-            SELECT u.id, u.email, u.password, u.balance, u.verified
+            SELECT u.id, u.email, u.password, u.verified, u.personal_organization_id, o.balance
             FROM users u
             INNER JOIN sessions s ON s.user_id = u.id
+            INNER JOIN organizations o ON o.id = u.personal_organization_id
             WHERE s.token = $1 AND s.expires_at > NOW()
             "#,
         )
         .bind(&token)
-        .fetch_one(&pool)
-        .await?;
+        .fetch_optional(pool)
+        .await?
+        .ok_or("Invalid or expired session")?;
+        if let Err(e) = session::touch(pool, &token).await {
+            eprintln!("Could not record use of a session: {}", e);
+        }
 
-        let bal: i32 = row.get("balance");
+        let bal: i64 = row.get("balance");
         let mut user = User {
             id: row.get("id"),
             email: row.get("email"),
             password: row.get("password"),
-            balance: bal as u32,
             verified: row.get("verified"),
+            personal_organization_id: row.get("personal_organization_id"),
         };
 
-        Ok(HiddenUser::from_user(&mut user).await)
-    }
-
-    pub async fn new_token(
-        pool: Option<PgPool>,
-        user_id: i32,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
-        let now = Utc::now();
-        let exp = now + Duration::hours(24);
-
-        let claims = Claims {
-            sub: user_id,
-            iat: now.timestamp() as usize,
-            exp: exp.timestamp() as usize,
-        };
-
-        let secret = std::env::var("JWT_SECRET")?;
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(secret.as_ref()),
-        )?;
-
-        // Store token in `sessions` table
-        sqlx::query(
-            r#"
-        INSERT INTO sessions (user_id, token, created_at, expires_at)
-        VALUES ($1, $2, $3, $4)
-        "#,
-        )
-        .bind(user_id)
-        .bind(&token)
-        .bind(now)
-        .bind(exp)
-        .execute(&pool)
-        .await?;
-
-        Ok(token)
+        Ok(HiddenUser::from_user(&mut user, bal).await)
     }
 
     //    pub async fn verify_token(payload: &TokenInput) -> Result<(), Box<dyn Error>> {
@@ -145,15 +103,10 @@ impl User {
     //        Ok(())
     //    }
 
-    pub async fn is_verified(&self, pool: Option<PgPool>) -> Result<bool, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
+    pub async fn is_verified(&self, pool: &PgPool) -> Result<bool, Box<dyn Error>> {
         let row = sqlx::query("SELECT verified FROM users WHERE email = $1")
             .bind(&self.email)
-            .fetch_optional(&pool)
+            .fetch_optional(pool)
             .await?;
 
         match row {
@@ -161,32 +114,22 @@ impl User {
             None => Ok(false), // Or Err("User not found") if you prefer
         }
     }
-    pub async fn verify_user(pool: Option<PgPool>, email: &str) -> Result<(), Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
+    pub async fn verify_user(pool: &PgPool, email: &str) -> Result<(), Box<dyn Error>> {
         sqlx::query("UPDATE users SET verified = TRUE WHERE email = $1")
             .bind(email)
-            .execute(&pool)
+            .execute(pool)
             .await?;
 
         Ok(())
     }
-    pub async fn count_apikey(pool: Option<PgPool>, email: &str) -> Result<i64, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
+    pub async fn count_apikey(pool: &PgPool, email: &str) -> Result<i64, Box<dyn Error>> {
         let row = sqlx::query(
             "SELECT COUNT(*) as count \
              FROM api_keys \
              WHERE user_id = (SELECT id FROM users WHERE email = $1)",
         )
         .bind(email)
-        .fetch_one(&pool)
+        .fetch_one(pool)
         .await?;
         let count: i64 = row.try_get("count")?;
 
@@ -194,23 +137,23 @@ impl User {
     }
 
     pub async fn delete_apikey(
-        pool: Option<PgPool>,
+        pool: &PgPool,
+        jwt_secret: &str,
         token: &str,
         name: Option<&str>,
         all: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
         // 1. Decode JWT and get user_id
         let token_data = jsonwebtoken::decode::<Claims>(
             token,
-            &jsonwebtoken::DecodingKey::from_secret(std::env::var("JWT_SECRET")?.as_bytes()),
+            &jsonwebtoken::DecodingKey::from_secret(jwt_secret.as_bytes()),
             &jsonwebtoken::Validation::default(),
         )?;
         let user_id = token_data.claims.sub; // assuming `sub` is user_id
+        // A valid signature doesn't mean the session wasn't logged out since
+        if session::user_id(pool, token).await? != user_id {
+            return Err("Invalid or expired session".into());
+        }
 
         // 2. Delete all or one API key
         let query = if all {
@@ -222,7 +165,7 @@ impl User {
                 .bind(name)
         };
 
-        let result = query.execute(&pool).await?;
+        let result = query.execute(pool).await?;
 
         if result.rows_affected() == 0 {
             return Err("No API key(s) found to delete.".into());
@@ -230,156 +173,371 @@ impl User {
 
         Ok(())
     }
+    /// Creates a key spending the balance of `organization_id`, which the user must be
+    /// allowed to use the API in. The user's email has to be verified first.
     pub async fn generate_apikey(
         &self,
-        pool: Option<PgPool>,
+        pool: &PgPool,
+        organization_id: i32,
         name: &str,
+        max_keys: i64,
     ) -> Result<String, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
+        if !self.is_verified(pool).await? {
+            return Err("Please verify your email before creating API keys.".into());
+        }
+        organization::require_role(pool, organization_id, self.id, Role::can_use_api).await?;
 
         // Count how many keys this user already has
-        let count = Self::count_apikey(Some(pool.clone()), &self.email).await?;
+        let count = Self::count_apikey(pool, &self.email).await?;
 
-        if count >= 10 {
-            return Err("You have reached the maximum of 10 API keys.".into());
+        if count >= max_keys {
+            return Err(format!("You have reached the maximum of {} API keys.", max_keys).into());
         }
 
         // Generate a new random API key
 
         let new_key = generate_api();
         sqlx::query(
-            "INSERT INTO api_keys (user_id, key, name) VALUES ((SELECT id FROM users WHERE email = $1), $2, $3)",
-        ).bind(&self.email).bind(&new_key).bind(name)
-        .execute(&pool)
+            "INSERT INTO api_keys (user_id, organization_id, public_id, key_hash, name) \
+             VALUES ((SELECT id FROM users WHERE email = $1), $2, $3, $4, $5)",
+        )
+        .bind(&self.email)
+        .bind(organization_id)
+        .bind(&new_key.public_id)
+        .bind(hash_api(&new_key.key))
+        .bind(name)
+        .execute(pool)
         .await?;
 
-        Ok(new_key)
+        // The only time the full key is available
+        Ok(new_key.key)
     }
-    pub async fn get_row_api(pool: Option<PgPool>, apikey: String) -> Result<User, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
+    /// The owner of `apikey` and the key itself. Keys replaced by a rotation are accepted
+    /// until their grace period ends, expired keys are rejected.
+    pub async fn get_row_api(
+        pool: &PgPool,
+        apikey: String,
+    ) -> Result<(User, ApiKey), Box<dyn Error>> {
+        let hash = hash_api(&apikey);
+        let (column, value) = match api_public_id(&apikey) {
+            Some(public_id) => ("public_id", public_id),
+            None => ("key_hash", hash.as_str()),
         };
 
-        let row = sqlx::query(
-            "SELECT users.id, users.email, users.password, users.balance, users.verified \
-     FROM users \
-     JOIN api_keys a ON users.id = a.user_id \
-     WHERE a.key = $1",
-        )
-        .bind(apikey)
-        .fetch_optional(&pool)
+        let rows = sqlx::query(&format!(
+            "SELECT users.id, users.email, users.password, users.verified, \
+             users.personal_organization_id, users.plan, o.balance, \
+             a.id AS key_id, a.organization_id, a.key_hash, a.daily_limit, a.monthly_limit, a.allowed_models, \
+             a.scopes, a.expires_at, a.previous_key_hash, a.previous_expires_at \
+             FROM users \
+             JOIN api_keys a ON users.id = a.user_id \
+             JOIN organizations o ON o.id = a.organization_id \
+             WHERE a.{0} = $1 OR a.previous_{0} = $1",
+            column
+        ))
+        .bind(value)
+        .fetch_all(pool)
         .await?;
 
-        if let Some(record) = row {
-            let balance: i32 = record.try_get("balance")?;
+        let now = Utc::now();
+        let row = rows.into_iter().find(|record| {
+            let previous: Option<String> = record.get("previous_key_hash");
+            let previous_until: Option<DateTime<Utc>> = record.get("previous_expires_at");
+
+            hashes_match(record.get("key_hash"), &hash)
+                || previous.is_some_and(|p| hashes_match(&p, &hash))
+                    && previous_until.is_some_and(|until| until > now)
+        });
 
-            Ok(User {
+        if let Some(record) = row {
+            let user = User {
                 id: record.get("id"),
                 email: record.get("email"),
                 password: record.get("password"),
-                balance: balance as u32,
                 verified: record.get("verified"),
-            })
+                personal_organization_id: record.get("personal_organization_id"),
+            };
+
+            let key = ApiKey {
+                id: record.get("key_id"),
+                organization_id: record.get("organization_id"),
+                balance: record.try_get("balance")?,
+                limits: key_limits(&record)?,
+                access: key_access(&record)?,
+                plan: record.try_get("plan")?,
+            };
+
+            if key.access.expires_at.is_some_and(|at| at <= now) {
+                return Err("This API key has expired".into());
+            }
+
+            Ok((user, key))
         } else {
             Err(Box::new(MissingUser("No such user was found".to_string())))
         }
     }
 
-    pub async fn get_row(pool: Option<PgPool>, email: String) -> Result<User, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
+    /// Records that a key was just used. Skipped if it was already recorded in the last
+    /// minute, to save a write per request.
+    pub async fn touch_apikey(pool: &PgPool, key_id: i32) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE api_keys SET last_used_at = NOW() WHERE id = $1 \
+             AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute')",
+        )
+        .bind(key_id)
+        .execute(pool)
+        .await?;
 
-        let row = sqlx::query(
-            "SELECT id, email, password, balance, verified FROM users WHERE email = $1",
+        Ok(())
+    }
+
+    /// Replaces the scopes and expiry of the user's key called `name`.
+    pub async fn set_key_access(
+        &self,
+        pool: &PgPool,
+        name: &str,
+        access: &KeyAccess,
+    ) -> Result<(), Box<dyn Error>> {
+        let scopes: Option<Vec<String>> = access
+            .scopes
+            .as_ref()
+            .map(|scopes| scopes.iter().map(|s| s.to_string()).collect());
+
+        let result = sqlx::query(
+            "UPDATE api_keys SET scopes = $1, expires_at = $2 WHERE user_id = $3 AND name = $4",
         )
-        .bind(&email)
-        .fetch_optional(&pool)
+        .bind(scopes)
+        .bind(access.expires_at)
+        .bind(self.id)
+        .bind(name)
+        .execute(pool)
         .await?;
 
-        if let Some(record) = row {
-            let balance: i32 = record.try_get("balance")?;
+        if result.rows_affected() == 0 {
+            return Err("No API key with this name.".into());
+        }
 
-            Ok(User {
-                id: record.get("id"),
-                email: record.get("email"),
-                password: record.get("password"),
-                balance: balance as u32,
-                verified: record.get("verified"),
-            })
-        } else {
-            Err(Box::new(MissingUser("Invalid apikey".to_string())))
+        Ok(())
+    }
+
+    /// Issues a new secret for the user's key called `name`. The old one keeps working for
+    /// `grace_secs`, replacing any key still in the grace period of an earlier rotation.
+    /// Limits, scopes and usage stay with the key.
+    pub async fn rotate_apikey(
+        &self,
+        pool: &PgPool,
+        name: &str,
+        grace_secs: u64,
+    ) -> Result<String, Box<dyn Error>> {
+        let new_key = generate_api();
+
+        let result = sqlx::query(
+            "UPDATE api_keys SET \
+             previous_public_id = public_id, \
+             previous_key_hash = key_hash, \
+             previous_expires_at = NOW() + make_interval(secs => $1), \
+             public_id = $2, \
+             key_hash = $3 \
+             WHERE user_id = $4 AND name = $5",
+        )
+        .bind(grace_secs as f64)
+        .bind(&new_key.public_id)
+        .bind(hash_api(&new_key.key))
+        .bind(self.id)
+        .bind(name)
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err("No API key with this name.".into());
         }
+
+        Ok(new_key.key)
     }
 
-    #[allow(unused)]
-    pub async fn change_password(
-        pool: Option<PgPool>,
-        token: &str,
-        new_password: String,
+    pub async fn list_apikeys(&self, pool: &PgPool) -> Result<Vec<KeyInfo>, Box<dyn Error>> {
+        let rows = sqlx::query(
+            "SELECT name, public_id, scopes, expires_at, last_used_at, previous_expires_at \
+             FROM api_keys WHERE user_id = $1 ORDER BY id",
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(KeyInfo {
+                    name: row.try_get("name")?,
+                    public_id: row.try_get("public_id")?,
+                    access: key_access(row)?,
+                    last_used_at: row.try_get("last_used_at")?,
+                    previous_expires_at: row.try_get("previous_expires_at")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces the limits of the user's key called `name`.
+    pub async fn set_key_limits(
+        &self,
+        pool: &PgPool,
+        name: &str,
+        limits: &KeyLimits,
     ) -> Result<(), Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
+        if limits.daily_limit.is_some_and(|l| l < 0) || limits.monthly_limit.is_some_and(|l| l < 0)
+        {
+            return Err("Limits can't be negative.".into());
+        }
+
+        let models: Option<Vec<String>> = limits
+            .allowed_models
+            .as_ref()
+            .map(|models| models.iter().map(|m| m.to_string()).collect());
 
-        // Check if token is verified and not expired
-        let verified =
-            sqlx::query("SELECT verified FROM sessions WHERE token = $1 AND expires_at > NOW()")
-                .bind(token)
-                .fetch_optional(&pool)
-                .await?
-                .map(|r| r.get::<bool, _>("verified"))
-                .unwrap_or(false);
-
-        if !verified {
-            return Err("Attempt at using unverified or expired token".into());
+        let result = sqlx::query(
+            "UPDATE api_keys SET daily_limit = $1, monthly_limit = $2, allowed_models = $3 \
+             WHERE user_id = $4 AND name = $5",
+        )
+        .bind(limits.daily_limit)
+        .bind(limits.monthly_limit)
+        .bind(models)
+        .bind(self.id)
+        .bind(name)
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err("No API key with this name.".into());
         }
 
-        // Get user email from session
-        let row = sqlx::query("SELECT user_id FROM sessions WHERE token = $1")
-            .bind(token)
-            .fetch_one(&pool)
-            .await?;
+        Ok(())
+    }
+
+    /// The limits of the user's key called `name` and what it spent against them.
+    pub async fn get_key_budget(
+        &self,
+        pool: &PgPool,
+        name: &str,
+    ) -> Result<KeyBudget, Box<dyn Error>> {
+        let row = sqlx::query(
+            "SELECT id, daily_limit, monthly_limit, allowed_models FROM api_keys \
+             WHERE user_id = $1 AND name = $2",
+        )
+        .bind(self.id)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?
+        .ok_or("No API key with this name.")?;
+
+        let (spent_today, spent_this_month) = usage::key_spend(pool, row.get("id")).await?;
+
+        Ok(KeyBudget {
+            limits: key_limits(&row)?,
+            spent_today,
+            spent_this_month,
+        })
+    }
 
-        let user_id: i32 = row.get("user_id");
+    pub async fn get_row(pool: &PgPool, email: String) -> Result<User, Box<dyn Error>> {
+        match User::find(pool, &email).await? {
+            Some(user) => Ok(user),
+            None => Err(Box::new(MissingUser("Invalid apikey".to_string()))),
+        }
+    }
 
-        // Get full user row from users table
-        let user_row =
-            sqlx::query("SELECT id, email, password, balance, verified FROM users WHERE id = $1")
-                .bind(user_id)
-                .fetch_one(&pool)
-                .await?;
+    /// The user with `email`, `None` if there is none.
+    pub async fn find(pool: &PgPool, email: &str) -> Result<Option<User>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT id, email, password, verified, personal_organization_id FROM users \
+             WHERE email = $1",
+        )
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
 
-        // Construct the User object
-        let user = User {
-            id: user_row.get("id"),
-            email: user_row.get("email"),
-            password: user_row.get("password"),
-            balance: user_row.get::<i32, _>("balance") as u32,
-            verified: user_row.get("verified"),
+        Ok(row.map(|record| User {
+            id: record.get("id"),
+            email: record.get("email"),
+            password: record.get("password"),
+            verified: record.get("verified"),
+            personal_organization_id: record.get("personal_organization_id"),
+        }))
+    }
+
+    /// Issues a password reset token for `email`, replacing any earlier one. `None` when
+    /// there is no such user, which callers shouldn't reveal.
+    pub async fn create_password_reset(
+        pool: &PgPool,
+        email: &str,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let mut tx = pool.begin().await?;
+
+        let Some(user_id) = sqlx::query_scalar::<_, i32>("SELECT id FROM users WHERE email = $1")
+            .bind(email)
+            .fetch_optional(&mut *tx)
+            .await?
+        else {
+            return Ok(None);
         };
 
-        // Call the update method on the user to update password
-        user.update_db(Some(pool), TableFields::Password, &new_password)
+        sqlx::query("DELETE FROM password_resets WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
             .await?;
 
+        let token = generate_token();
+        sqlx::query(
+            "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+        )
+        .bind(hash_api(&token))
+        .bind(user_id)
+        .bind(Utc::now() + PASSWORD_RESET_TTL)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(token))
+    }
+
+    /// Sets a new password with a token from `create_password_reset`. The token can only be
+    /// used once and every session of the user is revoked.
+    pub async fn reset_password(
+        pool: &PgPool,
+        token: &str,
+        new_password: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if new_password.is_empty() {
+            return Err("The new password can't be empty.".into());
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let user_id: i32 = sqlx::query_scalar(
+            "DELETE FROM password_resets WHERE token_hash = $1 AND expires_at > NOW() \
+             RETURNING user_id",
+        )
+        .bind(hash_api(token))
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or("Invalid or expired reset link")?;
+
+        sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+            .bind(generate_hash(new_password))
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
         Ok(())
     }
-    pub async fn get_keynames(
-        pool: Option<PgPool>,
-        email: &str,
-    ) -> Result<Vec<String>, Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
 
+    pub async fn get_keynames(pool: &PgPool, email: &str) -> Result<Vec<String>, Box<dyn Error>> {
         // Get API key names
         let rows = sqlx::query(
             "SELECT api_keys.name 
@@ -388,7 +546,7 @@ impl User {
          WHERE users.email = $1",
         )
         .bind(email)
-        .fetch_all(&pool)
+        .fetch_all(pool)
         .await?;
         let keynames = rows
             .into_iter()
@@ -397,46 +555,64 @@ impl User {
 
         Ok(keynames)
     }
-    pub async fn new_user(&self, pool: Option<PgPool>) -> Result<(), Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
+    /// Inserts the user along with their personal organization.
+    pub async fn new_user(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+        let mut tx = pool.begin().await?;
+
+        let organization_id: i32 =
+            sqlx::query_scalar("INSERT INTO organizations (name) VALUES ($1) RETURNING id")
+                .bind(&self.email)
+                .fetch_one(&mut *tx)
+                .await?;
 
-        let _user_row = sqlx::query(
-            "INSERT INTO users (email, password, balance) VALUES ($1, $2, $3) RETURNING id",
+        let user_id: i32 = sqlx::query_scalar(
+            "INSERT INTO users (email, password, personal_organization_id) \
+             VALUES ($1, $2, $3) RETURNING id",
         )
         .bind(&self.email)
         .bind(&self.password)
-        .bind(self.balance as i32)
-        .fetch_one(&pool)
+        .bind(organization_id)
+        .fetch_one(&mut *tx)
         .await?;
 
+        sqlx::query(
+            "INSERT INTO organization_members (organization_id, user_id, role) \
+             VALUES ($1, $2, $3)",
+        )
+        .bind(organization_id)
+        .bind(user_id)
+        .bind(Role::Owner.as_str())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
         Ok(())
     }
 
+    /// Balance of the user's personal organization.
+    pub async fn personal_balance(&self, pool: &PgPool) -> Result<i64, Box<dyn Error>> {
+        Ok(
+            sqlx::query_scalar("SELECT balance FROM organizations WHERE id = $1")
+                .bind(self.personal_organization_id)
+                .fetch_one(pool)
+                .await?,
+        )
+    }
+
+    #[allow(unused)]
     pub async fn update_db(
         &self,
-        pool: Option<PgPool>,
+        pool: &PgPool,
         field: TableFields,
         new_value: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
-
         let mut temp_user = self.clone();
 
         // Update the in-memory user struct with the new value
         match field {
             TableFields::Email => temp_user.email = new_value.to_string(),
             TableFields::Password => temp_user.password = new_value.to_string(),
-            TableFields::Balance => {
-                temp_user.balance = new_value
-                    .parse::<u32>()
-                    .expect("Error while trying to parse balance");
-            }
         }
 
         // Apply hashing logic if needed (likely hashes password if field == Password)
@@ -456,39 +632,62 @@ impl User {
             TableFields::Password => {
                 query_builder = query_builder.bind(&temp_user.password);
             }
-            TableFields::Balance => {
-                query_builder = query_builder.bind(temp_user.balance as i32);
-            }
         }
 
         // Bind the email for the WHERE clause and execute
-        query_builder.bind(&self.email).execute(&pool).await?;
+        query_builder.bind(&self.email).execute(pool).await?;
 
         Ok(())
     }
     #[allow(unused)]
-    pub async fn delete_user(pool: Option<PgPool>, email: &str) -> Result<(), Box<dyn Error>> {
-        let pool = match pool {
-            Some(a) => a,
-            None => init_pool().await?,
-        };
+    pub async fn delete_user(pool: &PgPool, email: &str) -> Result<(), Box<dyn Error>> {
+        let mut tx = pool.begin().await?;
 
-        let result = sqlx::query("DELETE FROM users WHERE email = $1")
-            .bind(email)
-            .execute(&pool)
+        let organization_id: i32 = sqlx::query_scalar(
+            "DELETE FROM users WHERE email = $1 RETURNING personal_organization_id",
+        )
+        .bind(email)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| MissingUser("User not found".into()))?;
+
+        // Shared organizations stay with their other members
+        sqlx::query("DELETE FROM organizations WHERE id = $1")
+            .bind(organization_id)
+            .execute(&mut *tx)
             .await?;
 
-        if result.rows_affected() == 0 {
-            return Err(Box::new(MissingUser("User not found".into())));
-        }
+        tx.commit().await?;
 
         Ok(())
     }
 }
-pub async fn init_db() -> Result<(), Box<dyn Error>> {
-    let pool = init_pool().await?;
+/// Reads the limit columns of an `api_keys` row.
+fn key_limits(row: &PgRow) -> Result<KeyLimits, sqlx::Error> {
+    let models: Option<Vec<String>> = row.try_get("allowed_models")?;
+
+    Ok(KeyLimits {
+        daily_limit: row.try_get("daily_limit")?,
+        monthly_limit: row.try_get("monthly_limit")?,
+        // Models removed from OneLLM since the list was saved can't be used anyway
+        allowed_models: models.map(|m| m.iter().filter_map(|n| Model::from_name(n)).collect()),
+    })
+}
+
+/// Reads the scope and expiry columns of an `api_keys` row.
+fn key_access(row: &PgRow) -> Result<KeyAccess, Box<dyn Error>> {
+    let scopes: Option<Vec<String>> = row.try_get("scopes")?;
+
+    Ok(KeyAccess {
+        scopes: scopes
+            .map(|scopes| scopes.into_iter().map(Scope::try_from).collect())
+            .transpose()?,
+        expires_at: row.try_get("expires_at")?,
+    })
+}
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+pub async fn init_db(pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::migrate!("./migrations").run(pool).await?;
 
     Ok(())
 }
@@ -498,7 +697,6 @@ impl TableFields {
         match self {
             TableFields::Email => "email",
             TableFields::Password => "password",
-            TableFields::Balance => "balance",
         }
     }
 }
@@ -0,0 +1,178 @@
+use std::{error::Error, fmt};
+
+use axum::{
+    Json,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+use crate::{
+    ratelimit::{Limit, RateLimit},
+    requests::provider::ProviderError,
+    utils::Output,
+};
+
+/// Everything that can go wrong while serving a request. Each variant maps to an HTTP status
+/// and a stable `error_code` clients can match on instead of parsing the message.
+#[derive(Debug)]
+pub enum OneLlmError {
+    /// Missing, malformed or unknown credentials.
+    Auth(String),
+    /// One of the limits of the key's plan was reached.
+    RateLimited(RateLimit),
+    InsufficientBalance,
+    /// The API key reached its daily or monthly spending cap.
+    BudgetExceeded,
+    /// The credentials are valid but don't allow this request.
+    Forbidden(String),
+    /// The provider rejected the request, `status` is what it answered with.
+    Provider {
+        status: u16,
+        message: String,
+    },
+    InvalidRequest(String),
+    Internal(String),
+}
+
+impl OneLlmError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            OneLlmError::Auth(_) => StatusCode::UNAUTHORIZED,
+            OneLlmError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            OneLlmError::InsufficientBalance | OneLlmError::BudgetExceeded => {
+                StatusCode::PAYMENT_REQUIRED
+            }
+            OneLlmError::Forbidden(_) => StatusCode::FORBIDDEN,
+            // Client errors are passed on, anything else (including the provider refusing
+            // OneLLM's own key) is our upstream failing
+            OneLlmError::Provider { status, .. } => match StatusCode::from_u16(*status) {
+                Ok(s)
+                    if s.is_client_error()
+                        && s != StatusCode::UNAUTHORIZED
+                        && s != StatusCode::FORBIDDEN =>
+                {
+                    s
+                }
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            OneLlmError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            OneLlmError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The machine-readable code sent in the body next to the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            OneLlmError::Auth(_) => "authentication_error",
+            OneLlmError::RateLimited(_) => "rate_limit_exceeded",
+            OneLlmError::InsufficientBalance => "insufficient_balance",
+            OneLlmError::BudgetExceeded => "budget_exceeded",
+            OneLlmError::Forbidden(_) => "permission_denied",
+            OneLlmError::Provider { .. } => "provider_error",
+            OneLlmError::InvalidRequest(_) => "invalid_request",
+            OneLlmError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Headers sent along with the error, the rate limits and when to retry if one was hit.
+    pub fn headers(&self) -> HeaderMap {
+        match self {
+            OneLlmError::RateLimited(status) => status.headers(),
+            _ => HeaderMap::new(),
+        }
+    }
+}
+
+impl Error for OneLlmError {}
+
+impl fmt::Display for OneLlmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OneLlmError::Auth(message)
+            | OneLlmError::Forbidden(message)
+            | OneLlmError::InvalidRequest(message)
+            | OneLlmError::Internal(message) => f.write_str(message),
+            OneLlmError::RateLimited(status) => match status.exceeded {
+                Some(Limit::Tokens) => {
+                    f.write_str("Rate limit exceeded: too many tokens per minute.")
+                }
+                Some(Limit::Concurrency) => {
+                    f.write_str("Rate limit exceeded: too many requests to this model at once.")
+                }
+                _ => f.write_str("Rate limit exceeded: too many requests per minute."),
+            },
+            OneLlmError::InsufficientBalance => f.write_str(
+                "Insufficient balance, please topup your balance to continue using OneLLM",
+            ),
+            OneLlmError::BudgetExceeded => {
+                f.write_str("This API key has reached its spending limit.")
+            }
+            OneLlmError::Provider { status, message } => {
+                write!(f, "Provider returned {}: {}", status, message)
+            }
+        }
+    }
+}
+
+/// Errors keep the `{code, output}` shape of successful `/api` responses, with the HTTP
+/// status set to match `code`.
+impl IntoResponse for OneLlmError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let mut output = json!({
+            "error": self.to_string(),
+            "error_code": self.code(),
+        });
+        if let OneLlmError::Provider { status, .. } = &self {
+            output["upstream_status"] = json!(status);
+        }
+
+        let body = Output {
+            code: status.as_u16() as u32,
+            output,
+        };
+
+        (status, self.headers(), Json(body)).into_response()
+    }
+}
+
+impl From<ProviderError> for OneLlmError {
+    fn from(e: ProviderError) -> Self {
+        OneLlmError::Provider {
+            status: e.status,
+            message: e.message,
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for OneLlmError {
+    fn from(e: Box<dyn Error>) -> Self {
+        OneLlmError::Internal(e.to_string())
+    }
+}
+
+/// The database's own message can name tables and constraints, so it is only logged.
+impl From<sqlx::Error> for OneLlmError {
+    fn from(e: sqlx::Error) -> Self {
+        eprintln!("Database error: {}", e);
+        OneLlmError::Internal("Database error, please try again later.".to_string())
+    }
+}
+
+impl From<redis::RedisError> for OneLlmError {
+    fn from(e: redis::RedisError) -> Self {
+        OneLlmError::Internal(e.to_string())
+    }
+}
+
+/// Only provider calls go through reqwest, so a failed connection, timeout or body is the
+/// upstream failing.
+impl From<reqwest::Error> for OneLlmError {
+    fn from(e: reqwest::Error) -> Self {
+        OneLlmError::Provider {
+            status: e.status().map_or(502, |s| s.as_u16()),
+            message: e.to_string(),
+        }
+    }
+}
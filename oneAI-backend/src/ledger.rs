@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use sqlx::{PgConnection, PgPool};
+
+use crate::{usage, utils::KeyLimits};
+
+/// Longer than any completion takes, so only holds of requests that died are released.
+const STALE_HOLD_AGE: Duration = Duration::from_secs(60 * 60);
+/// How often holds are checked for ones left behind.
+const STALE_HOLD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Why a balance changed, stored in `balance_transactions.kind`.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    Charge,
+    Topup,
+    Refund,
+    Adjustment,
+}
+
+impl TransactionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Charge => "charge",
+            TransactionKind::Topup => "topup",
+            TransactionKind::Refund => "refund",
+            TransactionKind::Adjustment => "adjustment",
+        }
+    }
+}
+
+/// One change to an organization's balance. `amount` is in micro-dollars, negative for
+/// charges.
+#[derive(Debug, Clone)]
+pub struct Transaction<'a> {
+    pub organization_id: i32,
+    /// Who made the change, e.g. the creator of the key that was charged.
+    pub user_id: i32,
+    pub api_key_id: Option<i32>,
+    pub amount: i64,
+    pub kind: TransactionKind,
+    /// A transaction whose kind and request id were already recorded is skipped, which makes
+    /// retried Stripe webhooks safe.
+    pub request_id: Option<&'a str>,
+}
+
+impl Transaction<'_> {
+    /// Records the transaction and applies it to the balance in one SQL transaction. Returns
+    /// the new balance, or `None` if the transaction had already been recorded.
+    pub async fn record(&self, pool: &PgPool) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let balance = self.apply(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(balance)
+    }
+
+    async fn apply(&self, conn: &mut PgConnection) -> Result<Option<i64>, sqlx::Error> {
+        let inserted = sqlx::query(
+            "INSERT INTO balance_transactions \
+             (organization_id, user_id, api_key_id, amount, kind, request_id) \
+             VALUES ($1, $2, $3, $4, $5, $6) \
+             ON CONFLICT (kind, request_id) WHERE request_id IS NOT NULL DO NOTHING",
+        )
+        .bind(self.organization_id)
+        .bind(self.user_id)
+        .bind(self.api_key_id)
+        .bind(self.amount)
+        .bind(self.kind.as_str())
+        .bind(self.request_id)
+        .execute(&mut *conn)
+        .await?;
+
+        if inserted.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let balance: i64 = sqlx::query_scalar(
+            "UPDATE organizations SET balance = balance + $1 WHERE id = $2 RETURNING balance",
+        )
+        .bind(self.amount)
+        .bind(self.organization_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(Some(balance))
+    }
+}
+
+/// Balance reserved for a request while the provider works on it, so parallel or oversized
+/// requests can't spend more than the user has. `amount` is the most the request can cost.
+#[derive(Debug, Clone)]
+pub struct Hold {
+    pub organization_id: i32,
+    pub user_id: i32,
+    pub api_key_id: Option<i32>,
+    pub request_id: String,
+    pub amount: i64,
+}
+
+/// What became of a hold passed to `Hold::place`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Placed,
+    /// The part of the organization's balance not already held doesn't cover it.
+    InsufficientBalance,
+    /// It would take the key past its daily or monthly limit.
+    BudgetExceeded,
+}
+
+impl Hold {
+    /// Reserves `amount` if the part of the balance not already held covers it and, for holds
+    /// of a key, if it fits in what `limits` leave of the key's budget.
+    pub async fn place(&self, pool: &PgPool, limits: &KeyLimits) -> Result<Placement, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        if let Some(api_key_id) = self.api_key_id
+            && (limits.daily_limit.is_some() || limits.monthly_limit.is_some())
+        {
+            // Holds of the key are placed one at a time, so each one sees those placed before
+            sqlx::query("SELECT id FROM api_keys WHERE id = $1 FOR UPDATE")
+                .bind(api_key_id)
+                .execute(&mut *tx)
+                .await?;
+
+            let left = usage::budget_left(&mut *tx, api_key_id, limits).await?;
+            if left.is_some_and(|left| left < self.amount) {
+                return Ok(Placement::BudgetExceeded);
+            }
+        }
+
+        let reserved = sqlx::query(
+            "UPDATE organizations SET held = held + $1 WHERE id = $2 AND balance - held >= $1",
+        )
+        .bind(self.amount)
+        .bind(self.organization_id)
+        .execute(&mut *tx)
+        .await?;
+
+        if reserved.rows_affected() == 0 {
+            return Ok(Placement::InsufficientBalance);
+        }
+
+        sqlx::query(
+            "INSERT INTO balance_holds (request_id, organization_id, user_id, api_key_id, amount) \
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&self.request_id)
+        .bind(self.organization_id)
+        .bind(self.user_id)
+        .bind(self.api_key_id)
+        .bind(self.amount)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Placement::Placed)
+    }
+
+    /// Releases the hold and charges what the request actually cost, in one SQL transaction.
+    /// Returns the new balance.
+    pub async fn settle(&self, pool: &PgPool, cost: i64) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        self.remove(&mut tx).await?;
+
+        let balance = Transaction {
+            organization_id: self.organization_id,
+            user_id: self.user_id,
+            api_key_id: self.api_key_id,
+            amount: -cost,
+            kind: TransactionKind::Charge,
+            request_id: Some(&self.request_id),
+        }
+        .apply(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(balance)
+    }
+
+    /// Gives the held amount back without charging anything, for requests the provider failed.
+    pub async fn release(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        self.remove(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        // Already gone if it was swept as stale, the organization's `held` was lowered then
+        let removed: Option<i64> =
+            sqlx::query_scalar("DELETE FROM balance_holds WHERE request_id = $1 RETURNING amount")
+                .bind(&self.request_id)
+                .fetch_optional(&mut *conn)
+                .await?;
+
+        if let Some(amount) = removed {
+            sqlx::query("UPDATE organizations SET held = held - $1 WHERE id = $2")
+                .bind(amount)
+                .bind(self.organization_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Releases holds older than `age`, left behind by requests whose server stopped or whose task
+/// died before settling them. Returns how many were released.
+pub async fn release_stale_holds(pool: &PgPool, age: Duration) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let stale: Vec<(i32, i64)> = sqlx::query_as(
+        "DELETE FROM balance_holds WHERE created_at < NOW() - make_interval(secs => $1) \
+         RETURNING organization_id, amount",
+    )
+    .bind(age.as_secs_f64())
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for (organization_id, amount) in &stale {
+        sqlx::query("UPDATE organizations SET held = held - $1 WHERE id = $2")
+            .bind(amount)
+            .bind(organization_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(stale.len() as u64)
+}
+
+/// Runs `release_stale_holds` every `STALE_HOLD_INTERVAL` for as long as the server runs,
+/// starting right away with holds of requests in flight when a server last stopped.
+pub fn spawn_hold_releaser(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STALE_HOLD_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = release_stale_holds(&pool, STALE_HOLD_AGE).await {
+                eprintln!("Could not release stale holds: {}", e);
+            }
+        }
+    });
+}
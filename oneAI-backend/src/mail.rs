@@ -0,0 +1,159 @@
+use std::{
+    error::Error,
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{Datelike, Utc};
+use futures_util::future::BoxFuture;
+use lettre::{
+    Message, SmtpTransport, Transport,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+use rand::{Rng, distr::Alphanumeric};
+
+use crate::config::Config;
+
+/// An HTML email to one recipient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+}
+
+/// Somewhere emails can be sent. Picked by `mail.transport` in the config, SMTP unless
+/// running locally.
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), Box<dyn Error>>>;
+}
+
+/// The mailer `config` asks for.
+pub fn from_config(config: &Config) -> Result<Box<dyn Mailer>, Box<dyn Error>> {
+    let from: Mailbox = config.mail.from.parse()?;
+
+    Ok(match config.mail.transport.as_str() {
+        "smtp" => {
+            let smtp = &config.smtp;
+            let mut builder = SmtpTransport::relay(&smtp.relay)?;
+            if let Some(port) = smtp.port {
+                builder = builder.port(port);
+            }
+            let transport = smtp.password.as_ref().map(|password| {
+                builder
+                    .credentials(Credentials::new(smtp.username.clone(), password.clone()))
+                    .build()
+            });
+            Box::new(SmtpMailer { from, transport })
+        }
+        "file" => Box::new(FileMailer {
+            from,
+            dir: PathBuf::from(&config.mail.spool_dir),
+        }),
+        "stdout" => Box::new(StdoutMailer { from }),
+        other => return Err(format!("Unknown mail transport '{}'", other).into()),
+    })
+}
+
+/// Sends through an SMTP relay, e.g. Gmail.
+pub struct SmtpMailer {
+    from: Mailbox,
+    /// `None` without a password, every email then fails.
+    transport: Option<SmtpTransport>,
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let transport = self
+                .transport
+                .clone()
+                .ok_or("SMTP password is not configured")?;
+            let message = message(&self.from, email)?;
+
+            // The transport blocks for the whole exchange with the relay
+            match tokio::task::spawn_blocking(move || transport.send(&message)).await? {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("Could not send email: {e}").into()),
+            }
+        })
+    }
+}
+
+/// Writes each email to its own `.eml` file in `dir`, for running without an SMTP server.
+pub struct FileMailer {
+    pub from: Mailbox,
+    pub dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+            let suffix: String = rand::rng()
+                .sample_iter(Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect();
+
+            let formatted = message(&self.from, email)?.formatted();
+
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(
+                self.dir.join(format!("{}-{}.eml", millis, suffix)),
+                formatted,
+            )
+            .await?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Prints each email to stdout.
+pub struct StdoutMailer {
+    from: Mailbox,
+}
+
+impl Mailer for StdoutMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&message(&self.from, email)?.formatted())?;
+            writeln!(stdout)?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Fills in the `{{ NAME }}` placeholders of an email template. Values are HTML escaped and
+/// `{{ YEAR }}` is always the current year.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let year = Utc::now().year().to_string();
+    let mut html = template.replace("{{ YEAR }}", &year);
+    for (name, value) in values {
+        html = html.replace(&format!("{{{{ {} }}}}", name), &escape_html(value));
+    }
+    html
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn message(from: &Mailbox, email: &Email) -> Result<Message, Box<dyn Error>> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(Mailbox::new(None, email.to.parse()?))
+        .subject(&email.subject)
+        .header(ContentType::TEXT_HTML)
+        .body(email.html.clone())?)
+}
@@ -1,18 +1,38 @@
 mod auth;
+mod compat;
+mod config;
 mod database;
+mod error;
+mod ledger;
+mod mail;
+mod organization;
 mod payment;
 mod pricing;
+mod ratelimit;
 mod requests;
 mod server;
+mod session;
+mod state;
 mod testing;
+mod usage;
 mod utils;
 
+use config::Config;
 use server::server;
+use state::AppState;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    database::init_db().await?;
-    let _server = server().await;
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let state = AppState::new(config).await?;
+    server(state).await;
 
     Ok(())
 }
@@ -0,0 +1,354 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+
+/// What a member of an organization may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Everything, and can't be removed by admins.
+    Owner,
+    /// Manages members and API keys.
+    Admin,
+    /// Creates API keys that spend the shared balance.
+    Member,
+    /// Sees the balance and tops it up, can't use the API.
+    Billing,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Admin => "admin",
+            Role::Member => "member",
+            Role::Billing => "billing",
+        }
+    }
+
+    fn parse(role: &str) -> Result<Self, Box<dyn Error>> {
+        match role {
+            "owner" => Ok(Role::Owner),
+            "admin" => Ok(Role::Admin),
+            "member" => Ok(Role::Member),
+            "billing" => Ok(Role::Billing),
+            _ => Err(format!("Unknown role '{}'", role).into()),
+        }
+    }
+
+    pub fn can_manage_members(&self) -> bool {
+        matches!(self, Role::Owner | Role::Admin)
+    }
+
+    pub fn can_use_api(&self) -> bool {
+        !matches!(self, Role::Billing)
+    }
+}
+
+/// An organization as seen by one of its members, or by someone invited to it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Membership {
+    pub organization_id: i32,
+    pub name: String,
+    pub role: Role,
+    /// Micro-dollars shared by every member.
+    pub balance: i64,
+    /// The user's own organization, which can't be left or shared.
+    pub personal: bool,
+    /// Invited but not accepted yet.
+    pub pending: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Member {
+    pub email: String,
+    pub role: Role,
+    pub pending: bool,
+}
+
+/// Creates an organization with `owner_id` as its owner and returns its id.
+pub async fn create(pool: &PgPool, name: &str, owner_id: i32) -> Result<i32, Box<dyn Error>> {
+    if name.trim().is_empty() {
+        return Err("The organization needs a name.".into());
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let id: i32 = sqlx::query_scalar("INSERT INTO organizations (name) VALUES ($1) RETURNING id")
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO organization_members (organization_id, user_id, role) VALUES ($1, $2, $3)",
+    )
+    .bind(id)
+    .bind(owner_id)
+    .bind(Role::Owner.as_str())
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(id)
+}
+
+/// The role of `user_id` in the organization, `None` if they aren't a member.
+pub async fn role_of(
+    pool: &PgPool,
+    organization_id: i32,
+    user_id: i32,
+) -> Result<Option<Role>, Box<dyn Error>> {
+    let role: Option<String> = sqlx::query_scalar(
+        "SELECT role FROM organization_members WHERE organization_id = $1 AND user_id = $2",
+    )
+    .bind(organization_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    role.as_deref().map(Role::parse).transpose()
+}
+
+/// Fails unless `user_id` is a member allowed to do what `allowed` checks.
+pub async fn require_role(
+    pool: &PgPool,
+    organization_id: i32,
+    user_id: i32,
+    allowed: fn(&Role) -> bool,
+) -> Result<Role, Box<dyn Error>> {
+    match role_of(pool, organization_id, user_id).await? {
+        Some(role) if allowed(&role) => Ok(role),
+        Some(_) => Err("Your role in this organization does not allow this.".into()),
+        None => Err("You are not a member of this organization.".into()),
+    }
+}
+
+/// Organizations the user belongs to or was invited to, personal one first.
+pub async fn memberships(
+    pool: &PgPool,
+    user_id: i32,
+    email: &str,
+) -> Result<Vec<Membership>, Box<dyn Error>> {
+    let rows = sqlx::query(
+        "SELECT o.id, o.name, m.role, o.balance, o.id = u.personal_organization_id AS personal, \
+         FALSE AS pending \
+         FROM organization_members m \
+         JOIN organizations o ON o.id = m.organization_id \
+         JOIN users u ON u.id = m.user_id \
+         WHERE m.user_id = $1 \
+         UNION ALL \
+         SELECT o.id, o.name, i.role, 0, FALSE, TRUE \
+         FROM organization_invites i \
+         JOIN organizations o ON o.id = i.organization_id \
+         WHERE i.email = $2 \
+         ORDER BY personal DESC, pending, id",
+    )
+    .bind(user_id)
+    .bind(email)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            let pending: bool = row.try_get("pending")?;
+            Ok(Membership {
+                organization_id: row.try_get("id")?,
+                name: row.try_get("name")?,
+                role: Role::parse(row.try_get("role")?)?,
+                // Not shown before joining
+                balance: if pending { 0 } else { row.try_get("balance")? },
+                personal: row.try_get("personal")?,
+                pending,
+            })
+        })
+        .collect()
+}
+
+/// Members and pending invites of the organization.
+pub async fn members(pool: &PgPool, organization_id: i32) -> Result<Vec<Member>, Box<dyn Error>> {
+    let rows = sqlx::query(
+        "SELECT u.email, m.role, FALSE AS pending \
+         FROM organization_members m JOIN users u ON u.id = m.user_id \
+         WHERE m.organization_id = $1 \
+         UNION ALL \
+         SELECT email, role, TRUE FROM organization_invites WHERE organization_id = $1 \
+         ORDER BY pending, email",
+    )
+    .bind(organization_id)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(Member {
+                email: row.try_get("email")?,
+                role: Role::parse(row.try_get("role")?)?,
+                pending: row.try_get("pending")?,
+            })
+        })
+        .collect()
+}
+
+/// Invites `email` to join with `role`. Only owners can make admins, and nobody can be
+/// invited as an owner.
+pub async fn invite(
+    pool: &PgPool,
+    organization_id: i32,
+    inviter_id: i32,
+    email: &str,
+    role: Role,
+) -> Result<(), Box<dyn Error>> {
+    let inviter = require_role(pool, organization_id, inviter_id, Role::can_manage_members).await?;
+    if role == Role::Owner || (role == Role::Admin && inviter != Role::Owner) {
+        return Err("Your role in this organization does not allow this.".into());
+    }
+    if is_personal(pool, organization_id).await? {
+        return Err("Personal organizations can't have other members.".into());
+    }
+
+    let already_member: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM organization_members m JOIN users u ON u.id = m.user_id \
+         WHERE m.organization_id = $1 AND u.email = $2)",
+    )
+    .bind(organization_id)
+    .bind(email)
+    .fetch_one(pool)
+    .await?;
+    if already_member {
+        return Err("This user is already a member.".into());
+    }
+
+    sqlx::query(
+        "INSERT INTO organization_invites (organization_id, email, role, invited_by) \
+         VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (organization_id, email) DO UPDATE SET role = $3, invited_by = $4",
+    )
+    .bind(organization_id)
+    .bind(email)
+    .bind(role.as_str())
+    .bind(inviter_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Turns the user's invite into a membership.
+pub async fn accept_invite(
+    pool: &PgPool,
+    organization_id: i32,
+    user_id: i32,
+    email: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+
+    let role: String = sqlx::query_scalar(
+        "DELETE FROM organization_invites WHERE organization_id = $1 AND email = $2 \
+         RETURNING role",
+    )
+    .bind(organization_id)
+    .bind(email)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or("No invite to this organization.")?;
+
+    sqlx::query(
+        "INSERT INTO organization_members (organization_id, user_id, role) VALUES ($1, $2, $3)",
+    )
+    .bind(organization_id)
+    .bind(user_id)
+    .bind(role)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Removes a member, or withdraws the invite of `email`. Their API keys in the organization
+/// are deleted with them. Members can always remove themselves, except the last owner.
+pub async fn remove_member(
+    pool: &PgPool,
+    organization_id: i32,
+    remover_id: i32,
+    email: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+
+    let member: Option<(i32, String)> = sqlx::query_as(
+        "SELECT u.id, m.role FROM organization_members m JOIN users u ON u.id = m.user_id \
+         WHERE m.organization_id = $1 AND u.email = $2 FOR UPDATE OF m",
+    )
+    .bind(organization_id)
+    .bind(email)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let removing_self = member.as_ref().is_some_and(|(id, _)| *id == remover_id);
+    if !removing_self {
+        let remover =
+            require_role(pool, organization_id, remover_id, Role::can_manage_members).await?;
+        if member
+            .as_ref()
+            .is_some_and(|(_, role)| role == Role::Owner.as_str())
+            && remover != Role::Owner
+        {
+            return Err("Only owners can remove owners.".into());
+        }
+    }
+
+    let Some((user_id, role)) = member else {
+        let withdrawn = sqlx::query(
+            "DELETE FROM organization_invites WHERE organization_id = $1 AND email = $2",
+        )
+        .bind(organization_id)
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+        if withdrawn.rows_affected() == 0 {
+            return Err("No member or invite with this email.".into());
+        }
+        tx.commit().await?;
+        return Ok(());
+    };
+
+    if role == Role::Owner.as_str() {
+        let owners: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM organization_members WHERE organization_id = $1 AND role = $2",
+        )
+        .bind(organization_id)
+        .bind(Role::Owner.as_str())
+        .fetch_one(&mut *tx)
+        .await?;
+        if owners <= 1 {
+            return Err("The last owner can't be removed.".into());
+        }
+    }
+
+    sqlx::query("DELETE FROM organization_members WHERE organization_id = $1 AND user_id = $2")
+        .bind(organization_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM api_keys WHERE organization_id = $1 AND user_id = $2")
+        .bind(organization_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn is_personal(pool: &PgPool, organization_id: i32) -> Result<bool, Box<dyn Error>> {
+    Ok(sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM users WHERE personal_organization_id = $1)",
+    )
+    .bind(organization_id)
+    .fetch_one(pool)
+    .await?)
+}
@@ -2,27 +2,28 @@
 use axum::{
     Error,
     body::Body,
-    extract::FromRequest,
+    extract::{FromRequest, State},
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
 };
 use stripe::{Event, EventObject, EventType};
 
-use crate::auth::basicauth::update_bal;
+use crate::{
+    error::OneLlmError,
+    ledger::{Transaction, TransactionKind},
+    organization,
+    state::AppState,
+    utils::User,
+};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub struct StripeEvent(Event);
 
-impl<S> FromRequest<S> for StripeEvent
-where
-    String: FromRequest<S>,
-    S: Send + Sync,
-{
+impl FromRequest<AppState> for StripeEvent {
     type Rejection = Response;
 
-    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
-        dotenv::dotenv().ok();
+    async fn from_request(req: Request<Body>, state: &AppState) -> Result<Self, Self::Rejection> {
         let signature = if let Some(sig) = req.headers().get("stripe-signature") {
             sig.to_owned()
         } else {
@@ -33,38 +34,87 @@ where
             .await
             .map_err(IntoResponse::into_response)?;
 
+        let signature = signature
+            .to_str()
+            .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+        let secret = state
+            .config
+            .stripe
+            .webhook_secret
+            .as_deref()
+            .ok_or_else(|| {
+                OneLlmError::Internal("WHSTRIPE env key not set".to_string()).into_response()
+            })?;
+
         Ok(Self(
-            stripe::Webhook::construct_event(
-                &payload,
-                signature.to_str().unwrap(),
-                &std::env::var("WHSTRIPE").expect("WHSTRIPE env key not set"),
-            )
-            .map_err(|_| StatusCode::BAD_REQUEST.into_response())?,
+            stripe::Webhook::construct_event(&payload, signature, secret)
+                .map_err(|_| StatusCode::BAD_REQUEST.into_response())?,
         ))
     }
 }
 
-pub async fn handle_webhook(StripeEvent(event): StripeEvent) {
-    if event.type_ == EventType::CheckoutSessionCompleted {
-        if let EventObject::CheckoutSession(session) = event.data.object {
-            let details = match &session.customer_details {
-                Some(d) => d,
-                None => return,
-            };
+/// Credits completed checkouts. Failing with a non-2xx status makes Stripe retry the event.
+/// The organization is taken from the session's `client_reference_id` (set by appending
+/// `?client_reference_id=<organization id>` to the payment link) when the paying user is a
+/// member of it, and is their personal organization otherwise.
+pub async fn handle_webhook(
+    State(state): State<AppState>,
+    StripeEvent(event): StripeEvent,
+) -> Result<(), OneLlmError> {
+    if event.type_ == EventType::CheckoutSessionCompleted
+        && let EventObject::CheckoutSession(session) = event.data.object
+    {
+        let details = match &session.customer_details {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+
+        let email = match &details.email {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let amount_total = match session.amount_total {
+            Some(a) => a * 10000,
+            None => return Ok(()),
+        };
 
-            let email = match &details.email {
-                Some(e) => e,
-                None => return,
-            };
+        // An error would have Stripe retry an event that can never succeed
+        let Some(user) = User::find(&state.pool, email).await? else {
+            eprintln!(
+                "Checkout session {} was paid by {}, who has no account",
+                session.id, email
+            );
+            return Ok(());
+        };
 
-            let amount_total = match session.amount_total {
-                Some(a) => a * 10000,
-                None => return,
-            };
+        let organization_id = match session
+            .client_reference_id
+            .as_deref()
+            .and_then(|id| id.parse::<i32>().ok())
+        {
+            Some(id)
+                if organization::role_of(&state.pool, id, user.id)
+                    .await?
+                    .is_some() =>
+            {
+                id
+            }
+            _ => user.personal_organization_id,
+        };
 
-            update_bal(None, email.to_owned(), amount_total as i32)
-                .await
-                .expect("Error while updating balance from stripe");
+        // The session id makes Stripe's retries of the same event a no-op
+        Transaction {
+            organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: amount_total,
+            kind: TransactionKind::Topup,
+            request_id: Some(session.id.as_str()),
         }
+        .record(&state.pool)
+        .await?;
     }
+
+    Ok(())
 }
@@ -1,168 +1,14 @@
-#![allow(non_camel_case_types)]
-use crate::requests::requests::AIProvider;
+//! Prices of each model, in the units `charge` debits per token. The model list itself is
+//! shared with the client in `onellm-types`.
+pub use onellm_types::Model;
 
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub enum Model {
-    // ==== OpenAI ====
-    #[serde(rename = "GPT-4.1")]
-    Gpt4_1,
-    #[serde(rename = "GPT-4.1-Mini")]
-    Gpt4_1Mini,
-    #[serde(rename = "GPT-4.1-Nano")]
-    Gpt4_1Nano,
-    #[serde(rename = "GPT-o3")]
-    GptO3,
-    #[serde(rename = "GPT-o4-mini")]
-    GptO4Mini,
-    #[serde(rename = "GPT-o3-pro")]
-    GptO3Pro,
-    #[serde(rename = "GPT-4o")]
-    Gpt4o,
-    #[serde(rename = "GPT-4o-mini")]
-    Gpt4oMini,
-    #[serde(rename = "GPT-o1")]
-    GptO1,
-    #[serde(rename = "GPT-o3-DeepResearch")]
-    GptO3DeepResearch,
-    #[serde(rename = "GPT-o3-Mini")]
-    GptO3Mini,
-    #[serde(rename = "GPT-o1-Mini")]
-    GptO1Mini,
-    #[serde(rename = "GPT-5")]
-    GPT5,
-    #[serde(rename = "GPT-5-mini")]
-    GPT5_Mini,
-    #[serde(rename = "GPT-5-Nano")]
-    GPT5_Nano,
-    #[serde(rename = "GPT-5-Chat-Latest")]
-    GPT5_Chat_Latest,
-
-    // ==== Anthropic ====
-    #[serde(rename = "Opus-4")]
-    ClaudeOpus4,
-    #[serde(rename = "Sonnet-4")]
-    ClaudeSonnet4,
-    #[serde(rename = "Haiku-3.5")]
-    ClaudeHaiku3_5,
-    #[serde(rename = "Opus-3")]
-    ClaudeOpus3,
-    #[serde(rename = "Sonnet-3.7")]
-    ClaudeSonnet3_7,
-    #[serde(rename = "Haiku-3")]
-    ClaudeHaiku3,
-
-    // ==== DeepSeek ====
-    #[serde(rename = "DeepSeek-Reasoner")]
-    DeepSeekR1,
-    #[serde(rename = "DeepSeek-Chat")]
-    DeepSeekV3,
-
-    // ==== Gemini (Google) ====
-    #[serde(rename = "2.5-Flash-preview")]
-    Gemini25FlashPreview,
-    #[serde(rename = "2.5-Pro-preview")]
-    Gemini25ProPreview,
-    #[serde(rename = "2.0-Flash")]
-    Gemini20Flash,
-    #[serde(rename = "2.0-Flash-lite")]
-    Gemini20FlashLite,
-    #[serde(rename = "1.5-Flash")]
-    Gemini15Flash,
-    #[serde(rename = "1.5-Flash-8B")]
-    Gemini15Flash8B,
-    #[serde(rename = "1.5-Pro")]
-    Gemini15Pro,
-
-    // ==== Mistral ====
-    #[serde(rename = "Mistral-Medium-3")]
-    MistralMedium3,
-    #[serde(rename = "Magistral-Medium")]
-    MagistralMedium,
-    #[serde(rename = "Codestral")]
-    Codestral,
-    #[serde(rename = "Devstral-Medium")]
-    DevstralMedium,
-    #[serde(rename = "Mistral-Large")]
-    MistralLarge,
-    #[serde(rename = "Pixtral-Large")]
-    PixtralLarge,
-    #[serde(rename = "Ministral-8B-24.10")]
-    Ministral8B_24_10,
-    #[serde(rename = "Ministral-3B-24.10")]
-    Ministral3B_24_10,
-    #[serde(rename = "Mistral-Small-3.2")]
-    MistralSmall3_2,
-    #[serde(rename = "Magistral-Small")]
-    MagistralSmall,
-    #[serde(rename = "Devstral-Small")]
-    DevstralSmall,
-    #[serde(rename = "Pixtral-12B")]
-    Pixtral12B,
-    #[serde(rename = "Mistral-NeMo")]
-    MistralNemo,
+pub trait Pricing {
+    fn input_price(&self) -> u32;
+    fn output_price(&self) -> u32;
 }
 
-impl Model {
-    pub fn name(&self) -> &str {
-        match self {
-            // ==== OpenAI ====
-            Model::Gpt4_1 => "gpt-4.1",
-            Model::Gpt4_1Mini => "gpt-4.1-mini",
-            Model::Gpt4_1Nano => "4.1-nano",
-            Model::GptO3 => "o3",
-            Model::GptO4Mini => "o4-mini",
-            Model::GptO3Pro => "o3-pro",
-            Model::Gpt4o => "gpt-4o",
-            Model::Gpt4oMini => "gpt-4o-mini",
-            Model::GptO1 => "o1",
-            Model::GptO3DeepResearch => "o3-DeepResearch",
-            Model::GptO3Mini => "o3-mini",
-            Model::GptO1Mini => "o1-mini",
-            Model::GPT5 => "gpt-5",
-            Model::GPT5_Mini => "gpt-5-mini",
-            Model::GPT5_Nano => "gpt-5-nano",
-            Model::GPT5_Chat_Latest => "gpt-5-chat-latest",
-
-            // ==== Anthropic ====
-            Model::ClaudeOpus4 => "claude-opus-4-20250514",
-            Model::ClaudeSonnet4 => "claude-sonnet-4-20250514",
-            Model::ClaudeHaiku3_5 => "claude-3-5-haiku-latest",
-            Model::ClaudeOpus3 => "claude-3-opus-20240229",
-            Model::ClaudeSonnet3_7 => "claude-3-7-sonnet-latest",
-            Model::ClaudeHaiku3 => "claude-3-haiku-20240307",
-
-            // ==== DeepSeek ====
-            Model::DeepSeekR1 => "DeepSeek-Reasoner",
-            Model::DeepSeekV3 => "DeepSeek-Chat",
-
-            // ==== Gemini (Google) ====
-            Model::Gemini25FlashPreview => "2.5-Flash-preview",
-            Model::Gemini25ProPreview => "2.5-Pro-preview",
-            Model::Gemini20Flash => "2.0-Flash",
-            Model::Gemini20FlashLite => "2.0-Flash-lite",
-            Model::Gemini15Flash => "1.5-Flash",
-            Model::Gemini15Flash8B => "1.5-Flash-8B",
-            Model::Gemini15Pro => "1.5-Pro",
-
-            // ==== Mistral ====
-            Model::MistralMedium3 => "Mistral-Medium-2505",
-            Model::MagistralMedium => "Magistral-Medium-2506",
-            Model::Codestral => "Codestral-2501",
-            Model::DevstralMedium => "Devstral-Medium-2507",
-            Model::MistralLarge => "Mistral-Large-2411",
-            Model::PixtralLarge => "Pixtral-Large-2411",
-            Model::Ministral8B_24_10 => "Ministral-8B-2410",
-            Model::Ministral3B_24_10 => "Ministral-3B-2410",
-            Model::MistralSmall3_2 => "Mistral-Small-2506",
-            Model::MagistralSmall => "Magistral-Small-2506",
-            Model::DevstralSmall => "Devstral-Small-2507",
-            Model::Pixtral12B => "Pixtral-12B-2409",
-            Model::MistralNemo => "open-Mistral-NeMo",
-        }
-    }
-    pub fn input_price(&self) -> u32 {
+impl Pricing for Model {
+    fn input_price(&self) -> u32 {
         match self {
             // ==== OpenAI ====
             Model::Gpt4_1 => 208,
@@ -220,7 +66,7 @@ impl Model {
         }
     }
 
-    pub fn output_price(&self) -> u32 {
+    fn output_price(&self) -> u32 {
         match self {
             // ==== OpenAI ====
             Model::Gpt4_1 => 832,
@@ -330,60 +176,4 @@ impl Model {
     //            Model::MistralNemo => 312,
     //        }
     //    }
-    pub fn provider(&self) -> AIProvider {
-        match self {
-            // ==== OpenAI ====
-            Model::Gpt4_1
-            | Model::Gpt4_1Mini
-            | Model::Gpt4_1Nano
-            | Model::GptO3
-            | Model::GptO4Mini
-            | Model::GptO3Pro
-            | Model::Gpt4o
-            | Model::Gpt4oMini
-            | Model::GptO1
-            | Model::GptO3DeepResearch
-            | Model::GptO3Mini
-            | Model::GPT5
-            | Model::GPT5_Mini
-            | Model::GPT5_Nano
-            | Model::GPT5_Chat_Latest
-            | Model::GptO1Mini => AIProvider::OpenAI,
-
-            // ==== Anthropic ====
-            Model::ClaudeOpus4
-            | Model::ClaudeSonnet4
-            | Model::ClaudeHaiku3_5
-            | Model::ClaudeOpus3
-            | Model::ClaudeSonnet3_7
-            | Model::ClaudeHaiku3 => AIProvider::Anthropic,
-
-            // ==== Gemini ====
-            Model::Gemini25FlashPreview
-            | Model::Gemini25ProPreview
-            | Model::Gemini20Flash
-            | Model::Gemini20FlashLite
-            | Model::Gemini15Flash
-            | Model::Gemini15Flash8B
-            | Model::Gemini15Pro => AIProvider::Gemini,
-
-            // ==== DeepSeek ====
-            Model::DeepSeekR1 | Model::DeepSeekV3 => AIProvider::DeepSeek,
-
-            // ==== Mistral ====
-            Model::MistralMedium3
-            | Model::MagistralMedium
-            | Model::Codestral
-            | Model::DevstralMedium
-            | Model::MistralLarge
-            | Model::PixtralLarge
-            | Model::Ministral8B_24_10
-            | Model::Ministral3B_24_10
-            | Model::MagistralSmall
-            | Model::DevstralSmall
-            | Model::Pixtral12B
-            | Model::MistralNemo
-            | Model::MistralSmall3_2 => AIProvider::Mistral,
-        }
-    }
 }
@@ -0,0 +1,233 @@
+use std::{
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{HeaderMap, HeaderValue};
+use redis::{Script, aio::ConnectionManager};
+
+use crate::{config::PlanLimits, error::OneLlmError, pricing::Model};
+
+/// Requests and tokens are counted in fixed windows of this many seconds.
+const WINDOW_SECS: u64 = 60;
+/// How long a concurrency slot is kept if its request never gives it back, e.g. because the
+/// server stopped. Longer than any completion takes.
+const SLOT_TTL_SECS: u64 = 60 * 60;
+/// What clients refused for running too many requests at once are told to wait.
+const CONCURRENCY_RETRY_SECS: u64 = 1;
+
+/// Checks every limit and, only if none is reached, counts the request and takes a slot, all
+/// in one step so concurrent requests can't both get the last one. Returns which limit was
+/// reached (0 for none) and the counts of the window.
+static ACQUIRE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local requests = tonumber(redis.call('GET', KEYS[1]) or '0')
+        local tokens = tonumber(redis.call('GET', KEYS[2]) or '0')
+        local running = tonumber(redis.call('GET', KEYS[3]) or '0')
+        local exceeded = 0
+        if requests >= tonumber(ARGV[1]) then
+            exceeded = 1
+        elseif tokens >= tonumber(ARGV[2]) then
+            exceeded = 2
+        elseif running >= tonumber(ARGV[3]) then
+            exceeded = 3
+        else
+            requests = redis.call('INCR', KEYS[1])
+            redis.call('EXPIRE', KEYS[1], ARGV[4])
+            redis.call('INCR', KEYS[3])
+            redis.call('EXPIRE', KEYS[3], ARGV[5])
+        end
+        return {exceeded, requests, tokens}
+        ",
+    )
+});
+
+/// Adds the tokens a finished request used to the window it started in and gives its slot back.
+static RELEASE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if tonumber(ARGV[1]) > 0 then
+            redis.call('INCRBY', KEYS[1], ARGV[1])
+            redis.call('EXPIRE', KEYS[1], ARGV[2])
+        end
+        if tonumber(redis.call('GET', KEYS[2]) or '0') > 0 then
+            redis.call('DECR', KEYS[2])
+        end
+        return 0
+        ",
+    )
+});
+
+/// The limit that refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Requests,
+    Tokens,
+    Concurrency,
+}
+
+/// Where an API key stands against the limits of its plan, sent back in `X-RateLimit-*`
+/// headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub limits: PlanLimits,
+    /// Requests made in the current window, including this one if it was allowed.
+    pub requests: u32,
+    /// Tokens used in the current window. Only counted once a request finishes, since
+    /// providers report them at the end.
+    pub tokens: u64,
+    /// Seconds until the window ends and both counts start over.
+    pub reset: u64,
+    pub exceeded: Option<Limit>,
+}
+
+impl RateLimit {
+    /// Seconds until a refused request is worth retrying, `None` if it wasn't refused.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self.exceeded? {
+            Limit::Requests | Limit::Tokens => Some(self.reset),
+            Limit::Concurrency => Some(CONCURRENCY_RETRY_SECS),
+        }
+    }
+
+    /// `X-RateLimit-*` headers in the format OpenAI uses, with resets in seconds, plus
+    /// `Retry-After` for refused requests.
+    pub fn headers(&self) -> HeaderMap {
+        let limits = &self.limits;
+        let values = [
+            (
+                "x-ratelimit-limit-requests",
+                limits.requests_per_minute as u64,
+            ),
+            (
+                "x-ratelimit-remaining-requests",
+                limits.requests_per_minute.saturating_sub(self.requests) as u64,
+            ),
+            ("x-ratelimit-reset-requests", self.reset),
+            ("x-ratelimit-limit-tokens", limits.tokens_per_minute),
+            (
+                "x-ratelimit-remaining-tokens",
+                limits.tokens_per_minute.saturating_sub(self.tokens),
+            ),
+            ("x-ratelimit-reset-tokens", self.reset),
+        ];
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(name, HeaderValue::from(value));
+        }
+        if let Some(after) = self.retry_after() {
+            headers.insert("retry-after", HeaderValue::from(after));
+        }
+        headers
+    }
+}
+
+/// A request's slot among those its key can run at once on the model. Dropping it gives the
+/// slot back and counts the tokens passed to `used`.
+pub struct Permit {
+    pub status: RateLimit,
+    redis: ConnectionManager,
+    tokens_key: String,
+    running_key: String,
+    tokens: u64,
+}
+
+impl Permit {
+    pub fn used(&mut self, tokens: u64) {
+        self.tokens = tokens;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let mut redis = self.redis.clone();
+        let tokens_key = std::mem::take(&mut self.tokens_key);
+        let running_key = std::mem::take(&mut self.running_key);
+        let tokens = self.tokens;
+
+        runtime.spawn(async move {
+            let released: redis::RedisResult<()> = RELEASE
+                .key(tokens_key)
+                .key(running_key)
+                .arg(tokens)
+                .arg(WINDOW_SECS * 2)
+                .invoke_async(&mut redis)
+                .await;
+            if let Err(e) = released {
+                eprintln!("Could not release rate limit slot: {}", e);
+            }
+        });
+    }
+}
+
+/// Counts a request of the key to `model` against `limits`. Nothing is counted if one of
+/// them is reached, the request fails with `OneLlmError::RateLimited` instead.
+pub async fn acquire(
+    redis: &ConnectionManager,
+    api_key_id: i32,
+    model: &Model,
+    limits: PlanLimits,
+) -> Result<Permit, OneLlmError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    acquire_at(redis, api_key_id, model, limits, now).await
+}
+
+/// `acquire` at `now`, in seconds since the Unix epoch, which picks the window the request and
+/// its tokens are counted in.
+pub async fn acquire_at(
+    redis: &ConnectionManager,
+    api_key_id: i32,
+    model: &Model,
+    limits: PlanLimits,
+    now: u64,
+) -> Result<Permit, OneLlmError> {
+    let window = now / WINDOW_SECS;
+    // The braces keep a key's counters in one cluster slot, as scripts require
+    let tokens_key = format!("ratelimit:{{{}}}:tokens:{}", api_key_id, window);
+    let running_key = format!("ratelimit:{{{}}}:running:{}", api_key_id, model);
+    let mut redis = redis.clone();
+
+    let (exceeded, requests, tokens): (u8, u32, u64) = ACQUIRE
+        .key(format!("ratelimit:{{{}}}:requests:{}", api_key_id, window))
+        .key(&tokens_key)
+        .key(&running_key)
+        .arg(limits.requests_per_minute)
+        .arg(limits.tokens_per_minute)
+        .arg(limits.concurrent_per_model)
+        .arg(WINDOW_SECS * 2)
+        .arg(SLOT_TTL_SECS)
+        .invoke_async(&mut redis)
+        .await?;
+
+    let status = RateLimit {
+        limits,
+        requests,
+        tokens,
+        reset: WINDOW_SECS - now % WINDOW_SECS,
+        exceeded: match exceeded {
+            1 => Some(Limit::Requests),
+            2 => Some(Limit::Tokens),
+            3 => Some(Limit::Concurrency),
+            _ => None,
+        },
+    };
+    if status.exceeded.is_some() {
+        return Err(OneLlmError::RateLimited(status));
+    }
+
+    Ok(Permit {
+        status,
+        redis,
+        tokens_key,
+        running_key,
+        tokens: 0,
+    })
+}
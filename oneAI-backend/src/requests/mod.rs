@@ -1,3 +1,6 @@
 pub mod parseapi;
+pub mod provider;
+#[allow(clippy::module_inception)]
 pub mod requests;
 pub mod responseparser;
+pub mod stream;
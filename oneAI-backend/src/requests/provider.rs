@@ -0,0 +1,146 @@
+use std::{collections::HashMap, error::Error, fmt, sync::LazyLock};
+
+use reqwest::RequestBuilder;
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    error::OneLlmError,
+    pricing::Model,
+    requests::{
+        parseapi::{APIInput, ContentPart},
+        requests::AIProvider,
+        responseparser::common::{LlmStreamChunk, LlmUnifiedResponse},
+        stream::SseEvent,
+    },
+};
+
+/// An error reported by a provider, with the HTTP status it answered with.
+#[derive(Debug)]
+pub struct ProviderError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl Error for ProviderError {}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Provider returned {}: {}", self.status, self.message)
+    }
+}
+
+/// Everything OneLLM needs to know to talk to one LLM provider. Each implementation lives
+/// next to that provider's response structs in `responseparser`.
+pub trait Provider: Send + Sync {
+    /// Environment variable holding OneLLM's own key for this provider. Lowercased, it is also
+    /// the provider's `[providers.<name>]` section in the config file.
+    fn key_var(&self) -> &'static str;
+
+    /// The provider's public API, used unless the config or `<KEY_VAR>_BASE_URL` points
+    /// elsewhere.
+    fn default_base_url(&self) -> &'static str;
+
+    /// The URL to POST a request for `model` to. Most providers use one chat endpoint for
+    /// every model and mode.
+    fn endpoint(&self, base_url: &str, _model: &Model, _stream: bool) -> String {
+        format!("{}/chat/completions", base_url)
+    }
+
+    /// Attaches OneLLM's provider key to the request.
+    fn authenticate(&self, request: RequestBuilder, key: &str) -> RequestBuilder {
+        request.bearer_auth(key)
+    }
+
+    /// Rejects a content part the provider can't take in the form it was sent, e.g. media by
+    /// URL. Whether the model handles images or documents at all is checked before this.
+    fn check_part(&self, _part: &ContentPart) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Translates a OneLLM request into the provider's request body.
+    fn build_request(&self, input: &APIInput, max_tokens: u32) -> Value;
+
+    fn parse_response(&self, body: &str) -> Result<LlmUnifiedResponse, serde_json::Error>;
+
+    /// Parses one server-sent event, `None` for events that carry nothing for the client.
+    /// `model` is the model name seen so far in the stream.
+    fn parse_stream_event(
+        &self,
+        event: &SseEvent,
+        model: &str,
+    ) -> Result<Option<LlmStreamChunk>, String>;
+
+    /// Extracts the message from a non-success response. Most providers use the OpenAI
+    /// `{"error": {"message": ...}}` shape.
+    fn map_error(&self, status: u16, body: &str) -> ProviderError {
+        let message = serde_json::from_str::<Value>(body)
+            .ok()
+            .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+            .unwrap_or_else(|| body.to_string());
+
+        ProviderError { status, message }
+    }
+}
+
+static PROVIDERS: LazyLock<HashMap<AIProvider, Box<dyn Provider>>> = LazyLock::new(|| {
+    #[allow(unused_mut)]
+    let mut providers: HashMap<AIProvider, Box<dyn Provider>> = HashMap::new();
+
+    #[cfg(feature = "openai")]
+    providers.insert(
+        AIProvider::OpenAI,
+        Box::new(crate::requests::responseparser::openai::OpenAI),
+    );
+    #[cfg(feature = "anthropic")]
+    providers.insert(
+        AIProvider::Anthropic,
+        Box::new(crate::requests::responseparser::anthropic::Anthropic),
+    );
+    #[cfg(feature = "gemini")]
+    providers.insert(
+        AIProvider::Gemini,
+        Box::new(crate::requests::responseparser::gemini::Gemini),
+    );
+    #[cfg(feature = "deepseek")]
+    providers.insert(
+        AIProvider::DeepSeek,
+        Box::new(crate::requests::responseparser::deepseek::DeepSeek),
+    );
+    #[cfg(feature = "mistral")]
+    providers.insert(
+        AIProvider::Mistral,
+        Box::new(crate::requests::responseparser::mistral::Mistral),
+    );
+
+    providers
+});
+
+/// Every provider compiled into this server.
+pub fn enabled_providers() -> impl Iterator<Item = &'static dyn Provider> {
+    PROVIDERS.values().map(|provider| provider.as_ref())
+}
+
+/// The URL a request for `model` is sent to. Base URLs are owned by the server so that callers
+/// can never direct requests carrying OneLLM's provider keys to a host of their choosing.
+pub fn provider_url(config: &Config, model: &Model, stream: bool) -> Result<String, OneLlmError> {
+    let kind = model.provider();
+    let provider = get_provider(kind)?;
+    let base_url = config
+        .provider(kind)
+        .and_then(|c| c.base_url.as_deref())
+        .unwrap_or(provider.default_base_url());
+
+    Ok(provider.endpoint(base_url, model, stream))
+}
+
+/// Looks up the implementation for `kind`, failing if it was compiled out.
+pub fn get_provider(kind: AIProvider) -> Result<&'static dyn Provider, OneLlmError> {
+    match PROVIDERS.get(&kind) {
+        Some(provider) => Ok(provider.as_ref()),
+        None => Err(OneLlmError::InvalidRequest(format!(
+            "{:?} is not enabled on this server",
+            kind
+        ))),
+    }
+}
@@ -1,132 +1,376 @@
 #![allow(non_snake_case)]
 use crate::{
-    auth::basicauth::update_bal,
-    requests::responseparser::{
-        anthropic::ClaudeMessageResponse, common::LlmUnifiedResponse, deepseek::DeepSeekResponse,
-        gemini::GeminiResponse, openai::OpenAIResponse,
+    compat::response_id,
+    error::OneLlmError,
+    ledger::{Hold, Placement},
+    pricing::{Model, Pricing},
+    ratelimit::{self, Permit, RateLimit},
+    requests::{
+        parseapi::{ContentPart, MediaSource},
+        provider::{Provider, get_provider, provider_url},
+        responseparser::common::{LlmUnifiedResponse, LlmUsage},
+        stream::{StreamEvent, drive_stream},
     },
 };
 
-use reqwest::Error;
-use serde::{Deserialize, Serialize};
-use serde_json::from_str;
+use reqwest::RequestBuilder;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
 
 use crate::{
-    database::init_pool,
-    requests::{parseapi::APIInput, responseparser::mistral::MistralResponse},
-    utils::User,
+    requests::parseapi::APIInput,
+    state::AppState,
+    usage::{self, UsageEvent},
+    utils::{ApiKey, Scope, User},
 };
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum AIProvider {
-    OpenAI,
-    Anthropic,
-    Gemini,
-    DeepSeek,
-    Mistral,
+pub use onellm_types::AIProvider;
+
+/// Prompt tokens assumed for each image and message when estimating a request's cost.
+const IMAGE_TOKENS: u64 = 1_600;
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+/// The least a PDF is assumed to cost, larger ones are estimated from their size.
+const DOCUMENT_TOKENS: u64 = 3_000;
+
+/// What `input_tokens` and `output_tokens` cost on `model`, in micro-dollars.
+pub fn cost(model: &Model, input_tokens: u32, output_tokens: u32) -> i64 {
+    model.input_price() as i64 * input_tokens as i64
+        + model.output_price() as i64 * output_tokens as i64
 }
 
-impl APIInput {
-    pub async fn get(
-        &self,
-        onellm_apikey: String,
-    ) -> Result<LlmUnifiedResponse, Box<dyn std::error::Error>> {
-        dotenv::dotenv().ok();
-
-        let pool = init_pool().await?;
-
-        let mut endpoint = self.endpoint.clone();
-        let apikey = match self.model.provider() {
-            AIProvider::OpenAI => std::env::var("OPENAI").expect("Error getting OPENAI apikey"),
-            AIProvider::Anthropic => std::env::var("CLAUDE").expect("Error getting CLAUDE apikey"),
-            AIProvider::Gemini => {
-                let key = std::env::var("GEMINI").expect("Error getting GEMINI apikey");
-                endpoint += &format!("?key={}", key);
-                key
-            }
-            AIProvider::DeepSeek => std::env::var("DEEPSEEK").expect("Error getting DS apikey"),
-            AIProvider::Mistral => std::env::var("MISTRAL").expect("Error getting Mistral APIKEY"),
-        };
-        let client = reqwest::Client::new();
+/// Debits the user for the tokens reported in `usage` and releases the request's hold.
+/// Without a usage report the whole hold is charged. Returns the amount charged.
+pub async fn charge(
+    pool: &PgPool,
+    model: &Model,
+    hold: &Hold,
+    usage: Option<&LlmUsage>,
+) -> Result<i64, OneLlmError> {
+    let amount = match usage {
+        Some(usage) => cost(
+            model,
+            usage.input_tokens.unwrap_or(0),
+            usage.output_tokens.unwrap_or(0),
+        ),
+        None => hold.amount,
+    };
 
-        let mut max_tokens = self.max_tokens;
+    hold.settle(pool, amount).await?;
 
-        let user = User::get_row_api(Some(pool.clone()), onellm_apikey).await?;
+    Ok(amount)
+}
 
-        if user.balance <= 1000000 {
-            return Err(
-                "Insufficient balance, please topup your balance to continue using OneLLM".into(),
-            );
-        }
+/// Releases the hold of a request that failed before anything could be charged for it.
+pub async fn release(pool: &PgPool, hold: &Hold) {
+    if let Err(e) = hold.release(pool).await {
+        eprintln!("Could not release hold {}: {}", hold.request_id, e);
+    }
+}
 
-        let price = self.model.output_price();
-        let max_allowed = (user.balance as u64) * (1000000 / price as u64);
+/// Stores the usage event of a finished request. Failing to do so doesn't fail the request.
+pub async fn log_usage(pool: &PgPool, event: &UsageEvent) {
+    if let Err(e) = event.record(pool).await {
+        eprintln!("Could not record usage of {}: {}", event.request_id, e);
+    }
+}
 
-        if max_allowed < max_tokens as u64 {
-            max_tokens = max_allowed as u32;
-        }
+/// Running an `APIInput` against its provider. A trait since the type is shared with the client
+/// in `onellm-types`.
+pub trait Completion {
+    /// Rejects images and documents the model can't read, or that its provider can't take in
+    /// the form they were sent, before anything is billed or sent upstream. So are requests for
+    /// more than one choice, since only the first would be returned but all would be billed.
+    fn check_content(&self, provider: &dyn Provider) -> Result<(), String>;
 
-        let request = &self.clone().into_provider_request(max_tokens).await;
+    /// A deliberately generous guess of the prompt's size, since providers only report the
+    /// real count afterwards. Text is counted at about three characters per token.
+    fn estimate_input_tokens(&self) -> u32;
 
-        let resp = client.post(endpoint).json(request);
+    /// Runs a completion, returned with the key's rate limits after it.
+    async fn get(
+        &self,
+        state: &AppState,
+        user: &User,
+        key: &ApiKey,
+    ) -> Result<(LlmUnifiedResponse, RateLimit), OneLlmError>;
 
-        let output: Result<String, Error>;
+    /// Starts a streamed completion. Chunks are produced by a background task which also
+    /// bills the user and records the request's usage once the provider reports the final usage.
+    /// The key's rate limits are returned with the receiver.
+    async fn stream(
+        &self,
+        state: &AppState,
+        user: &User,
+        key: &ApiKey,
+    ) -> Result<(mpsc::Receiver<StreamEvent>, RateLimit), OneLlmError>;
+}
 
-        match self.model.provider() {
-            AIProvider::Gemini => {
-                output = resp.send().await?.text().await;
-                //                return Err("Gemini isn't available at this moment (OneLLM's response)".into());
-            }
-            AIProvider::Anthropic => {
-                output = resp
-                    .header("x-api-key", apikey)
-                    .header("anthropic-version", "2023-06-01")
-                    .send()
-                    .await?
-                    .text()
-                    .await;
-            }
-            _ => {
-                output = resp.bearer_auth(apikey).send().await?.text().await;
-                dbg!(&output);
+impl Completion for APIInput {
+    fn check_content(&self, provider: &dyn Provider) -> Result<(), String> {
+        if self.n.is_some_and(|n| n > 1) {
+            return Err("n greater than 1 is not supported".to_string());
+        }
+        if let Some(config) = &self.generation_config
+            && config.candidate_count > 1
+        {
+            return Err("candidate_count greater than 1 is not supported".to_string());
+        }
+
+        for part in self.messages.iter().flat_map(|m| m.content.parts()) {
+            match part {
+                ContentPart::Image { .. } if !self.model.supports_images() => {
+                    return Err(format!("{} does not accept images", self.model.name()));
+                }
+                ContentPart::Document { .. } if !self.model.supports_documents() => {
+                    return Err(format!("{} does not accept documents", self.model.name()));
+                }
+                _ => provider.check_part(part)?,
             }
         }
+        Ok(())
+    }
+
+    fn estimate_input_tokens(&self) -> u32 {
+        let chars = |text: &str| text.chars().count() as u64;
 
-        let unified_response: LlmUnifiedResponse = match self.model.provider() {
-            AIProvider::OpenAI => {
-                let openai: OpenAIResponse = from_str(&output?)?;
-                openai.into()
+        let mut tokens: u64 = self.system.as_deref().map(chars).unwrap_or(0) / 3;
+        for message in &self.messages {
+            tokens += MESSAGE_OVERHEAD_TOKENS;
+            for part in message.content.parts() {
+                tokens += match part {
+                    ContentPart::Text { text } => chars(text) / 3 + 1,
+                    ContentPart::Image { .. } => IMAGE_TOKENS,
+                    ContentPart::Document { source } => match source {
+                        MediaSource::Base64 { data, .. } => {
+                            (data.len() as u64 / 16).max(DOCUMENT_TOKENS)
+                        }
+                        MediaSource::Url { .. } => DOCUMENT_TOKENS,
+                    },
+                };
             }
-            AIProvider::Anthropic => {
-                let claude: ClaudeMessageResponse = from_str(&output?)?;
-                claude.into()
+        }
+        // Sent to Gemini as they are, in place of the messages
+        for content in self.contents.iter().flatten() {
+            tokens += MESSAGE_OVERHEAD_TOKENS;
+            for part in &content.parts {
+                tokens += chars(&part.text) / 3 + 1;
             }
-            AIProvider::Mistral => {
-                let mistral: MistralResponse = from_str(&output?)?;
-                mistral.into()
+        }
+        if let Some(tools) = &self.tools {
+            tokens += serde_json::to_string(tools).map(|t| chars(&t)).unwrap_or(0) / 3;
+        }
+
+        tokens.min(u32::MAX as u64) as u32
+    }
+
+    async fn get(
+        &self,
+        state: &AppState,
+        user: &User,
+        key: &ApiKey,
+    ) -> Result<(LlmUnifiedResponse, RateLimit), OneLlmError> {
+        let provider = get_provider(self.model.provider())?;
+
+        let (hold, resp, mut permit) = prepare(self, state, user, key).await?;
+
+        let mut event = UsageEvent::start(&hold, &self.model);
+
+        let result = match complete(provider, resp).await {
+            Ok(response) => charge(&state.pool, &self.model, &hold, response.usage.as_ref())
+                .await
+                .map(|cost| {
+                    event.succeeded(
+                        response.usage.as_ref(),
+                        cost,
+                        response.finish_reason.as_deref(),
+                    );
+                    response
+                }),
+            Err(e) => {
+                release(&state.pool, &hold).await;
+                Err(e)
             }
-            AIProvider::Gemini => {
-                let gemini: GeminiResponse = from_str(&output?)?;
+        };
+
+        if let Err(e) = &result {
+            event.failed(e);
+        }
+        log_usage(&state.pool, &event).await;
+        permit.used(event.input_tokens as u64 + event.output_tokens as u64);
+
+        result.map(|response| (response, permit.status.clone()))
+    }
+
+    async fn stream(
+        &self,
+        state: &AppState,
+        user: &User,
+        key: &ApiKey,
+    ) -> Result<(mpsc::Receiver<StreamEvent>, RateLimit), OneLlmError> {
+        let provider = get_provider(self.model.provider())?;
 
-                gemini.into()
+        let (hold, resp, permit) = prepare(self, state, user, key).await?;
+        let mut event = UsageEvent::start(&hold, &self.model);
+
+        let failure = match resp.send().await {
+            Ok(r) if r.status().is_success() => Ok(r),
+            Ok(r) => {
+                let status = r.status();
+                let body = r.text().await.unwrap_or_default();
+                Err(provider.map_error(status.as_u16(), &body).into())
             }
-            AIProvider::DeepSeek => {
-                let deepseek: DeepSeekResponse = from_str(&output?)?;
-                deepseek.into()
+            Err(e) => Err(OneLlmError::from(e)),
+        };
+        let response = match failure {
+            Ok(r) => r,
+            Err(e) => {
+                release(&state.pool, &hold).await;
+                event.failed(&e);
+                log_usage(&state.pool, &event).await;
+                return Err(e);
             }
         };
-        let usage = unified_response.usage.as_ref().unwrap();
 
-        let input_cost = self.model.input_price() * usage.input_tokens.unwrap();
-        let output_cost = self.model.output_price() * usage.output_tokens.unwrap();
+        let (tx, rx) = mpsc::channel(64);
+        let limits = permit.status.clone();
+        tokio::spawn(drive_stream(
+            response,
+            provider,
+            self.model.clone(),
+            state.pool.clone(),
+            hold,
+            permit,
+            event,
+            tx,
+        ));
+
+        Ok((rx, limits))
+    }
+}
+
+/// Checks the balance of the key's organization and then its rate limits, builds the
+/// authenticated provider request and places a hold for its worst-case cost. The hold must
+/// be settled or released by the caller, and the permit kept until the request is done.
+/// Requests refused here are recorded in `usage_events` too.
+async fn prepare(
+    input: &APIInput,
+    state: &AppState,
+    user: &User,
+    key: &ApiKey,
+) -> Result<(Hold, RequestBuilder, Permit), OneLlmError> {
+    let prepared = reserve(input, state, user, key).await;
+    if let Err(e) = &prepared {
+        let event = UsageEvent::rejected(user.id, Some(key.id), &input.model, e);
+        log_usage(&state.pool, &event).await;
+    }
+    prepared
+}
 
-        let total_cost = input_cost + output_cost;
+async fn reserve(
+    input: &APIInput,
+    state: &AppState,
+    user: &User,
+    key: &ApiKey,
+) -> Result<(Hold, RequestBuilder, Permit), OneLlmError> {
+    let provider = get_provider(input.model.provider())?;
+    input
+        .check_content(provider)
+        .map_err(OneLlmError::InvalidRequest)?;
+    let config = &state.config;
+    let apikey = &config
+        .provider(input.model.provider())
+        .ok_or_else(|| {
+            OneLlmError::Internal(format!("Error getting {} apikey", provider.key_var()))
+        })?
+        .key;
+    let endpoint = provider_url(config, &input.model, input.stream.unwrap_or(false))?;
 
-        // This cast is safe only if total_cost <= i32::MAX
-        let update_val = -(total_cost as i32);
-        match update_bal(Some(pool), user.email, update_val).await {
-            Some(_) => Ok(unified_response),
-            None => Err("An Unexpected error occurred".into()),
+    if !key.allows(Scope::Chat) || !key.allows(Scope::Provider(input.model.provider())) {
+        return Err(OneLlmError::Forbidden(format!(
+            "This API key does not have the scopes to use {}",
+            input.model
+        )));
+    }
+    if let Some(models) = &key.limits.allowed_models
+        && !models.contains(&input.model)
+    {
+        return Err(OneLlmError::Forbidden(format!(
+            "This API key is not allowed to use {}",
+            input.model
+        )));
+    }
+
+    if key.balance <= config.limits.min_balance {
+        return Err(OneLlmError::InsufficientBalance);
+    }
+
+    // Output is clamped to what the balance, or the key's budget if that's lower, can pay
+    // for after the prompt, and the worst case of both is held until the provider reports
+    // the real usage
+    let budget = usage::key_budget_left(&state.pool, key).await?;
+    let spendable = budget.map_or(key.balance, |b| b.min(key.balance));
+    let input_tokens = input.estimate_input_tokens();
+    let affordable =
+        (spendable - cost(&input.model, input_tokens, 0)) / input.model.output_price() as i64;
+    if affordable < 1 {
+        return Err(match budget {
+            Some(b) if b < key.balance => OneLlmError::BudgetExceeded,
+            _ => OneLlmError::InsufficientBalance,
+        });
+    }
+    let max_tokens = input.max_tokens.min(affordable.min(u32::MAX as i64) as u32);
+
+    let hold = Hold {
+        organization_id: key.organization_id,
+        user_id: user.id,
+        api_key_id: Some(key.id),
+        request_id: response_id("req_"),
+        amount: cost(&input.model, input_tokens, max_tokens),
+    };
+    match hold.place(&state.pool, &key.limits).await? {
+        Placement::Placed => {}
+        Placement::InsufficientBalance => return Err(OneLlmError::InsufficientBalance),
+        // Another request of the key took what was left of its budget since it was checked
+        Placement::BudgetExceeded => return Err(OneLlmError::BudgetExceeded),
+    }
+
+    // Only requests that could be paid for count against the rate limits, the hold is given
+    // back if the limits refuse it
+    let permit = match ratelimit::acquire(
+        &state.redis,
+        key.id,
+        &input.model,
+        config.limits.plan(&key.plan),
+    )
+    .await
+    {
+        Ok(permit) => permit,
+        Err(e) => {
+            release(&state.pool, &hold).await;
+            return Err(e);
         }
+    };
+
+    let request = provider.build_request(input, max_tokens);
+
+    let resp = provider.authenticate(state.http.post(endpoint).json(&request), apikey);
+
+    Ok((hold, resp, permit))
+}
+
+/// Sends the request and parses a successful answer.
+async fn complete(
+    provider: &dyn Provider,
+    resp: RequestBuilder,
+) -> Result<LlmUnifiedResponse, OneLlmError> {
+    let response = resp.send().await?;
+    let status = response.status();
+    let output = response.text().await?;
+    if !status.is_success() {
+        return Err(provider.map_error(status.as_u16(), &output).into());
     }
+
+    provider
+        .parse_response(&output)
+        .map_err(|e| OneLlmError::Internal(format!("Error parsing provider response: {}", e)))
 }
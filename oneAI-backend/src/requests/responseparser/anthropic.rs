@@ -1,5 +1,17 @@
-use crate::requests::responseparser::common::{LlmUnifiedResponse, LlmUsage};
+use crate::{
+    pricing::Model,
+    requests::{
+        parseapi::{APIInput, Message, MessageContent, ToolChoice, ToolMode},
+        provider::{Provider, ProviderError},
+        responseparser::common::{
+            LlmStreamChunk, LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta,
+        },
+        stream::SseEvent,
+    },
+};
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClaudeMessageResponse {
@@ -15,9 +27,19 @@ pub struct ClaudeMessageResponse {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct ClaudeContent {
-    pub r#type: String,
-    pub text: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClaudeContent {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    // Thinking blocks and anything newer are not passed on
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,20 +48,94 @@ pub struct ClaudeUsage {
     pub output_tokens: u32,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClaudeStreamEvent {
+    MessageStart {
+        message: ClaudeStreamMessage,
+    },
+    ContentBlockStart {
+        index: u32,
+        content_block: ClaudeContent,
+    },
+    ContentBlockDelta {
+        index: u32,
+        delta: ClaudeContentDelta,
+    },
+    MessageDelta {
+        delta: ClaudeMessageDelta,
+        usage: Option<ClaudeDeltaUsage>,
+    },
+    Error {
+        error: ClaudeStreamError,
+    },
+    // ping, content_block_stop and message_stop carry nothing we forward
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaudeStreamMessage {
+    pub id: String,
+    pub model: String,
+    pub usage: ClaudeUsage,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClaudeContentDelta {
+    TextDelta {
+        text: String,
+    },
+    // A piece of a tool call's arguments, the pieces together form a JSON document
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaudeMessageDelta {
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaudeDeltaUsage {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaudeStreamError {
+    pub r#type: String,
+    pub message: String,
+}
+
 impl From<ClaudeMessageResponse> for LlmUnifiedResponse {
     fn from(res: ClaudeMessageResponse) -> Self {
-        let content = res
-            .content
-            .iter()
-            .map(|c| c.text.clone())
-            .collect::<Vec<_>>()
-            .join("\n");
+        let mut text = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in res.content {
+            match block {
+                ClaudeContent::Text { text: t } => text.push(t),
+                ClaudeContent::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id,
+                    name,
+                    arguments: input,
+                }),
+                ClaudeContent::Other => {}
+            }
+        }
+        let content = text.join("\n");
 
         LlmUnifiedResponse {
             provider: "Claude".into(),
             model: res.model,
             role: Some(res.role),
             content,
+            tool_calls,
             usage: Some(LlmUsage {
                 input_tokens: Some(res.usage.input_tokens),
                 output_tokens: Some(res.usage.output_tokens),
@@ -49,3 +145,246 @@ impl From<ClaudeMessageResponse> for LlmUnifiedResponse {
         }
     }
 }
+
+impl ClaudeStreamEvent {
+    /// Converts an event into a unified chunk, `None` for events that carry no data for the client.
+    /// The model name is only sent in `message_start`, so it is passed in from the stream driver.
+    pub fn into_chunk(self, model: &str) -> Result<Option<LlmStreamChunk>, String> {
+        let mut chunk = LlmStreamChunk {
+            provider: "Claude".into(),
+            model: model.to_string(),
+            ..Default::default()
+        };
+
+        match self {
+            ClaudeStreamEvent::MessageStart { message } => {
+                chunk.model = message.model;
+                chunk.usage = Some(LlmUsage {
+                    input_tokens: Some(message.usage.input_tokens),
+                    output_tokens: Some(message.usage.output_tokens),
+                    total_tokens: None,
+                });
+            }
+            ClaudeStreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => match content_block {
+                ClaudeContent::ToolUse { id, name, .. } => {
+                    chunk.tool_calls.push(ToolCallDelta {
+                        index,
+                        id: Some(id),
+                        name: Some(name),
+                        arguments: None,
+                    });
+                }
+                _ => return Ok(None),
+            },
+            ClaudeStreamEvent::ContentBlockDelta { index, delta } => match delta {
+                ClaudeContentDelta::TextDelta { text } => chunk.delta = Some(text),
+                ClaudeContentDelta::InputJsonDelta { partial_json } => {
+                    chunk.tool_calls.push(ToolCallDelta {
+                        index,
+                        arguments: Some(partial_json),
+                        ..Default::default()
+                    });
+                }
+                ClaudeContentDelta::Other => return Ok(None),
+            },
+            ClaudeStreamEvent::MessageDelta { delta, usage } => {
+                chunk.finish_reason = delta.stop_reason;
+                chunk.usage = usage.map(|u| LlmUsage {
+                    input_tokens: u.input_tokens,
+                    output_tokens: u.output_tokens,
+                    total_tokens: None,
+                });
+            }
+            ClaudeStreamEvent::Error { error } => {
+                return Err(format!("{}: {}", error.r#type, error.message));
+            }
+            ClaudeStreamEvent::Other => return Ok(None),
+        }
+
+        Ok(Some(chunk))
+    }
+}
+
+/// Claude's content for one of our messages. Our content parts already have the shape of
+/// Claude's blocks. Tool calls become `tool_use` blocks and tool results `tool_result` blocks,
+/// which Claude expects in a user turn.
+fn claude_content(msg: &Message) -> Value {
+    if msg.role == "tool" {
+        return json!([{
+            "type": "tool_result",
+            "tool_use_id": msg.tool_call_id,
+            "content": msg.content.text(),
+        }]);
+    }
+
+    let mut blocks = match &msg.content {
+        MessageContent::Text(text) if msg.tool_calls.is_empty() => return json!(text),
+        MessageContent::Text(text) if text.is_empty() => Vec::new(),
+        MessageContent::Text(text) => vec![json!({ "type": "text", "text": text })],
+        MessageContent::Parts(parts) => parts.iter().map(|part| json!(part)).collect(),
+    };
+    for call in &msg.tool_calls {
+        blocks.push(json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.name,
+            "input": call.arguments,
+        }));
+    }
+    json!(blocks)
+}
+
+/// Our `tool_choice` as Claude's object. Claude calls `required` "any".
+fn claude_tool_choice(choice: &ToolChoice) -> Value {
+    match choice {
+        ToolChoice::Mode(ToolMode::Auto) => json!({ "type": "auto" }),
+        ToolChoice::Mode(ToolMode::None) => json!({ "type": "none" }),
+        ToolChoice::Mode(ToolMode::Required) => json!({ "type": "any" }),
+        ToolChoice::Tool { name } => json!({ "type": "tool", "name": name }),
+    }
+}
+
+#[cfg(feature = "anthropic")]
+pub struct Anthropic;
+
+#[cfg(feature = "anthropic")]
+impl Provider for Anthropic {
+    fn key_var(&self) -> &'static str {
+        "CLAUDE"
+    }
+
+    fn default_base_url(&self) -> &'static str {
+        "https://api.anthropic.com/v1"
+    }
+
+    fn endpoint(&self, base_url: &str, _model: &Model, _stream: bool) -> String {
+        format!("{}/messages", base_url)
+    }
+
+    fn authenticate(&self, request: RequestBuilder, key: &str) -> RequestBuilder {
+        request
+            .header("x-api-key", key)
+            .header("anthropic-version", "2023-06-01")
+    }
+
+    fn build_request(&self, input: &APIInput, max_tokens: u32) -> Value {
+        // Claude only accepts the system prompt as a top level field
+        let mut system = input.system.clone();
+        let mut messages: Vec<Value> = Vec::new();
+        for msg in &input.messages {
+            if msg.role == "system" {
+                system = Some(match system {
+                    Some(s) => format!("{}\n{}", s, msg.content.text()),
+                    None => msg.content.text(),
+                });
+                continue;
+            }
+
+            let content = claude_content(msg);
+            if msg.role == "tool" {
+                // Results of parallel calls have to arrive together in a single user turn
+                if let Some(last) = messages.last_mut()
+                    && last["role"] == "user"
+                    && let Some(blocks) = last["content"].as_array_mut()
+                {
+                    blocks.extend(content.as_array().unwrap().iter().cloned());
+                    continue;
+                }
+                messages.push(json!({ "role": "user", "content": content }));
+                continue;
+            }
+
+            messages.push(json!({
+                "role": msg.role,
+                "content": content
+            }));
+        }
+
+        let mut req = json!({
+            "model": input.model.name(),
+            "messages": messages,
+            "max_tokens": max_tokens,
+            "temperature": input.temperature,
+            "top_p": input.top_p,
+            "stream": input.stream.unwrap_or(false),
+        });
+
+        if let Some(system) = system {
+            req.as_object_mut()
+                .unwrap()
+                .insert("system".into(), json!(system));
+        }
+
+        if let Some(stop_sequences) = &input.stop_sequences {
+            req.as_object_mut()
+                .unwrap()
+                .insert("stop_sequences".into(), json!(stop_sequences));
+        }
+
+        if let Some(top_k) = input.top_k {
+            req.as_object_mut()
+                .unwrap()
+                .insert("top_k".into(), json!(top_k));
+        }
+
+        if let Some(tools) = &input.tools
+            && !tools.is_empty()
+        {
+            let tools: Vec<Value> = tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.function.name,
+                        "description": tool.function.description,
+                        "input_schema": tool.function.parameters,
+                    })
+                })
+                .collect();
+            req.as_object_mut()
+                .unwrap()
+                .insert("tools".into(), json!(tools));
+        }
+
+        if let Some(choice) = &input.tool_choice {
+            req.as_object_mut()
+                .unwrap()
+                .insert("tool_choice".into(), claude_tool_choice(choice));
+        }
+        req
+    }
+
+    fn parse_response(&self, body: &str) -> Result<LlmUnifiedResponse, serde_json::Error> {
+        let claude: ClaudeMessageResponse = serde_json::from_str(body)?;
+        Ok(claude.into())
+    }
+
+    fn parse_stream_event(
+        &self,
+        event: &SseEvent,
+        model: &str,
+    ) -> Result<Option<LlmStreamChunk>, String> {
+        serde_json::from_str::<ClaudeStreamEvent>(&event.data)
+            .map_err(|e| e.to_string())?
+            .into_chunk(model)
+    }
+
+    fn map_error(&self, status: u16, body: &str) -> ProviderError {
+        // {"type": "error", "error": {"type": "...", "message": "..."}}
+        let message = serde_json::from_str::<Value>(body)
+            .ok()
+            .and_then(|v| {
+                let error = &v["error"];
+                Some(format!(
+                    "{}: {}",
+                    error["type"].as_str()?,
+                    error["message"].as_str()?
+                ))
+            })
+            .unwrap_or_else(|| body.to_string());
+
+        ProviderError { status, message }
+    }
+}
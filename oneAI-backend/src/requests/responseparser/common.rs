@@ -1,18 +1,3 @@
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LlmUnifiedResponse {
-    pub provider: String,
-    pub model: String,
-    pub role: Option<String>,
-    pub content: String,
-    pub usage: Option<LlmUsage>,
-    pub finish_reason: Option<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LlmUsage {
-    pub input_tokens: Option<u32>,
-    pub output_tokens: Option<u32>,
-    pub total_tokens: Option<u32>,
-}
+pub use onellm_types::response::{
+    LlmStreamChunk, LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta,
+};
@@ -1,5 +1,19 @@
-use crate::requests::responseparser::common::{LlmUnifiedResponse, LlmUsage};
+use crate::{
+    pricing::Model,
+    requests::{
+        parseapi::APIInput,
+        provider::Provider,
+        responseparser::{
+            common::{LlmStreamChunk, LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta},
+            openai::{
+                OpenAIToolCall, OpenAIToolCallDelta, insert_tools, openai_messages, openai_part,
+            },
+        },
+        stream::SseEvent,
+    },
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeepSeekResponse {
@@ -21,7 +35,32 @@ pub struct DeepSeekChoice {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeepSeekMessage {
     pub role: String,
-    pub content: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    // Same shape as OpenAI's tool calls
+    pub tool_calls: Option<Vec<OpenAIToolCall>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeepSeekStreamChunk {
+    pub id: String,
+    pub model: String,
+    pub choices: Vec<DeepSeekStreamChoice>,
+    pub usage: Option<DeepSeekUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeepSeekStreamChoice {
+    pub index: u32,
+    pub delta: DeepSeekDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeepSeekDelta {
+    pub role: Option<String>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<OpenAIToolCallDelta>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,18 +76,24 @@ impl From<DeepSeekResponse> for LlmUnifiedResponse {
         let (role, content, finish_reason) = if let Some(c) = choice {
             (
                 Some(c.message.role.clone()),
-                c.message.content.clone(),
+                c.message.content.clone().unwrap_or_default(),
                 c.finish_reason.clone(),
             )
         } else {
             (None, String::new(), None)
         };
 
+        let tool_calls = choice
+            .and_then(|c| c.message.tool_calls.as_ref())
+            .map(|calls| calls.iter().map(ToolCall::from).collect())
+            .unwrap_or_default();
+
         LlmUnifiedResponse {
             provider: "DeepSeek".into(),
             model: res.model,
             role,
             content,
+            tool_calls,
             usage: Some(LlmUsage {
                 input_tokens: Some(res.usage.prompt_tokens),
                 output_tokens: Some(res.usage.completion_tokens),
@@ -58,3 +103,89 @@ impl From<DeepSeekResponse> for LlmUnifiedResponse {
         }
     }
 }
+
+impl From<DeepSeekStreamChunk> for LlmStreamChunk {
+    fn from(chunk: DeepSeekStreamChunk) -> Self {
+        let choice = chunk.choices.first();
+
+        LlmStreamChunk {
+            provider: "DeepSeek".into(),
+            model: chunk.model,
+            delta: choice.and_then(|c| c.delta.content.clone()),
+            tool_calls: choice
+                .and_then(|c| c.delta.tool_calls.as_ref())
+                .map(|calls| calls.iter().map(ToolCallDelta::from).collect())
+                .unwrap_or_default(),
+            finish_reason: choice.and_then(|c| c.finish_reason.clone()),
+            usage: chunk.usage.map(|u| LlmUsage {
+                input_tokens: Some(u.prompt_tokens),
+                output_tokens: Some(u.completion_tokens),
+                total_tokens: Some(u.total_tokens),
+            }),
+        }
+    }
+}
+
+#[cfg(feature = "deepseek")]
+pub struct DeepSeek;
+
+#[cfg(feature = "deepseek")]
+impl Provider for DeepSeek {
+    fn key_var(&self) -> &'static str {
+        "DEEPSEEK"
+    }
+
+    fn default_base_url(&self) -> &'static str {
+        "https://api.deepseek.com"
+    }
+
+    fn build_request(&self, input: &APIInput, max_tokens: u32) -> Value {
+        let model = match input.model {
+            Model::DeepSeekR1 => "deepseek-reasoner",
+            Model::DeepSeekV3 => "deepseek-chat",
+            _ => panic!("This shouldn't be possible"),
+        };
+        let mut req = json!({
+            "model": model,
+            "messages": openai_messages(&input.messages, openai_part),
+            "temperature": input.temperature,
+            "max_tokens": max_tokens,
+            "top_p": input.top_p,
+            "stop": input.stop_sequences,
+            "stream": input.stream,
+            "frequency_penalty": input.frequency_penalty,
+            "presence_penalty": input.presence_penalty,
+            "logprobs": input.logprobs,
+            "top_logprobs": input.top_logprobs,
+        });
+
+        insert_tools(&mut req, &input.tools, &input.tool_choice);
+
+        if input.stream.unwrap_or(false) {
+            req.as_object_mut()
+                .unwrap()
+                .insert("stream_options".into(), json!({ "include_usage": true }));
+        }
+
+        req
+    }
+
+    fn parse_response(&self, body: &str) -> Result<LlmUnifiedResponse, serde_json::Error> {
+        let deepseek: DeepSeekResponse = serde_json::from_str(body)?;
+        Ok(deepseek.into())
+    }
+
+    fn parse_stream_event(
+        &self,
+        event: &SseEvent,
+        _model: &str,
+    ) -> Result<Option<LlmStreamChunk>, String> {
+        if event.data == "[DONE]" {
+            return Ok(None);
+        }
+
+        let chunk: DeepSeekStreamChunk =
+            serde_json::from_str(&event.data).map_err(|e| e.to_string())?;
+        Ok(Some(chunk.into()))
+    }
+}
@@ -1,6 +1,21 @@
 #![allow(non_snake_case)]
-use crate::requests::responseparser::common::LlmUnifiedResponse;
+use std::collections::HashMap;
+
+use crate::{
+    compat::response_id,
+    pricing::Model,
+    requests::{
+        parseapi::{
+            APIInput, ContentPart, MediaSource, Message, MessageContent, ToolChoice, ToolMode,
+        },
+        provider::Provider,
+        responseparser::common::{LlmStreamChunk, LlmUnifiedResponse, ToolCall, ToolCallDelta},
+        stream::SseEvent,
+    },
+};
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GeminiUsageMetadata {
@@ -28,8 +43,10 @@ pub struct GeminiResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GeminiCandidate {
     pub content: GeminiContent,
-    pub finishReason: String,
+    // Only present on the last chunk of a streamed response
+    pub finishReason: Option<String>,
     pub index: Option<u32>, // optional now
+    #[serde(default)]
     pub safetyRatings: Vec<GeminiSafetyRating>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub avgLogprobs: Option<f64>,
@@ -37,13 +54,26 @@ pub struct GeminiCandidate {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GeminiContent {
+    #[serde(default)]
     pub parts: Vec<GeminiPart>,
     pub role: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GeminiPart {
-    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functionCall: Option<GeminiFunctionCall>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GeminiFunctionCall {
+    // Only sent by newer models, we make one up otherwise
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub args: Value,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -66,18 +96,29 @@ impl From<GeminiResponse> for LlmUnifiedResponse {
                 .content
                 .parts
                 .iter()
-                .map(|p| p.text.clone())
+                .filter_map(|p| p.text.clone())
                 .collect::<Vec<_>>()
                 .join("\n");
-            (
-                Some(c.content.role.clone()),
-                text,
-                Some(c.finishReason.clone()),
-            )
+            (Some(c.content.role.clone()), text, c.finishReason.clone())
         } else {
             (None, String::new(), None)
         };
 
+        let tool_calls = candidate
+            .map(|c| {
+                c.content
+                    .parts
+                    .iter()
+                    .filter_map(|p| p.functionCall.as_ref())
+                    .map(|call| ToolCall {
+                        id: call.id.clone().unwrap_or_else(|| response_id("call_")),
+                        name: call.name.clone(),
+                        arguments: call.args.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
         let usage = res
             .usage_metadata
             .map(|u| crate::requests::responseparser::common::LlmUsage {
@@ -91,8 +132,239 @@ impl From<GeminiResponse> for LlmUnifiedResponse {
             model: "gemini".into(), // Gemini API doesn't return model in response, can inject manually
             role,
             content,
+            tool_calls,
             usage, // Gemini's response usually doesn't include token usage
             finish_reason,
         }
     }
 }
+
+impl From<GeminiResponse> for LlmStreamChunk {
+    fn from(res: GeminiResponse) -> Self {
+        let unified: LlmUnifiedResponse = res.into();
+
+        LlmStreamChunk {
+            provider: unified.provider,
+            model: unified.model,
+            delta: Some(unified.content).filter(|c| !c.is_empty()),
+            // Gemini sends each call whole, so every call is a complete delta of its own
+            tool_calls: unified
+                .tool_calls
+                .into_iter()
+                .enumerate()
+                .map(|(i, call)| ToolCallDelta {
+                    index: i as u32,
+                    arguments: Some(call.arguments_json()),
+                    id: Some(call.id),
+                    name: Some(call.name),
+                })
+                .collect(),
+            finish_reason: unified.finish_reason,
+            usage: unified.usage,
+        }
+    }
+}
+
+/// Gemini's parts for one of our messages. Gemini has no call ids, so a tool result names the
+/// function it answers, looked up from the call it references.
+fn gemini_parts(msg: &Message, call_names: &HashMap<String, String>) -> Vec<Value> {
+    if msg.role == "tool" {
+        let id = msg.tool_call_id.clone().unwrap_or_default();
+        let name = call_names.get(&id).cloned().unwrap_or(id);
+        // The response has to be an object, plain text results are wrapped in one
+        let content = msg.content.text();
+        let response = match serde_json::from_str::<Value>(&content) {
+            Ok(v @ Value::Object(_)) => v,
+            _ => json!({ "result": content }),
+        };
+        return vec![json!({ "functionResponse": { "name": name, "response": response } })];
+    }
+
+    let mut parts = match &msg.content {
+        MessageContent::Text(text) if text.is_empty() && !msg.tool_calls.is_empty() => Vec::new(),
+        MessageContent::Text(text) => vec![json!({ "text": text })],
+        MessageContent::Parts(parts) => parts.iter().map(gemini_part).collect(),
+    };
+    for call in &msg.tool_calls {
+        parts.push(json!({ "functionCall": { "name": call.name, "args": call.arguments } }));
+    }
+    parts
+}
+
+/// A content part as a Gemini part. Media has to be inline, `check_part` rejects URLs.
+fn gemini_part(part: &ContentPart) -> Value {
+    match part {
+        ContentPart::Text { text } => json!({ "text": text }),
+        ContentPart::Image { source } | ContentPart::Document { source } => match source {
+            MediaSource::Base64 { media_type, data } => {
+                json!({ "inlineData": { "mimeType": media_type, "data": data } })
+            }
+            MediaSource::Url { url } => json!({ "fileData": { "fileUri": url } }),
+        },
+    }
+}
+
+/// Our `tool_choice` as Gemini's function calling mode, restricted to one function when the
+/// choice names it.
+fn gemini_tool_config(choice: &ToolChoice) -> Value {
+    let config = match choice {
+        ToolChoice::Mode(ToolMode::Auto) => json!({ "mode": "AUTO" }),
+        ToolChoice::Mode(ToolMode::None) => json!({ "mode": "NONE" }),
+        ToolChoice::Mode(ToolMode::Required) => json!({ "mode": "ANY" }),
+        ToolChoice::Tool { name } => json!({ "mode": "ANY", "allowedFunctionNames": [name] }),
+    };
+    json!({ "functionCallingConfig": config })
+}
+
+#[cfg(feature = "gemini")]
+pub struct Gemini;
+
+#[cfg(feature = "gemini")]
+impl Provider for Gemini {
+    fn key_var(&self) -> &'static str {
+        "GEMINI"
+    }
+
+    fn default_base_url(&self) -> &'static str {
+        "https://generativelanguage.googleapis.com/v1beta"
+    }
+
+    // Gemini has the model in the path and a separate method for streaming
+    fn endpoint(&self, base_url: &str, model: &Model, stream: bool) -> String {
+        if stream {
+            format!(
+                "{}/models/{}:streamGenerateContent?alt=sse",
+                base_url,
+                model.name()
+            )
+        } else {
+            format!("{}/models/{}:generateContent", base_url, model.name())
+        }
+    }
+
+    // Gemini takes the key as a query parameter rather than a header
+    fn authenticate(&self, request: RequestBuilder, key: &str) -> RequestBuilder {
+        request.query(&[("key", key)])
+    }
+
+    fn check_part(&self, part: &ContentPart) -> Result<(), String> {
+        match part {
+            ContentPart::Image {
+                source: MediaSource::Url { .. },
+            }
+            | ContentPart::Document {
+                source: MediaSource::Url { .. },
+            } => Err("Gemini models only accept images and documents as base64 data".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn build_request(&self, input: &APIInput, max_tokens: u32) -> Value {
+        let mut system = input.system.clone();
+        let contents: Vec<Value> = if let Some(contents) = input.contents.clone() {
+            contents.into_iter().map(|c| json!(c)).collect()
+        } else {
+            let call_names: HashMap<String, String> = input
+                .messages
+                .iter()
+                .flat_map(|msg| &msg.tool_calls)
+                .map(|call| (call.id.clone(), call.name.clone()))
+                .collect();
+
+            let mut contents: Vec<Value> = Vec::new();
+            for msg in &input.messages {
+                // Gemini calls the assistant "model" and takes system prompts separately
+                let role = match msg.role.as_str() {
+                    "system" => {
+                        system = Some(match system {
+                            Some(s) => format!("{}\n{}", s, msg.content.text()),
+                            None => msg.content.text(),
+                        });
+                        continue;
+                    }
+                    "assistant" => "model",
+                    "tool" => "user",
+                    other => other,
+                };
+                let parts = gemini_parts(msg, &call_names);
+
+                // Responses to parallel calls go together in one turn
+                if msg.role == "tool"
+                    && let Some(last) = contents.last_mut()
+                    && last["role"] == "user"
+                    && last["parts"][0].get("functionResponse").is_some()
+                {
+                    last["parts"].as_array_mut().unwrap().extend(parts);
+                    continue;
+                }
+
+                contents.push(json!({
+                    "role": role,
+                    "parts": parts
+                }));
+            }
+            contents
+        };
+
+        let mut req = json!({
+            "contents": contents,
+            "safetySettings": input.safety_settings,
+            "generationConfig": {
+                "temperature": input.temperature,
+                "topP": input.top_p,
+                "topK": input.generation_config.as_ref().map(|cfg| cfg.top_k).or(input.top_k),
+                "candidateCount": input.generation_config.as_ref().map(|cfg| cfg.candidate_count),
+                "maxOutputTokens": max_tokens,
+                "stopSequences": input.stop_sequences,
+            },
+        });
+
+        if let Some(tools) = &input.tools
+            && !tools.is_empty()
+        {
+            let declarations: Vec<Value> = tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.function.name,
+                        "description": tool.function.description,
+                        "parameters": tool.function.parameters,
+                    })
+                })
+                .collect();
+            req.as_object_mut().unwrap().insert(
+                "tools".into(),
+                json!([{ "functionDeclarations": declarations }]),
+            );
+        }
+
+        if let Some(choice) = &input.tool_choice {
+            req.as_object_mut()
+                .unwrap()
+                .insert("toolConfig".into(), gemini_tool_config(choice));
+        }
+
+        if let Some(system) = system {
+            req.as_object_mut().unwrap().insert(
+                "systemInstruction".into(),
+                json!({ "parts": [{ "text": system }] }),
+            );
+        }
+
+        req
+    }
+
+    fn parse_response(&self, body: &str) -> Result<LlmUnifiedResponse, serde_json::Error> {
+        let gemini: GeminiResponse = serde_json::from_str(body)?;
+        Ok(gemini.into())
+    }
+
+    fn parse_stream_event(
+        &self,
+        event: &SseEvent,
+        _model: &str,
+    ) -> Result<Option<LlmStreamChunk>, String> {
+        let chunk: GeminiResponse = serde_json::from_str(&event.data).map_err(|e| e.to_string())?;
+        Ok(Some(chunk.into()))
+    }
+}
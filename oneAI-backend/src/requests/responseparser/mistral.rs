@@ -1,5 +1,14 @@
-use crate::requests::responseparser::common::{LlmUnifiedResponse, LlmUsage};
+use crate::requests::{
+    parseapi::{APIInput, ContentPart},
+    provider::Provider,
+    responseparser::{
+        common::{LlmStreamChunk, LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta},
+        openai::{OpenAIToolCall, OpenAIToolCallDelta, insert_tools, openai_messages},
+    },
+    stream::SseEvent,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MistralResponse {
@@ -21,7 +30,32 @@ pub struct MistralChoice {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MistralMessage {
     pub role: String,
-    pub content: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    // Same shape as OpenAI's tool calls
+    pub tool_calls: Option<Vec<OpenAIToolCall>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MistralStreamChunk {
+    pub id: String,
+    pub model: String,
+    pub choices: Vec<MistralStreamChoice>,
+    pub usage: Option<MistralUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MistralStreamChoice {
+    pub index: u32,
+    pub delta: MistralDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MistralDelta {
+    pub role: Option<String>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<OpenAIToolCallDelta>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,18 +71,24 @@ impl From<MistralResponse> for LlmUnifiedResponse {
         let (role, content, finish_reason) = if let Some(c) = choice {
             (
                 Some(c.message.role.clone()),
-                c.message.content.clone(),
+                c.message.content.clone().unwrap_or_default(),
                 c.finish_reason.clone(),
             )
         } else {
             (None, String::new(), None)
         };
 
+        let tool_calls = choice
+            .and_then(|c| c.message.tool_calls.as_ref())
+            .map(|calls| calls.iter().map(ToolCall::from).collect())
+            .unwrap_or_default();
+
         LlmUnifiedResponse {
             provider: "Mistral".into(),
             model: res.model,
             role,
             content,
+            tool_calls,
             usage: res.usage.map(|u| LlmUsage {
                 input_tokens: Some(u.prompt_tokens),
                 output_tokens: Some(u.completion_tokens),
@@ -58,3 +98,99 @@ impl From<MistralResponse> for LlmUnifiedResponse {
         }
     }
 }
+
+impl From<MistralStreamChunk> for LlmStreamChunk {
+    fn from(chunk: MistralStreamChunk) -> Self {
+        let choice = chunk.choices.first();
+
+        LlmStreamChunk {
+            provider: "Mistral".into(),
+            model: chunk.model,
+            delta: choice.and_then(|c| c.delta.content.clone()),
+            tool_calls: choice
+                .and_then(|c| c.delta.tool_calls.as_ref())
+                .map(|calls| calls.iter().map(ToolCallDelta::from).collect())
+                .unwrap_or_default(),
+            finish_reason: choice.and_then(|c| c.finish_reason.clone()),
+            usage: chunk.usage.map(|u| LlmUsage {
+                input_tokens: Some(u.prompt_tokens),
+                output_tokens: Some(u.completion_tokens),
+                total_tokens: Some(u.total_tokens),
+            }),
+        }
+    }
+}
+
+/// A content part in Mistral's format, where an image is given by its URL directly.
+fn mistral_part(part: &ContentPart) -> Value {
+    match part {
+        ContentPart::Text { text } => json!({ "type": "text", "text": text }),
+        ContentPart::Image { source } => {
+            json!({ "type": "image_url", "image_url": source.to_url() })
+        }
+        ContentPart::Document { source } => {
+            json!({ "type": "document_url", "document_url": source.to_url() })
+        }
+    }
+}
+
+#[cfg(feature = "mistral")]
+pub struct Mistral;
+
+#[cfg(feature = "mistral")]
+impl Provider for Mistral {
+    fn key_var(&self) -> &'static str {
+        "MISTRAL"
+    }
+
+    fn default_base_url(&self) -> &'static str {
+        "https://api.mistral.ai/v1"
+    }
+
+    fn build_request(&self, input: &APIInput, max_tokens: u32) -> Value {
+        let mut req = json!({
+            "model": input.model.name().to_lowercase(),
+            "messages": openai_messages(&input.messages, mistral_part),
+            "temperature": input.temperature,
+            "max_tokens": max_tokens,
+            "top_p": input.top_p,
+        });
+
+        if let Some(stop) = &input.stop_sequences
+            && !stop.is_empty()
+        {
+            req.as_object_mut()
+                .unwrap()
+                .insert("stop".to_string(), json!(stop));
+        }
+
+        insert_tools(&mut req, &input.tools, &input.tool_choice);
+
+        if let Some(stream) = input.stream {
+            req.as_object_mut()
+                .unwrap()
+                .insert("stream".to_string(), json!(stream));
+        }
+
+        req
+    }
+
+    fn parse_response(&self, body: &str) -> Result<LlmUnifiedResponse, serde_json::Error> {
+        let mistral: MistralResponse = serde_json::from_str(body)?;
+        Ok(mistral.into())
+    }
+
+    fn parse_stream_event(
+        &self,
+        event: &SseEvent,
+        _model: &str,
+    ) -> Result<Option<LlmStreamChunk>, String> {
+        if event.data == "[DONE]" {
+            return Ok(None);
+        }
+
+        let chunk: MistralStreamChunk =
+            serde_json::from_str(&event.data).map_err(|e| e.to_string())?;
+        Ok(Some(chunk.into()))
+    }
+}
@@ -1,6 +1,11 @@
+#[cfg_attr(not(feature = "anthropic"), allow(dead_code, unused_imports))]
 pub mod anthropic;
 pub mod common;
+#[cfg_attr(not(feature = "deepseek"), allow(dead_code, unused_imports))]
 pub mod deepseek;
+#[cfg_attr(not(feature = "gemini"), allow(dead_code, unused_imports))]
 pub mod gemini;
+#[cfg_attr(not(feature = "mistral"), allow(dead_code, unused_imports))]
 pub mod mistral;
+#[cfg_attr(not(feature = "openai"), allow(dead_code, unused_imports))]
 pub mod openai;
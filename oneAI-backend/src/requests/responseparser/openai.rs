@@ -1,5 +1,13 @@
-use crate::requests::responseparser::common::{LlmUnifiedResponse, LlmUsage};
+use crate::requests::{
+    parseapi::{APIInput, ContentPart, MediaSource, Message, MessageContent, Tool, ToolChoice},
+    provider::Provider,
+    responseparser::common::{
+        LlmStreamChunk, LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta,
+    },
+    stream::SseEvent,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIResponse {
@@ -35,15 +43,77 @@ pub struct OpenAIUsage {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIMessage {
     pub role: String,
-    pub content: String,
+    // null when the model only calls tools
+    pub content: Option<String>,
     pub refusal: Option<serde_json::Value>,
     pub function_call: Option<serde_json::Value>,
-    pub tool_calls: Option<Vec<serde_json::Value>>,
+    pub tool_calls: Option<Vec<OpenAIToolCall>>,
     pub parsed: Option<serde_json::Value>,
     #[serde(default)]
     pub annotations: Vec<serde_json::Value>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: OpenAIFunctionCall,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIFunctionCall {
+    pub name: String,
+    // A JSON document encoded as a string
+    pub arguments: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIStreamChunk {
+    pub id: String,
+    #[serde(default)]
+    pub object: String,
+    #[serde(default)]
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<OpenAIStreamChoice>,
+    pub usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIStreamChoice {
+    pub index: u32,
+    pub finish_reason: Option<String>,
+    pub delta: OpenAIDelta,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIDelta {
+    pub role: Option<String>,
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<OpenAIToolCallDelta>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIToolCallDelta {
+    #[serde(default)]
+    pub index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    pub function: OpenAIFunctionDelta,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIFunctionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIContent {
     pub r#type: String,
@@ -51,17 +121,20 @@ pub struct OpenAIContent {
     pub annotations: Vec<serde_json::Value>,
 }
 
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct OpenAIReasoning {
     pub effort: Option<serde_json::Value>,
     pub summary: Option<serde_json::Value>,
 }
 
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct OpenAITextField {
     pub format: OpenAIFormat,
 }
 
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct OpenAIFormat {
     #[serde(rename = "type")]
@@ -80,6 +153,7 @@ pub struct OpenAIOutputTokensDetails {
     pub audio_tokens: Option<u32>,
 }
 
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIMetadata {} // Empty object
 
@@ -87,7 +161,14 @@ impl From<OpenAIResponse> for LlmUnifiedResponse {
     fn from(res: OpenAIResponse) -> Self {
         let first = res.choices.first();
 
-        let content = first.map(|c| c.message.content.clone()).unwrap_or_default();
+        let content = first
+            .and_then(|c| c.message.content.clone())
+            .unwrap_or_default();
+
+        let tool_calls = first
+            .and_then(|c| c.message.tool_calls.as_ref())
+            .map(|calls| calls.iter().map(ToolCall::from).collect())
+            .unwrap_or_default();
 
         let role = first.map(|c| c.message.role.clone());
 
@@ -98,6 +179,7 @@ impl From<OpenAIResponse> for LlmUnifiedResponse {
             model: res.model,
             role,
             content,
+            tool_calls,
             usage: Some(LlmUsage {
                 input_tokens: Some(res.usage.prompt_tokens),
                 output_tokens: Some(res.usage.completion_tokens),
@@ -107,3 +189,197 @@ impl From<OpenAIResponse> for LlmUnifiedResponse {
         }
     }
 }
+
+impl From<OpenAIStreamChunk> for LlmStreamChunk {
+    fn from(chunk: OpenAIStreamChunk) -> Self {
+        let first = chunk.choices.first();
+
+        LlmStreamChunk {
+            provider: "OpenAI".into(),
+            model: chunk.model,
+            delta: first.and_then(|c| c.delta.content.clone()),
+            tool_calls: first
+                .and_then(|c| c.delta.tool_calls.as_ref())
+                .map(|calls| calls.iter().map(ToolCallDelta::from).collect())
+                .unwrap_or_default(),
+            finish_reason: first.and_then(|c| c.finish_reason.clone()),
+            usage: chunk.usage.map(|u| LlmUsage {
+                input_tokens: Some(u.prompt_tokens),
+                output_tokens: Some(u.completion_tokens),
+                total_tokens: Some(u.total_tokens),
+            }),
+        }
+    }
+}
+
+impl From<&OpenAIToolCall> for ToolCall {
+    fn from(call: &OpenAIToolCall) -> Self {
+        ToolCall::from_json_arguments(
+            call.id.clone(),
+            call.function.name.clone(),
+            &call.function.arguments,
+        )
+    }
+}
+
+impl From<&OpenAIToolCallDelta> for ToolCallDelta {
+    fn from(call: &OpenAIToolCallDelta) -> Self {
+        ToolCallDelta {
+            index: call.index,
+            id: call.id.clone(),
+            name: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+        }
+    }
+}
+
+/// A content part in the OpenAI format. PDFs can only be sent inline, as a `file` part.
+pub fn openai_part(part: &ContentPart) -> Value {
+    match part {
+        ContentPart::Text { text } => json!({ "type": "text", "text": text }),
+        ContentPart::Image { source } => {
+            json!({ "type": "image_url", "image_url": { "url": source.to_url() } })
+        }
+        ContentPart::Document { source } => json!({
+            "type": "file",
+            "file": { "filename": "document.pdf", "file_data": source.to_url() },
+        }),
+    }
+}
+
+/// Messages in the OpenAI chat format, which DeepSeek and Mistral accept as well apart from
+/// how content parts look, so each passes its own `part` translation. Tool calls carry their
+/// arguments as a JSON string and tool results reference the call by id.
+pub fn openai_messages(messages: &[Message], part: fn(&ContentPart) -> Value) -> Vec<Value> {
+    messages
+        .iter()
+        .map(|msg| {
+            if msg.role == "tool" {
+                return json!({
+                    "role": "tool",
+                    "tool_call_id": msg.tool_call_id,
+                    "content": msg.content.text(),
+                });
+            }
+
+            let content = match &msg.content {
+                MessageContent::Text(text) => json!(text),
+                MessageContent::Parts(parts) => json!(parts.iter().map(part).collect::<Vec<_>>()),
+            };
+
+            if msg.tool_calls.is_empty() {
+                return json!({ "role": msg.role, "content": content });
+            }
+
+            let tool_calls: Vec<Value> = msg
+                .tool_calls
+                .iter()
+                .map(|call| {
+                    json!({
+                        "id": call.id,
+                        "type": "function",
+                        "function": { "name": call.name, "arguments": call.arguments_json() },
+                    })
+                })
+                .collect();
+
+            json!({
+                "role": msg.role,
+                "content": Some(content).filter(|_| !msg.content.is_empty()),
+                "tool_calls": tool_calls,
+            })
+        })
+        .collect()
+}
+
+/// Our `tool_choice` in the OpenAI format, where a forced tool is given as an object.
+pub fn openai_tool_choice(choice: &ToolChoice) -> Value {
+    match choice {
+        ToolChoice::Mode(mode) => json!(mode),
+        ToolChoice::Tool { name } => json!({ "type": "function", "function": { "name": name } }),
+    }
+}
+
+/// Adds `tools` and `tool_choice` to an OpenAI-format request if the caller sent any.
+pub fn insert_tools(req: &mut Value, tools: &Option<Vec<Tool>>, tool_choice: &Option<ToolChoice>) {
+    let req = req.as_object_mut().unwrap();
+    if let Some(tools) = tools
+        && !tools.is_empty()
+    {
+        req.insert("tools".into(), json!(tools));
+    }
+    if let Some(choice) = tool_choice {
+        req.insert("tool_choice".into(), openai_tool_choice(choice));
+    }
+}
+
+#[cfg(feature = "openai")]
+pub struct OpenAI;
+
+#[cfg(feature = "openai")]
+impl Provider for OpenAI {
+    fn key_var(&self) -> &'static str {
+        "OPENAI"
+    }
+
+    fn default_base_url(&self) -> &'static str {
+        "https://api.openai.com/v1"
+    }
+
+    fn check_part(&self, part: &ContentPart) -> Result<(), String> {
+        match part {
+            ContentPart::Document {
+                source: MediaSource::Url { .. },
+            } => Err("OpenAI models only accept documents as base64 data".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn build_request(&self, input: &APIInput, max_tokens: u32) -> Value {
+        let mut req = json!({
+            "model": input.model.name(),
+            "messages": openai_messages(&input.messages, openai_part),
+            "temperature": input.temperature,
+            "max_completion_tokens": max_tokens,
+            "top_p": input.top_p,
+            "stop": input.stop_sequences,
+            "stream": input.stream,
+            "frequency_penalty": input.frequency_penalty,
+            "presence_penalty": input.presence_penalty,
+            "n": input.n,
+            "response_format": input.response_format,
+            "seed": input.seed,
+            "user": input.user,
+        });
+
+        insert_tools(&mut req, &input.tools, &input.tool_choice);
+
+        // Usage is only reported at the end of a stream when explicitly requested
+        if input.stream.unwrap_or(false) {
+            req.as_object_mut()
+                .unwrap()
+                .insert("stream_options".into(), json!({ "include_usage": true }));
+        }
+
+        req
+    }
+
+    fn parse_response(&self, body: &str) -> Result<LlmUnifiedResponse, serde_json::Error> {
+        let openai: OpenAIResponse = serde_json::from_str(body)?;
+        Ok(openai.into())
+    }
+
+    fn parse_stream_event(
+        &self,
+        event: &SseEvent,
+        _model: &str,
+    ) -> Result<Option<LlmStreamChunk>, String> {
+        if event.data == "[DONE]" {
+            return Ok(None);
+        }
+
+        let chunk: OpenAIStreamChunk =
+            serde_json::from_str(&event.data).map_err(|e| e.to_string())?;
+        Ok(Some(chunk.into()))
+    }
+}
@@ -0,0 +1,134 @@
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+use crate::{
+    error::OneLlmError,
+    ledger::Hold,
+    pricing::Model,
+    ratelimit::Permit,
+    requests::{
+        provider::Provider,
+        requests::{charge, log_usage, release},
+        responseparser::common::{LlmStreamChunk, LlmUsage},
+    },
+    usage::UsageEvent,
+};
+
+pub use onellm_types::sse::{SseDecoder, SseEvent};
+
+#[derive(Debug)]
+pub enum StreamEvent {
+    Chunk(LlmStreamChunk),
+    Error(String),
+}
+
+/// Reads the provider's event stream until it ends, forwarding text and tool call deltas and
+/// finish reasons to `tx`. Usage is merged across the stream and sent as a final chunk once the
+/// user has been billed for it, and counted against the key's rate limit when `permit` drops.
+/// Without a usage report nothing is charged and the hold is released, so a provider failing
+/// mid-stream never costs the user the worst case.
+/// The upstream body is drained even if the client disconnects, since the provider bills us for
+/// the whole completion either way.
+#[allow(clippy::too_many_arguments)]
+pub async fn drive_stream(
+    response: reqwest::Response,
+    provider: &'static dyn Provider,
+    model: Model,
+    pool: PgPool,
+    hold: Hold,
+    mut permit: Permit,
+    mut usage_event: UsageEvent,
+    tx: mpsc::Sender<StreamEvent>,
+) {
+    let mut body = response.bytes_stream();
+    let mut decoder = SseDecoder::default();
+    let mut usage = LlmUsage::default();
+    let mut error = None;
+    let mut last = LlmStreamChunk {
+        model: model.name().to_string(),
+        ..Default::default()
+    };
+
+    'outer: while let Some(bytes) = body.next().await {
+        let bytes = match bytes {
+            Ok(b) => b,
+            Err(e) => {
+                let e = OneLlmError::from(e);
+                let _ = tx.send(StreamEvent::Error(e.to_string())).await;
+                error = Some(e);
+                break;
+            }
+        };
+
+        for event in decoder.push(&bytes) {
+            match provider.parse_stream_event(&event, &last.model) {
+                Ok(Some(mut chunk)) => {
+                    if let Some(u) = chunk.usage.take() {
+                        usage.merge(u);
+                    }
+                    if chunk.finish_reason.is_some() {
+                        usage_event.finish_reason = chunk.finish_reason.clone();
+                    }
+                    last.provider = chunk.provider.clone();
+                    last.model = chunk.model.clone();
+
+                    if chunk.delta.is_some()
+                        || !chunk.tool_calls.is_empty()
+                        || chunk.finish_reason.is_some()
+                    {
+                        let _ = tx.send(StreamEvent::Chunk(chunk)).await;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = tx.send(StreamEvent::Error(e.clone())).await;
+                    // An event that can't be parsed is usually the provider reporting an error
+                    error = Some(OneLlmError::Provider {
+                        status: 502,
+                        message: e,
+                    });
+                    break 'outer;
+                }
+            }
+        }
+    }
+
+    if usage.total_tokens.is_none() {
+        usage.total_tokens =
+            Some(usage.input_tokens.unwrap_or(0) + usage.output_tokens.unwrap_or(0));
+    }
+    permit.used(usage.total_tokens.unwrap_or(0) as u64);
+
+    let charged = if usage.input_tokens.is_some() || usage.output_tokens.is_some() {
+        charge(&pool, &model, &hold, Some(&usage)).await
+    } else {
+        release(&pool, &hold).await;
+        Ok(0)
+    };
+    let cost = match charged {
+        Ok(cost) => cost,
+        Err(e) => {
+            usage_event.failed(&e);
+            log_usage(&pool, &usage_event).await;
+            let _ = tx.send(StreamEvent::Error(e.to_string())).await;
+            return;
+        }
+    };
+
+    let finish_reason = usage_event.finish_reason.take();
+    match &error {
+        // What was reported is still recorded, as the charge for it stands
+        Some(e) => {
+            usage_event.input_tokens = usage.input_tokens.unwrap_or(0);
+            usage_event.output_tokens = usage.output_tokens.unwrap_or(0);
+            usage_event.cost = cost;
+            usage_event.failed(e);
+        }
+        None => usage_event.succeeded(Some(&usage), cost, finish_reason.as_deref()),
+    }
+    log_usage(&pool, &usage_event).await;
+
+    last.usage = Some(usage);
+    let _ = tx.send(StreamEvent::Chunk(last)).await;
+}
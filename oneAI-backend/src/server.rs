@@ -1,9 +1,21 @@
-use redis::AsyncCommands;
 use sqlx::PgPool;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tower_http::cors::{Any, CorsLayer};
-
-use axum::{Json, Router, http::header::HeaderMap, response::IntoResponse, routing::post};
+use std::net::SocketAddr;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, State},
+    http::header,
+    http::header::HeaderMap,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::post,
+};
+use futures_util::{Stream, StreamExt, stream};
+use std::convert::Infallible;
+use tokio::sync::mpsc;
 
 use tower_http::services::ServeDir;
 
@@ -12,67 +24,83 @@ use serde_json::json;
 use crate::{
     auth::{
         basicauth::{self},
-        twofa::{self, send_verify},
+        twofa::{self, send_password_reset, send_verify},
     },
-    database::init_pool,
-    requests::parseapi::APIInput,
+    error::OneLlmError,
+    organization::{self, Role},
+    requests::{parseapi::APIInput, requests::Completion, stream::StreamEvent},
+    session,
+    state::AppState,
+    usage::{self, UsageFormat, UsageQuery},
 };
-use crate::{payment, utils::*};
+use crate::{compat, payment, utils::*};
 
-#[axum::debug_handler]
+/// Seconds a rotated key keeps working when the request doesn't say.
+const DEFAULT_ROTATION_GRACE: u64 = 24 * 60 * 60;
 
-pub async fn server() {
+pub async fn server(state: AppState) {
+    let origins = &state.config.server.cors_origins;
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        // Validated when the config was loaded
+        AllowOrigin::list(origins.iter().filter_map(|o| o.parse().ok()))
+    };
     let cors = CorsLayer::new()
-        .allow_origin(Any) // Allow all origins (good for development)
+        .allow_origin(allow_origin)
         .allow_methods(Any) // Allow all HTTP methods (GET, POST, etc.)
         .allow_headers(Any);
+    let ipaddr = state.config.server.listen.clone();
 
     let app = Router::new()
         .fallback_service(ServeDir::new("OneLLM-Website/"))
         .route("/api", post(handle_api))
+        .route(
+            "/v1/chat/completions",
+            post(compat::openai::chat_completions),
+        )
+        .route("/v1/messages", post(compat::anthropic::messages))
         .route("/post-backend", post(handle_post_website))
         .route("/verify-email", post(verify_email))
         .route("/check-verify", post(verify_code))
+        .route("/forgot-password", post(forgot_password))
+        .route("/reset-password", post(reset_password))
         .route("/apikey-commands", post(handle_token_auth))
         .route("/token-login", post(login_with_token))
+        .route("/refresh", post(refresh_session))
+        .route("/logout", post(logout))
+        .route("/usage", post(usage_report))
         .route("/webhook", post(payment::handle_webhook))
-        .layer(cors);
-    let ipaddr = "0.0.0.0:3000";
-    let listener = tokio::net::TcpListener::bind(ipaddr).await.unwrap();
+        .layer(cors)
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(&ipaddr).await.unwrap();
 
     println!("Listening at: {}", ipaddr);
-    axum::serve(listener, app).await.unwrap();
-}
-
-pub async fn verify_email(Json(payload): Json<VerifyInput>) -> Json<FailOrSucc> {
-    let mut redis = redis::Client::open(
-        std::env::var("REDIS")
-            .expect("Redis ENV VAR not found")
-            .as_str(),
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .expect("Unable to connect to redis")
-    .get_multiplexed_async_connection()
     .await
-    .expect("Unable to get MultiplexedAsyncConnection");
+    .unwrap();
+}
 
-    match send_verify(&mut redis, &payload.email).await {
-        Ok(()) => {
-            return Json(FailOrSucc::Successful("Successful".to_string()));
-        }
-        Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyInput>,
+) -> Result<Json<FailOrSucc>, OneLlmError> {
+    let mut redis = state.redis.clone();
+
+    match send_verify(&mut redis, state.mailer.as_ref(), &payload.email).await {
+        Ok(()) => Ok(Json(FailOrSucc::Successful("Successful".to_string()))),
+        Err(e) => Ok(Json(FailOrSucc::Failure(e.to_string()))),
     }
 }
 
-pub async fn verify_code(Json(payload): Json<VerifyInput>) -> Json<FailOrSucc> {
-    let mut redis = redis::Client::open(
-        std::env::var("REDIS")
-            .expect("Redis ENV VAR not found")
-            .as_str(),
-    )
-    .expect("Unable to connect to redis")
-    .get_multiplexed_async_connection()
-    .await
-    .expect("Unable to get MultiplexedAsyncConnection");
+pub async fn verify_code(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyInput>,
+) -> Result<Json<FailOrSucc>, OneLlmError> {
+    let mut redis = state.redis.clone();
 
     let did_verify = match twofa::verify_code(
         &mut redis,
@@ -82,147 +110,158 @@ pub async fn verify_code(Json(payload): Json<VerifyInput>) -> Json<FailOrSucc> {
     .await
     {
         Ok(a) => a,
-        Err(e) => return Json(FailOrSucc::Failure(e.to_string())),
+        Err(e) => return Ok(Json(FailOrSucc::Failure(e.to_string()))),
     };
 
     if did_verify {
-        match User::verify_user(None, &payload.email).await {
-            Ok(()) => Json(FailOrSucc::Successful("Successful".to_string())),
+        match User::verify_user(&state.pool, &payload.email).await {
+            Ok(()) => Ok(Json(FailOrSucc::Successful("Successful".to_string()))),
 
-            Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            Err(e) => Ok(Json(FailOrSucc::Failure(e.to_string()))),
         }
     } else {
-        Json(FailOrSucc::Failure("Verification unsuccessful".to_string()))
+        Ok(Json(FailOrSucc::Failure(
+            "Verification unsuccessful".to_string(),
+        )))
     }
 }
 
-const MAX_TOKENS: f64 = 40.0;
-const REFILL_RATE: f64 = MAX_TOKENS / 60.0; // tokens per second
-
-async fn allow_request(
-    conn: &mut redis::aio::MultiplexedConnection,
-    api_key: &str,
-) -> redis::RedisResult<bool> {
-    let tokens_key = format!("tokens:{}", api_key);
-    let timestamp_key = format!("timestamp:{}", api_key);
-
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f64();
-
-    let tokens: Option<f64> = conn.get(&tokens_key).await.ok();
-    let last_refill: Option<f64> = conn.get(&timestamp_key).await.ok();
-
-    let tokens = tokens.unwrap_or(MAX_TOKENS);
-    let last_refill = last_refill.unwrap_or(now);
+/// Emails a password reset link if the account exists. Answers the same either way, and
+/// before looking the account up, so the endpoint can't be used to find out who has one.
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ForgotPasswordInput>,
+) -> Result<Json<FailOrSucc>, OneLlmError> {
+    let mut redis = state.redis.clone();
+    if !twofa::reset_allowed(&mut redis, &payload.email, addr.ip()).await? {
+        return Ok(Json(FailOrSucc::Failure(
+            "A reset link was just asked for, please wait a minute before asking again."
+                .to_string(),
+        )));
+    }
 
-    let elapsed = now - last_refill;
-    let new_tokens = f64::min(MAX_TOKENS, tokens + elapsed * REFILL_RATE);
+    tokio::spawn(async move {
+        let token = match User::create_password_reset(&state.pool, &payload.email).await {
+            Ok(Some(token)) => token,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Could not create password reset: {}", e);
+                return;
+            }
+        };
+        let link = format!(
+            "{}/reset-password.html?token={}",
+            state.config.server.public_url, token
+        );
+        if let Err(e) = send_password_reset(state.mailer.as_ref(), &payload.email, &link).await {
+            eprintln!("Could not send password reset email: {}", e);
+        }
+    });
 
-    let allowed = if new_tokens >= 1.0 {
-        let updated_tokens = new_tokens - 1.0;
-        let _: () = conn.set_ex(&tokens_key, updated_tokens, 120).await?;
-        let _: () = conn.set_ex(&timestamp_key, now, 120).await?;
-        true
-    } else {
-        false
-    };
+    Ok(Json(FailOrSucc::Successful(
+        "If this email has an account, a reset link was sent to it".to_string(),
+    )))
+}
 
-    Ok(allowed)
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPasswordInput>,
+) -> Result<Json<FailOrSucc>, OneLlmError> {
+    match User::reset_password(&state.pool, &payload.token, &payload.password).await {
+        Ok(()) => Ok(Json(FailOrSucc::Successful(
+            "Successfully changed password".to_string(),
+        ))),
+        Err(e) => Ok(Json(FailOrSucc::Failure(e.to_string()))),
+    }
 }
-pub async fn handle_api(headers: HeaderMap, Json(payload): Json<APIInput>) -> Json<Output> {
-    dotenv::dotenv().ok();
+
+/// Resolves the OneLLM key of an API request to the key and its owner, looked up once per
+/// request. Anthropic's `x-api-key` header is accepted as well so its SDKs work unchanged.
+pub async fn authenticate(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<(User, ApiKey), OneLlmError> {
     let apikey = if let Some(auth_header_value) = headers.get("Authorization") {
-        match auth_header_value.to_str() {
-            Ok(header_str) => {
-                if let Some(token) = header_str.strip_prefix("Bearer ") {
-                    token.to_string()
-                } else {
-                    return Json(Output {
-                        code: 401,
-                        output: json!({
-                            "error": "Invalid authorization scheme. Expected Bearer token.",
-                        }),
-                    });
-                }
-            }
-            Err(e) => {
-                return Json(Output {
-                    code: 401,
-                    output: json!({
-                        "error": format!("Invalid header value: {}", e),
-                    }),
-                });
+        let header_str = auth_header_value
+            .to_str()
+            .map_err(|e| OneLlmError::Auth(format!("Invalid header value: {}", e)))?;
+        match header_str.strip_prefix("Bearer ") {
+            Some(token) => token.to_string(),
+            None => {
+                return Err(OneLlmError::Auth(
+                    "Invalid authorization scheme. Expected Bearer token.".to_string(),
+                ));
             }
         }
+    } else if let Some(key) = headers.get("x-api-key") {
+        key.to_str()
+            .map_err(|e| OneLlmError::Auth(format!("Invalid header value: {}", e)))?
+            .to_string()
     } else {
-        return Json(Output {
-            code: 401,
-            output: json!({
-                "error": "No Authorization header provided.",
-            }),
-        });
+        return Err(OneLlmError::Auth(
+            "No Authorization header provided.".to_string(),
+        ));
     };
 
-    let redis = redis::Client::open(
-        std::env::var("REDIS")
-            .expect("Redis ENV VAR not found")
-            .as_str(),
-    )
-    .expect("Unable to connect to redis");
-
-    if !allow_request(
-        &mut redis
-            .get_multiplexed_async_connection()
-            .await
-            .expect("Error while trying to get MultiplexedConnection"),
-        &apikey,
-    )
-    .await
-    .expect("Error while checking if request should be allowed")
-    {
-        return Json(Output {
-            code: 429,
-            output: json!({
-                "error": "Rate limit exceeded."
-            }),
-        });
+    let (user, key) = User::get_row_api(&state.pool, apikey)
+        .await
+        .map_err(|e| OneLlmError::Auth(e.to_string()))?;
+    if let Err(e) = User::touch_apikey(&state.pool, key.id).await {
+        eprintln!("Could not record use of API key {}: {}", key.id, e);
     }
 
-    match User::get_row_api(None, apikey.clone()).await {
-        Ok(user_struct) => user_struct,
-        Err(e) => {
-            return Json(Output {
-                code: 401,
-                output: json!({
-                    "error": e.to_string()
-                }),
-            });
-        }
-    };
+    Ok((user, key))
+}
 
-    let output = match payload.get(apikey).await {
-        Ok(result) => result,
-        Err(e) => {
-            return Json(Output {
-                code: 500,
-                output: json!({
-                "output": e.to_string()
-                }),
-            });
-        }
-    };
+pub async fn handle_api(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<APIInput>,
+) -> Result<Response, OneLlmError> {
+    let (user, key) = authenticate(&state, &headers).await?;
+
+    if payload.stream.unwrap_or(false) {
+        let (rx, limits) = payload.stream(&state, &user, &key).await?;
+
+        return Ok((
+            limits.headers(),
+            Sse::new(sse_events(rx)).keep_alive(KeepAlive::default()),
+        )
+            .into_response());
+    }
+
+    let (output, limits) = payload.get(&state, &user, &key).await?;
 
     // Return the successful response
-    Json(Output {
-        code: 200,
-        output: json!(output),
-    })
+    Ok((
+        limits.headers(),
+        Json(Output {
+            code: 200,
+            output: json!(output),
+        }),
+    )
+        .into_response())
+}
+
+/// Turns the chunks produced by `APIInput::stream` into SSE events, ending with `[DONE]` like
+/// the OpenAI wire format so existing SSE clients know when to stop reading.
+fn sse_events(rx: mpsc::Receiver<StreamEvent>) -> impl Stream<Item = Result<Event, Infallible>> {
+    let events = stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await? {
+            StreamEvent::Chunk(chunk) => Event::default()
+                .json_data(chunk)
+                .unwrap_or_else(|e| Event::default().event("error").data(e.to_string())),
+            StreamEvent::Error(e) => Event::default().event("error").data(e),
+        };
+        Some((Ok(event), rx))
+    });
+
+    events.chain(stream::once(async { Ok(Event::default().data("[DONE]")) }))
 }
 
 async fn signup_and_update_db(
-    pool: PgPool,
+    pool: &PgPool,
     email: String,
     password: String,
 ) -> Result<Option<User>, Box<dyn std::error::Error>> {
@@ -231,7 +270,7 @@ async fn signup_and_update_db(
         None => return Ok(None),
     };
 
-    match user.new_user(Some(pool)).await {
+    match user.new_user(pool).await {
         Ok(()) => {}
         Err(_) => return Ok(None),
     };
@@ -239,65 +278,75 @@ async fn signup_and_update_db(
     Ok(Some(user))
 }
 
-pub async fn handle_post_website(Json(query): Json<WebInput>) -> impl IntoResponse {
-    let pool = init_pool().await.expect("Error init pool");
-    match query.function {
-        WebQuery::Signup => {
-            let user: Option<User> = signup_and_update_db(pool, query.email, query.password)
-                .await
-                .unwrap();
-
-            match user {
-                Some(_) => Json(FailOrSucc::Successful(String::from("Successful operation"))),
-                None => Json(FailOrSucc::Failure(String::from(
-                    "Error while trying to create your account",
-                ))),
+pub async fn handle_post_website(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(query): Json<WebInput>,
+) -> Result<Json<FailOrSucc>, OneLlmError> {
+    let pool = &state.pool;
+    let res =
+        match query.function {
+            WebQuery::Signup => {
+                let user: Option<User> =
+                    signup_and_update_db(pool, query.email, query.password).await?;
+
+                match user {
+                    Some(_) => Json(FailOrSucc::Successful(String::from("Successful operation"))),
+                    None => Json(FailOrSucc::Failure(String::from(
+                        "Error while trying to create your account",
+                    ))),
+                }
             }
-        }
 
-        //        WebQuery::ChangePwd => {
-        //            match User::change_password(&query.token.unwrap_or("".to_string()), query.password)
-        //                .await
-        //            {
-        //                Ok(_) => {
-        //                    return Json(FailOrSucc::Successful(
-        //                        "Successfully changed password".to_string(),
-        //                    ));
-        //                }
-        //                Err(e) => return Json(FailOrSucc::Failure(e.to_string())),
-        //            }
-        //        }
-        WebQuery::Login => {
-            let mut user =
-                match basicauth::login(Some(pool.clone()), query.email, query.password).await {
-                    Some(u) => u,
-                    None => {
-                        return Json(FailOrSucc::Failure("Could not log user in".to_string()));
-                    }
+            WebQuery::Login => {
+                let mut redis = state.redis.clone();
+                let mut user = match basicauth::login(
+                    pool,
+                    &mut redis,
+                    query.email,
+                    query.password,
+                    query.code.as_deref(),
+                    state.config.auth.totp_key.as_deref(),
+                )
+                .await
+                {
+                    Ok(u) => u,
+                    Err(e) => return Ok(Json(FailOrSucc::Failure(e.to_string()))),
                 };
 
-            user.balance /= 1_000_000;
-
-            let token = match User::new_token(Some(pool), user.id).await {
-                Ok(tok) => tok,
-                Err(e) => return Json(FailOrSucc::Failure(e.to_string())),
-            };
+                let balance = user.personal_balance(pool).await? / 1_000_000;
+
+                let client = session_client(&headers, addr);
+                let new_session =
+                    match session::create(pool, &state.config.auth.jwt_secret, user.id, &client)
+                        .await
+                    {
+                        Ok(s) => s,
+                        Err(e) => return Ok(Json(FailOrSucc::Failure(e.to_string()))),
+                    };
+
+                let hidden_user = WebOutput {
+                    user: HiddenUser::from_user(&mut user, balance).await,
+                    token: new_session.token,
+                    refresh_token: Some(new_session.refresh_token),
+                };
 
-            let hidden_user = WebOutput {
-                user: HiddenUser::from_user(&mut user).await,
-                token,
-            };
+                Json(FailOrSucc::User(hidden_user))
+            }
+            _ => Json(FailOrSucc::Failure(
+                "Tried to do Handle API at POST section".to_owned(),
+            )),
+        };
 
-            Json(FailOrSucc::User(hidden_user))
-        }
-        _ => Json(FailOrSucc::Failure(
-            "Tried to do Handle API at POST section".to_owned(),
-        )),
-    }
+    Ok(res)
 }
 
-async fn login_with_token(Json(query): Json<TokenInput>) -> Json<FailOrSucc> {
-    let mut hidden_user = match User::from_token(None, query.token.clone()).await {
+async fn login_with_token(
+    State(state): State<AppState>,
+    Json(query): Json<TokenInput>,
+) -> Json<FailOrSucc> {
+    let mut hidden_user = match User::from_token(&state.pool, query.token.clone()).await {
         Ok(huser) => huser,
         Err(e) => return Json(FailOrSucc::Failure(e.to_string())),
     };
@@ -305,35 +354,109 @@ async fn login_with_token(Json(query): Json<TokenInput>) -> Json<FailOrSucc> {
     Json(FailOrSucc::User(WebOutput {
         user: hidden_user,
         token: query.token,
+        refresh_token: None,
     }))
 }
 
-pub async fn handle_token_auth(Json(payload): Json<TokenInput>) -> impl IntoResponse {
-    let pool = init_pool().await.expect("Error init pool");
+/// Swaps a refresh token for a new access token and refresh token.
+async fn refresh_session(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(query): Json<RefreshInput>,
+) -> Json<FailOrSucc> {
+    let client = session_client(&headers, addr);
+    match session::refresh(
+        &state.pool,
+        &state.config.auth.jwt_secret,
+        &query.refresh_token,
+        &client,
+    )
+    .await
+    {
+        Ok(new_session) => Json(FailOrSucc::SuccessSession(new_session)),
+        Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+    }
+}
+
+async fn logout(State(state): State<AppState>, Json(query): Json<LogoutInput>) -> Json<FailOrSucc> {
+    let pool = &state.pool;
+    let res = if query.everywhere {
+        let user_id = session::user_id(pool, &query.token)
+            .await
+            .map_err(|e| e.to_string());
+        match user_id {
+            Ok(user_id) => session::revoke_all(pool, user_id)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        }
+    } else {
+        session::revoke_token(pool, &query.token)
+            .await
+            .map_err(|e| e.to_string())
+    };
 
-    let res = match User::from_token(Some(pool.clone()), payload.token.clone()).await {
+    match res {
+        Ok(()) => Json(FailOrSucc::Successful("Logged out".to_string())),
+        Err(e) => Json(FailOrSucc::Failure(e)),
+    }
+}
+
+/// The user agent and address a login comes from. Behind a proxy the address is the first
+/// one in `X-Forwarded-For`, which is only shown to the user and never trusted.
+fn session_client(headers: &HeaderMap, addr: SocketAddr) -> session::Client {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.chars().take(512).collect::<String>())
+    };
+    let forwarded = header("x-forwarded-for")
+        .and_then(|v| v.split(',').next().map(|ip| ip.trim().to_string()))
+        .filter(|ip| !ip.is_empty());
+
+    session::Client {
+        user_agent: header("user-agent"),
+        ip: Some(forwarded.unwrap_or_else(|| addr.ip().to_string())),
+    }
+}
+
+pub async fn handle_token_auth(
+    State(state): State<AppState>,
+    Json(payload): Json<TokenInput>,
+) -> Result<Json<FailOrSucc>, OneLlmError> {
+    let pool = &state.pool;
+
+    let res = match User::from_token(pool, payload.token.clone()).await {
         Ok(u) => u,
         Err(e) => {
-            return Json(FailOrSucc::Failure(e.to_string()));
+            return Ok(Json(FailOrSucc::Failure(e.to_string())));
         }
     };
 
-    let user = match User::get_row(Some(pool.clone()), res.email).await {
+    let user = match User::get_row(pool, res.email).await {
         Ok(a) => a,
-        Err(e) => return Json(FailOrSucc::Failure(e.to_string())),
+        Err(e) => return Ok(Json(FailOrSucc::Failure(e.to_string()))),
     };
 
-    if !user
-        .is_verified(Some(pool.clone()))
-        .await
-        .expect("Error trying to see if user was verified")
-    {
-        return Json(FailOrSucc::Failure("User isn't verified".to_string()));
+    if !user.is_verified(pool).await? {
+        return Ok(Json(FailOrSucc::Failure("User isn't verified".to_string())));
     }
 
-    match payload.function {
+    let organization_id = payload
+        .organization_id
+        .unwrap_or(user.personal_organization_id);
+
+    let res = match payload.function {
         WebQuery::NewAPI => match user
-            .generate_apikey(Some(pool.clone()), &payload.name.unwrap_or("".to_owned()))
+            .generate_apikey(
+                pool,
+                organization_id,
+                &payload.name.unwrap_or("".to_owned()),
+                state.config.limits.max_api_keys,
+            )
             .await
         {
             Ok(api) => Json(FailOrSucc::SuccessData(api)),
@@ -342,7 +465,8 @@ pub async fn handle_token_auth(Json(payload): Json<TokenInput>) -> impl IntoResp
 
         WebQuery::DelAPI => {
             match User::delete_apikey(
-                Some(pool),
+                pool,
+                &state.config.auth.jwt_secret,
                 &payload.token,
                 Some(&payload.name.unwrap_or("".to_string())),
                 false,
@@ -355,12 +479,157 @@ pub async fn handle_token_auth(Json(payload): Json<TokenInput>) -> impl IntoResp
         }
 
         WebQuery::APICount => {
-            match User::get_keynames(Some(pool), &payload.email.unwrap_or("".to_string())).await {
+            match User::get_keynames(pool, &payload.email.unwrap_or("".to_string())).await {
                 Ok(keynamevec) => Json(FailOrSucc::SuccessVecData(keynamevec)),
                 Err(e) => Json(FailOrSucc::Failure(e.to_string())),
             }
         }
 
+        WebQuery::SetKeyLimits => {
+            let name = payload.name.unwrap_or_default();
+            let limits = payload.limits.unwrap_or_default();
+            match user.set_key_limits(pool, &name, &limits).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::SetKeyAccess => {
+            let name = payload.name.unwrap_or_default();
+            let access = payload.access.unwrap_or_default();
+            match user.set_key_access(pool, &name, &access).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::RotateAPI => {
+            let grace = payload.grace_period.unwrap_or(DEFAULT_ROTATION_GRACE);
+            match user
+                .rotate_apikey(pool, &payload.name.unwrap_or_default(), grace)
+                .await
+            {
+                Ok(api) => Json(FailOrSucc::SuccessData(api)),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::ListAPI => match user.list_apikeys(pool).await {
+            Ok(keys) => Json(FailOrSucc::SuccessKeys(keys)),
+            Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+        },
+
+        WebQuery::EnableTotp => {
+            match twofa::start_totp(pool, state.config.auth.totp_key.as_deref(), &user).await {
+                Ok(enrollment) => Json(FailOrSucc::SuccessTotp(enrollment)),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::ConfirmTotp => {
+            let code = payload.code.unwrap_or_default();
+            let totp_key = state.config.auth.totp_key.as_deref();
+            match twofa::confirm_totp(pool, totp_key, &user, &code).await {
+                Ok(recovery_codes) => Json(FailOrSucc::SuccessVecData(recovery_codes)),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::DisableTotp => {
+            let code = payload.code.unwrap_or_default();
+            let totp_key = state.config.auth.totp_key.as_deref();
+            let mut redis = state.redis.clone();
+            match twofa::disable_totp(pool, &mut redis, totp_key, &user, &code).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::NewRecoveryCodes => {
+            let code = payload.code.unwrap_or_default();
+            let totp_key = state.config.auth.totp_key.as_deref();
+            let mut redis = state.redis.clone();
+            match twofa::new_recovery_codes(pool, &mut redis, totp_key, &user, &code).await {
+                Ok(recovery_codes) => Json(FailOrSucc::SuccessVecData(recovery_codes)),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::ListSessions => match session::list(pool, user.id, &payload.token).await {
+            Ok(sessions) => Json(FailOrSucc::SuccessSessions(sessions)),
+            Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+        },
+
+        WebQuery::RevokeSession => {
+            let id = payload.session_id.unwrap_or_default();
+            match session::revoke(pool, user.id, id).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::CreateOrg => {
+            match organization::create(pool, &payload.name.unwrap_or_default(), user.id).await {
+                Ok(id) => Json(FailOrSucc::SuccessData(id.to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::ListOrgs => match organization::memberships(pool, user.id, &user.email).await {
+            Ok(orgs) => Json(FailOrSucc::SuccessOrgs(orgs)),
+            Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+        },
+
+        WebQuery::InviteMember => {
+            let email = payload.email.unwrap_or_default();
+            let role = payload.role.unwrap_or(Role::Member);
+            match organization::invite(pool, organization_id, user.id, &email, role).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::AcceptInvite => {
+            match organization::accept_invite(pool, organization_id, user.id, &user.email).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::RemoveMember => {
+            let email = payload.email.unwrap_or_default();
+            match organization::remove_member(pool, organization_id, user.id, &email).await {
+                Ok(()) => Json(FailOrSucc::Successful("Successful operation".to_string())),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
+        WebQuery::ListMembers => {
+            let member = organization::require_role(pool, organization_id, user.id, |_| true)
+                .await
+                .map_err(|e| e.to_string());
+            let members = match member {
+                Ok(_) => organization::members(pool, organization_id)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            match members {
+                Ok(members) => Json(FailOrSucc::SuccessMembers(members)),
+                Err(e) => Json(FailOrSucc::Failure(e)),
+            }
+        }
+
+        WebQuery::GetKeyLimits => {
+            match user
+                .get_key_budget(pool, &payload.name.unwrap_or_default())
+                .await
+            {
+                Ok(budget) => Json(FailOrSucc::SuccessKeyBudget(budget)),
+                Err(e) => Json(FailOrSucc::Failure(e.to_string())),
+            }
+        }
+
         //        WebQuery::DelAllAPI => {
         //            match User::delete_apikey(&user.email, &payload.password, "", true).await {
         //                Ok(()) => return Json(FailOrSucc::Successful("Successful operation".to_string())),
@@ -368,5 +637,61 @@ pub async fn handle_token_auth(Json(payload): Json<TokenInput>) -> impl IntoResp
         //            }
         //        }
         _ => Json(FailOrSucc::Failure(String::from("Incorrect endpoint"))),
+    };
+
+    Ok(res)
+}
+
+/// Usage of the logged in user, or of the owner of an API key with the `usage:read` scope,
+/// summed per day, model and/or key. Answers with `SuccessUsage` or, for `"format": "csv"`,
+/// a CSV file.
+async fn usage_report(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(query): Json<UsageQuery>,
+) -> Result<Response, OneLlmError> {
+    let pool = &state.pool;
+
+    let user_id = match query.token {
+        Some(token) => {
+            let session = User::from_token(pool, token)
+                .await
+                .map_err(|_| OneLlmError::Auth("Invalid or expired session".to_string()))?;
+            User::get_row(pool, session.email).await?.id
+        }
+        None => {
+            let (user, key) = authenticate(&state, &headers).await?;
+            if !key.allows(Scope::UsageRead) {
+                return Err(OneLlmError::Forbidden(
+                    "This API key does not have the usage:read scope".to_string(),
+                ));
+            }
+            user.id
+        }
+    };
+
+    if let (Some(from), Some(to)) = (query.from, query.to)
+        && from > to
+    {
+        return Err(OneLlmError::InvalidRequest(
+            "from must not be after to".to_string(),
+        ));
     }
+
+    let rows = usage::aggregate(pool, user_id, &query.group_by, query.from, query.to).await?;
+
+    Ok(match query.format {
+        UsageFormat::Json => Json(FailOrSucc::SuccessUsage(rows)).into_response(),
+        UsageFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"usage.csv\"",
+                ),
+            ],
+            usage::to_csv(&rows),
+        )
+            .into_response(),
+    })
 }
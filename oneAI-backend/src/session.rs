@@ -0,0 +1,260 @@
+use std::error::Error;
+
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{EncodingKey, Header, encode};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    auth::basicauth::{generate_token, hash_api},
+    utils::Claims,
+};
+
+/// How long an access token is accepted before it has to be refreshed.
+const ACCESS_TOKEN_TTL: Duration = Duration::hours(24);
+/// How long a session can go without being refreshed before the user has to log in again.
+const REFRESH_TOKEN_TTL: Duration = Duration::days(30);
+/// How often expired sessions and password reset links are deleted.
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Where a login comes from, shown when listing sessions.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Tokens of a new or refreshed session. `token` authenticates requests, `refresh_token`
+/// gets a new pair once it expires. Both are shown to the user once.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct NewSession {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow)]
+pub struct SessionInfo {
+    pub id: i32,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// The session the listing was asked from.
+    pub current: bool,
+}
+
+/// Starts a session for `user_id`.
+pub async fn create(
+    pool: &PgPool,
+    jwt_secret: &str,
+    user_id: i32,
+    client: &Client,
+) -> Result<NewSession, Box<dyn Error>> {
+    let (token, expires_at) = access_token(jwt_secret, user_id)?;
+    let refresh_token = generate_token();
+
+    sqlx::query(
+        "INSERT INTO sessions (user_id, token, created_at, expires_at, last_seen_at, \
+         user_agent, ip, refresh_token_hash, refresh_expires_at) \
+         VALUES ($1, $2, NOW(), $3, NOW(), $4, $5, $6, $7)",
+    )
+    .bind(user_id)
+    .bind(&token)
+    .bind(expires_at)
+    .bind(&client.user_agent)
+    .bind(&client.ip)
+    .bind(hash_api(&refresh_token))
+    .bind(Utc::now() + REFRESH_TOKEN_TTL)
+    .execute(pool)
+    .await?;
+
+    Ok(NewSession {
+        token,
+        refresh_token,
+    })
+}
+
+/// Replaces both tokens of the session `refresh_token` belongs to. A refresh token can only
+/// be used once, presenting a replaced one again means it leaked and revokes the session.
+pub async fn refresh(
+    pool: &PgPool,
+    jwt_secret: &str,
+    refresh_token: &str,
+    client: &Client,
+) -> Result<NewSession, Box<dyn Error>> {
+    let refresh_hash = hash_api(refresh_token);
+    let mut tx = pool.begin().await?;
+
+    let session: Option<(i32, i32)> = sqlx::query_as(
+        "SELECT id, user_id FROM sessions \
+         WHERE refresh_token_hash = $1 AND refresh_expires_at > NOW() FOR UPDATE",
+    )
+    .bind(&refresh_hash)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((id, user_id)) = session else {
+        let reused = sqlx::query("DELETE FROM sessions WHERE previous_refresh_token_hash = $1")
+            .bind(&refresh_hash)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        return Err(if reused.rows_affected() > 0 {
+            "This refresh token was already used, the session has been revoked".into()
+        } else {
+            "Invalid or expired refresh token".into()
+        });
+    };
+
+    let (token, expires_at) = access_token(jwt_secret, user_id)?;
+    let new_refresh_token = generate_token();
+
+    sqlx::query(
+        "UPDATE sessions SET token = $1, expires_at = $2, refresh_token_hash = $3, \
+         previous_refresh_token_hash = $4, refresh_expires_at = $5, last_seen_at = NOW(), \
+         user_agent = COALESCE($6, user_agent), ip = COALESCE($7, ip) \
+         WHERE id = $8",
+    )
+    .bind(&token)
+    .bind(expires_at)
+    .bind(hash_api(&new_refresh_token))
+    .bind(&refresh_hash)
+    .bind(Utc::now() + REFRESH_TOKEN_TTL)
+    .bind(&client.user_agent)
+    .bind(&client.ip)
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(NewSession {
+        token,
+        refresh_token: new_refresh_token,
+    })
+}
+
+/// The user `token` was issued to, as long as its session wasn't revoked and hasn't expired.
+pub async fn user_id(pool: &PgPool, token: &str) -> Result<i32, Box<dyn Error>> {
+    sqlx::query_scalar("SELECT user_id FROM sessions WHERE token = $1 AND expires_at > NOW()")
+        .bind(token)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| "Invalid or expired session".into())
+}
+
+/// Records that the session was just used. Only written once a minute at most.
+pub async fn touch(pool: &PgPool, token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE sessions SET last_seen_at = NOW() WHERE token = $1 \
+         AND (last_seen_at IS NULL OR last_seen_at < NOW() - INTERVAL '1 minute')",
+    )
+    .bind(token)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Sessions of the user that can still be used or refreshed, most recently used first.
+pub async fn list(
+    pool: &PgPool,
+    user_id: i32,
+    current_token: &str,
+) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, created_at, last_seen_at, user_agent, ip, token = $2 AS current \
+         FROM sessions \
+         WHERE user_id = $1 AND COALESCE(refresh_expires_at, expires_at) > NOW() \
+         ORDER BY last_seen_at DESC NULLS LAST, id DESC",
+    )
+    .bind(user_id)
+    .bind(current_token)
+    .fetch_all(pool)
+    .await
+}
+
+/// Logs out of the session `token` belongs to.
+pub async fn revoke_token(pool: &PgPool, token: &str) -> Result<(), Box<dyn Error>> {
+    let revoked = sqlx::query("DELETE FROM sessions WHERE token = $1")
+        .bind(token)
+        .execute(pool)
+        .await?;
+    if revoked.rows_affected() == 0 {
+        return Err("Invalid or expired session".into());
+    }
+
+    Ok(())
+}
+
+/// Logs out of one of the user's sessions, by its id in `list`.
+pub async fn revoke(pool: &PgPool, user_id: i32, session_id: i32) -> Result<(), Box<dyn Error>> {
+    let revoked = sqlx::query("DELETE FROM sessions WHERE id = $1 AND user_id = $2")
+        .bind(session_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    if revoked.rows_affected() == 0 {
+        return Err("No such session".into());
+    }
+
+    Ok(())
+}
+
+/// Logs the user out everywhere and returns how many sessions were ended.
+pub async fn revoke_all(pool: &PgPool, user_id: i32) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?
+        .rows_affected())
+}
+
+/// Deletes sessions that can't be refreshed anymore and password reset links that expired.
+pub async fn sweep_expired(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let sessions =
+        sqlx::query("DELETE FROM sessions WHERE COALESCE(refresh_expires_at, expires_at) <= NOW()")
+            .execute(pool)
+            .await?;
+    let resets = sqlx::query("DELETE FROM password_resets WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    Ok(sessions.rows_affected() + resets.rows_affected())
+}
+
+/// Runs `sweep_expired` every `SWEEP_INTERVAL` for as long as the server runs.
+pub fn spawn_sweeper(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep_expired(&pool).await {
+                eprintln!("Could not delete expired sessions: {}", e);
+            }
+        }
+    });
+}
+
+/// A signed access token for `user_id` and when it expires.
+fn access_token(jwt_secret: &str, user_id: i32) -> Result<(String, DateTime<Utc>), Box<dyn Error>> {
+    let now = Utc::now();
+    let exp = now + ACCESS_TOKEN_TTL;
+
+    let claims = Claims {
+        sub: user_id,
+        iat: now.timestamp() as usize,
+        exp: exp.timestamp() as usize,
+        // Tokens issued to the same user in the same second would be identical otherwise
+        jti: generate_token(),
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret.as_ref()),
+    )?;
+
+    Ok((token, exp))
+}
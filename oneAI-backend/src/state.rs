@@ -0,0 +1,46 @@
+use std::{error::Error, sync::Arc};
+
+use redis::aio::ConnectionManager;
+use sqlx::PgPool;
+
+use crate::{
+    config::Config,
+    database, ledger,
+    mail::{self, Mailer},
+    session,
+};
+
+/// Connections and settings shared by every handler. Cloning is cheap, all fields are handles
+/// to the same underlying pools.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub redis: ConnectionManager,
+    /// Used for every request to a provider so connections are reused.
+    pub http: reqwest::Client,
+    pub mailer: Arc<dyn Mailer>,
+    pub config: Arc<Config>,
+}
+
+impl AppState {
+    /// Connects to Postgres and Redis, runs pending migrations and starts releasing stale holds
+    /// and deleting expired sessions in the background.
+    pub async fn new(config: Config) -> Result<Self, Box<dyn Error>> {
+        let pool = database::init_pool(&config.database.postgres).await?;
+        database::init_db(&pool).await?;
+        ledger::spawn_hold_releaser(pool.clone());
+        session::spawn_sweeper(pool.clone());
+
+        let redis = redis::Client::open(config.database.redis.as_str())?
+            .get_connection_manager()
+            .await?;
+
+        Ok(Self {
+            pool,
+            redis,
+            http: reqwest::Client::new(),
+            mailer: mail::from_config(&config)?.into(),
+            config: Arc::new(config),
+        })
+    }
+}
@@ -1,16 +1,133 @@
 #[cfg(test)]
 mod tests {
     use crate::{
-        auth::basicauth::{login, signup},
+        auth::{
+            basicauth::{api_public_id, hash_api, login, signup},
+            twofa,
+        },
+        compat::{
+            anthropic::{EventState, MessagesRequest, message_events},
+            openai::ChatCompletionRequest,
+        },
+        config::{Config, ProviderConfig},
         database,
-        utils::User,
+        error::OneLlmError,
+        ledger::{Hold, Placement, Transaction, TransactionKind},
+        mail::{self, FileMailer},
+        organization::{self, Role},
+        payment::{self, StripeEvent},
+        pricing::Model,
+        ratelimit::{self, Limit, RateLimit},
+        requests::{
+            parseapi::{APIInput, Message, MessageContent, ToolChoice, ToolMode},
+            provider::{get_provider, provider_url},
+            requests::{AIProvider, Completion},
+            responseparser::common::{LlmStreamChunk, LlmUsage, ToolCallDelta},
+            stream::{SseEvent, StreamEvent, drive_stream},
+        },
+        session::{self, Client},
+        state::AppState,
+        usage::{self, UsageEvent, UsageGroup},
+        utils::{ApiKey, KeyAccess, KeyLimits, Scope, User},
     };
+    use futures_util::StreamExt;
+    use onellm_types::{Content, GenerationConfig, Part};
+    use redis::{AsyncCommands, aio::ConnectionManager};
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::sync::Arc;
+
+    /// A conversation where the assistant called two tools and both results are being sent back.
+    fn tool_round_trip_input(model: &str) -> APIInput {
+        serde_json::from_value(json!({
+            "model": model,
+            "max_tokens": 100,
+            "top_p": 1.0,
+            "temperature": null,
+            "stream": false,
+            "stop_sequences": null,
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "description": "Weather for a city",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }}],
+            "tool_choice": "get_weather",
+            "messages": [
+                {"role": "user", "content": "Weather in Paris and Rome?"},
+                {"role": "assistant", "content": "", "tool_calls": [
+                    {"id": "call_1", "name": "get_weather", "arguments": {"city": "Paris"}},
+                    {"id": "call_2", "name": "get_weather", "arguments": {"city": "Rome"}}
+                ]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "18C"},
+                {"role": "tool", "tool_call_id": "call_2", "content": "{\"temp\": 25}"}
+            ]
+        }))
+        .unwrap()
+    }
+
+    /// Connects to the database in `POSTGRES` and migrates it.
+    async fn test_pool() -> PgPool {
+        let url = std::env::var("POSTGRES").expect("POSTGRES must be set to run database tests");
+        let pool = database::init_pool(&url)
+            .await
+            .expect("error connecting to database");
+        database::init_db(&pool)
+            .await
+            .expect("error initialising database");
+        pool
+    }
+
+    /// Connects to the Redis in `REDIS`.
+    async fn test_redis() -> ConnectionManager {
+        let url = std::env::var("REDIS").expect("REDIS must be set to run Redis tests");
+        redis::Client::open(url)
+            .unwrap()
+            .get_connection_manager()
+            .await
+            .expect("error connecting to Redis")
+    }
+
+    /// State on the test database and Redis with a placeholder OpenAI key.
+    async fn test_state() -> AppState {
+        let mut config = Config::default();
+        config.database.postgres =
+            std::env::var("POSTGRES").expect("POSTGRES must be set to run database tests");
+        config.database.redis =
+            std::env::var("REDIS").expect("REDIS must be set to run Redis tests");
+        config.mail.transport = "stdout".to_string();
+        config.providers.insert(
+            "openai".to_string(),
+            ProviderConfig {
+                key: "test".to_string(),
+                base_url: Some("http://127.0.0.1:9/v1".to_string()),
+            },
+        );
+        AppState::new(config).await.unwrap()
+    }
+
+    /// A newly signed up user with `email`, replacing the one an earlier run left behind.
+    async fn test_user(email: &str) -> (PgPool, User) {
+        let pool = test_pool().await;
+        let _ = User::delete_user(&pool, email).await;
+        signup(email.to_string(), "wedFF1234".to_string())
+            .await
+            .unwrap()
+            .new_user(&pool)
+            .await
+            .unwrap();
+        let user = User::get_row(&pool, email.to_string()).await.unwrap();
+        (pool, user)
+    }
 
     #[tokio::test]
     async fn user_auth() {
-        database::init_db()
+        let pool = database::init_pool(&std::env::var("POSTGRES").unwrap())
+            .await
+            .expect("error connecting to database");
+        database::init_db(&pool)
             .await
             .expect("error initialising database");
+        let mut redis = test_redis().await;
 
         let email = "something@email.com".to_string();
         let password = "wedFF1234".to_string();
@@ -23,26 +140,1931 @@ mod tests {
                 id: 0,
                 email: String::new(),
                 password: String::new(),
-                balance: 1,
                 verified: false,
+                personal_organization_id: 0,
             },
         };
 
         println!("User: {:#?}", unwrapped_hashed_user);
 
         unwrapped_hashed_user
-            .new_user(None)
+            .new_user(&pool)
             .await
             .expect("Error while trying to add new_user to database");
         assert_eq!(unwrapped_hashed_user.email, email);
-        assert_eq!(unwrapped_hashed_user.balance, 0);
+        let user = User::get_row(&pool, email.clone()).await.unwrap();
+        assert_eq!(user.personal_balance(&pool).await.unwrap(), 0);
 
-        let res = login(None, email.clone(), password).await;
+        let res = login(&pool, &mut redis, email.clone(), password, None, None).await;
         println!("Res:\n{:#?}\n", res);
-        assert_ne!(res, None);
+        assert!(res.is_ok());
 
-        User::delete_user(None, &email)
+        User::delete_user(&pool, &email)
             .await
             .expect("Error Deleting user: ");
     }
+
+    #[test]
+    fn model_lookup_accepts_onellm_and_provider_names() {
+        assert!(matches!(Model::from_name("GPT-4o"), Some(Model::Gpt4o)));
+        assert!(matches!(Model::from_name("gpt-4o"), Some(Model::Gpt4o)));
+        assert!(matches!(
+            Model::from_name("deepseek-reasoner"),
+            Some(Model::DeepSeekR1)
+        ));
+        assert!(Model::from_name("gpt-unknown").is_none());
+    }
+
+    #[test]
+    fn anthropic_provider_parses_responses_and_errors() {
+        let provider = get_provider(AIProvider::Anthropic).unwrap();
+
+        let body = r#"{
+            "id": "msg_1", "type": "message", "role": "assistant", "model": "claude-sonnet-4-20250514",
+            "content": [{"type": "text", "text": "Hello"}],
+            "stop_reason": "end_turn", "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 2}
+        }"#;
+        let res = provider.parse_response(body).unwrap();
+        assert_eq!(res.content, "Hello");
+        assert_eq!(res.usage.unwrap().output_tokens, Some(2));
+
+        let err = provider.map_error(
+            429,
+            r#"{"type":"error","error":{"type":"rate_limit_error","message":"slow down"}}"#,
+        );
+        assert_eq!(err.status, 429);
+        assert_eq!(err.message, "rate_limit_error: slow down");
+    }
+
+    #[test]
+    fn openai_provider_parses_stream_events() {
+        let provider = get_provider(AIProvider::OpenAI).unwrap();
+
+        let event = SseEvent {
+            event: None,
+            data: r#"{"id":"c1","object":"chat.completion.chunk","created":1,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}]}"#.to_string(),
+        };
+        let chunk = provider.parse_stream_event(&event, "").unwrap().unwrap();
+        assert_eq!(chunk.delta.as_deref(), Some("Hi"));
+
+        let done = SseEvent {
+            event: None,
+            data: "[DONE]".to_string(),
+        };
+        assert!(provider.parse_stream_event(&done, "").unwrap().is_none());
+    }
+
+    #[test]
+    fn anthropic_provider_round_trips_tool_calls() {
+        let provider = get_provider(AIProvider::Anthropic).unwrap();
+
+        let body = r#"{
+            "id": "msg_1", "type": "message", "role": "assistant", "model": "claude-sonnet-4-20250514",
+            "content": [
+                {"type": "text", "text": "Checking"},
+                {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+            ],
+            "stop_reason": "tool_use", "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 2}
+        }"#;
+        let res = provider.parse_response(body).unwrap();
+        assert_eq!(res.content, "Checking");
+        assert_eq!(res.tool_calls.len(), 1);
+        assert_eq!(res.tool_calls[0].id, "toolu_1");
+        assert_eq!(res.tool_calls[0].arguments, json!({"city": "Paris"}));
+
+        let req = provider.build_request(&tool_round_trip_input("Sonnet-4"), 100);
+        assert_eq!(req["tools"][0]["input_schema"]["type"], "object");
+        assert_eq!(
+            req["tool_choice"],
+            json!({"type": "tool", "name": "get_weather"})
+        );
+
+        let messages = req["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][0]["type"], "tool_use");
+        assert_eq!(messages[1]["content"][1]["input"]["city"], "Rome");
+        // Both results in one user turn
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"][1]["tool_use_id"], "call_2");
+    }
+
+    #[test]
+    fn openai_and_gemini_translate_tool_calls() {
+        let input = tool_round_trip_input("GPT-4o");
+        let req = get_provider(AIProvider::OpenAI)
+            .unwrap()
+            .build_request(&input, 100);
+        assert_eq!(req["messages"][1]["content"], json!(null));
+        assert_eq!(
+            req["messages"][1]["tool_calls"][0]["function"]["arguments"],
+            r#"{"city":"Paris"}"#
+        );
+        assert_eq!(req["messages"][3]["tool_call_id"], "call_2");
+        assert_eq!(req["tool_choice"]["function"]["name"], "get_weather");
+
+        let input = tool_round_trip_input("2.0-Flash");
+        let req = get_provider(AIProvider::Gemini)
+            .unwrap()
+            .build_request(&input, 100);
+        assert_eq!(
+            req["tools"][0]["functionDeclarations"][0]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            req["toolConfig"]["functionCallingConfig"]["allowedFunctionNames"][0],
+            "get_weather"
+        );
+        let contents = req["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(
+            contents[1]["parts"][1]["functionCall"]["args"]["city"],
+            "Rome"
+        );
+        let response = &contents[2]["parts"][1]["functionResponse"];
+        assert_eq!(response["name"], "get_weather");
+        assert_eq!(response["response"], json!({"temp": 25}));
+        assert_eq!(
+            contents[2]["parts"][0]["functionResponse"]["response"]["result"],
+            "18C"
+        );
+
+        let body = r#"{"candidates": [{"content": {"role": "model", "parts": [
+            {"functionCall": {"name": "get_weather", "args": {"city": "Oslo"}}}
+        ]}, "finishReason": "STOP"}]}"#;
+        let res = get_provider(AIProvider::Gemini)
+            .unwrap()
+            .parse_response(body)
+            .unwrap();
+        assert_eq!(res.tool_calls[0].name, "get_weather");
+        assert!(res.tool_calls[0].id.starts_with("call_"));
+    }
+
+    #[test]
+    fn openai_compat_keeps_tool_choice_tagged() {
+        let request = |tool_choice: serde_json::Value, n: u32| -> ChatCompletionRequest {
+            serde_json::from_value(json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "hi"}],
+                "n": n,
+                "tool_choice": tool_choice,
+            }))
+            .unwrap()
+        };
+        let convert = |tool_choice, n| request(tool_choice, n).into_api_input(Model::Gpt4o);
+
+        let input = convert(json!({"type": "function", "function": {"name": "auto"}}), 1).unwrap();
+        let req = get_provider(AIProvider::OpenAI)
+            .unwrap()
+            .build_request(&input, 100);
+        assert_eq!(req["tool_choice"]["function"]["name"], "auto");
+
+        let input = convert(json!("auto"), 1).unwrap();
+        let req = get_provider(AIProvider::Gemini)
+            .unwrap()
+            .build_request(&input, 100);
+        assert_eq!(
+            req["toolConfig"]["functionCallingConfig"],
+            json!({"mode": "AUTO"})
+        );
+
+        assert!(
+            serde_json::from_value::<ChatCompletionRequest>(json!({
+                "model": "gpt-4o",
+                "messages": [],
+                "tool_choice": "sometimes",
+            }))
+            .is_err()
+        );
+        let openai = get_provider(AIProvider::OpenAI).unwrap();
+        assert_eq!(
+            convert(json!("none"), 2)
+                .unwrap()
+                .check_content(openai)
+                .unwrap_err(),
+            "n greater than 1 is not supported"
+        );
+    }
+
+    #[test]
+    fn estimates_count_gemini_contents_and_extra_choices_are_refused() {
+        let gemini = get_provider(AIProvider::Gemini).unwrap();
+        let flash = Model::from_name("2.0-Flash").unwrap();
+        let mut input = APIInput::new(flash, vec![Message::new("user", "hi")], 100);
+        let estimate = input.estimate_input_tokens();
+
+        input.contents = Some(vec![Content {
+            role: "user".to_string(),
+            parts: vec![Part {
+                text: "x".repeat(3_000),
+            }],
+        }]);
+        assert!(input.estimate_input_tokens() >= estimate + 1_000);
+        input.check_content(gemini).unwrap();
+
+        input.n = Some(8);
+        assert_eq!(
+            input.check_content(gemini).unwrap_err(),
+            "n greater than 1 is not supported"
+        );
+        input.n = None;
+        input.generation_config = Some(GenerationConfig {
+            temperature: 1.0,
+            top_p: 1.0,
+            top_k: 40,
+            candidate_count: 4,
+            max_output_tokens: 100,
+            stop_sequences: Vec::new(),
+        });
+        assert_eq!(
+            input.check_content(gemini).unwrap_err(),
+            "candidate_count greater than 1 is not supported"
+        );
+    }
+
+    #[test]
+    fn anthropic_compat_flattens_system_and_content_blocks() {
+        let request: MessagesRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "max_tokens": 100,
+            "system": [
+                {"type": "text", "text": "Be brief."},
+                {"type": "text", "text": "Answer in French."},
+            ],
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image", "source": {
+                        "type": "base64", "media_type": "image/png", "data": "iVBOR",
+                    }},
+                ]},
+                {"role": "assistant", "content": "A cat."},
+            ],
+        }))
+        .unwrap();
+        let input = request.into_api_input(Model::ClaudeHaiku3_5).unwrap();
+
+        assert_eq!(input.messages.len(), 3);
+        assert_eq!(input.messages[0].role, "system");
+        assert_eq!(
+            input.messages[0].content.text(),
+            "Be brief.\nAnswer in French."
+        );
+        assert!(matches!(
+            &input.messages[1].content,
+            MessageContent::Parts(parts) if parts.len() == 2
+        ));
+        assert_eq!(input.messages[2].content.text(), "A cat.");
+
+        let request: MessagesRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": [{"type": "thinking"}]}],
+        }))
+        .unwrap();
+        assert_eq!(
+            request.into_api_input(Model::ClaudeHaiku3_5).unwrap_err(),
+            "Unsupported content block type 'thinking'"
+        );
+    }
+
+    #[test]
+    fn anthropic_compat_translates_tool_use_and_results() {
+        let request: MessagesRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "max_tokens": 100,
+            "tools": [{
+                "name": "get_weather",
+                "input_schema": {"type": "object", "properties": {"city": {"type": "string"}}},
+            }],
+            "tool_choice": {"type": "any"},
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Checking."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather",
+                        "input": {"city": "Paris"}},
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "18C"},
+                    {"type": "text", "text": "And tomorrow?"},
+                ]},
+            ],
+        }))
+        .unwrap();
+        let input = request.into_api_input(Model::ClaudeHaiku3_5).unwrap();
+
+        assert_eq!(
+            input.tools.as_ref().unwrap()[0].function.name,
+            "get_weather"
+        );
+        assert_eq!(input.tools.as_ref().unwrap()[0].function.description, "");
+        assert_eq!(
+            input.tool_choice,
+            Some(ToolChoice::Mode(ToolMode::Required))
+        );
+
+        let roles: Vec<_> = input.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "tool", "user"]);
+        let call = &input.messages[1].tool_calls[0];
+        assert_eq!(call.id, "toolu_1");
+        assert_eq!(call.arguments, json!({"city": "Paris"}));
+        assert_eq!(input.messages[1].content.text(), "Checking.");
+        assert_eq!(input.messages[2].tool_call_id.as_deref(), Some("toolu_1"));
+        assert_eq!(input.messages[2].content.text(), "18C");
+        assert_eq!(input.messages[3].content.text(), "And tomorrow?");
+
+        let request: MessagesRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "max_tokens": 100,
+            "tool_choice": {"type": "tool", "name": "auto"},
+            "messages": [{"role": "user", "content": "hi"}],
+        }))
+        .unwrap();
+        assert_eq!(
+            request
+                .into_api_input(Model::ClaudeHaiku3_5)
+                .unwrap()
+                .tool_choice,
+            Some(ToolChoice::Tool {
+                name: "auto".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn anthropic_compat_streams_the_message_event_sequence() {
+        let chunk = |delta: Option<&str>, tool_calls: Vec<ToolCallDelta>| LlmStreamChunk {
+            delta: delta.map(Into::into),
+            tool_calls,
+            ..Default::default()
+        };
+        let tool_delta = |id: Option<&str>, arguments: &str| ToolCallDelta {
+            index: 0,
+            id: id.map(Into::into),
+            name: id.map(|_| "get_weather".into()),
+            arguments: Some(arguments.into()),
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        for event in [
+            chunk(Some("Let me check."), Vec::new()),
+            chunk(None, vec![tool_delta(Some("call_1"), "{\"city\":")]),
+            chunk(None, vec![tool_delta(None, "\"Paris\"}")]),
+            LlmStreamChunk {
+                finish_reason: Some("tool_calls".into()),
+                ..Default::default()
+            },
+            LlmStreamChunk {
+                usage: Some(LlmUsage {
+                    input_tokens: Some(12),
+                    output_tokens: Some(7),
+                    total_tokens: Some(19),
+                }),
+                ..Default::default()
+            },
+        ] {
+            tx.send(StreamEvent::Chunk(event)).await.unwrap();
+        }
+        drop(tx);
+
+        let events: Vec<_> = message_events(EventState::new(rx, "claude".into()))
+            .collect()
+            .await;
+        let names: Vec<_> = events.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+
+        assert_eq!(events[0].1["message"]["model"], "claude");
+        assert_eq!(events[1].1["content_block"]["type"], "text");
+        assert_eq!(events[2].1["delta"]["text"], "Let me check.");
+        assert_eq!(events[4].1["index"], 1);
+        assert_eq!(events[4].1["content_block"]["id"], "call_1");
+        assert_eq!(events[6].1["delta"]["partial_json"], "\"Paris\"}");
+        assert_eq!(events[7].1["index"], 1);
+        assert_eq!(events[8].1["delta"]["stop_reason"], "tool_use");
+        assert_eq!(
+            events[8].1["usage"],
+            json!({"input_tokens": 12, "output_tokens": 7})
+        );
+    }
+
+    #[test]
+    fn image_parts_are_translated_and_checked() {
+        let mut input: APIInput = serde_json::from_value(json!({
+            "model": "GPT-4o",
+            "max_tokens": 100,
+            "top_p": 1.0,
+            "temperature": null,
+            "stream": false,
+            "stop_sequences": null,
+            "tools": null,
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBOR"}}
+            ]}]
+        }))
+        .unwrap();
+
+        let openai = get_provider(AIProvider::OpenAI).unwrap();
+        input.check_content(openai).unwrap();
+        let req = openai.build_request(&input, 100);
+        assert_eq!(
+            req["messages"][0]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,iVBOR"
+        );
+
+        input.model = Model::ClaudeSonnet4;
+        let req = get_provider(AIProvider::Anthropic)
+            .unwrap()
+            .build_request(&input, 100);
+        assert_eq!(
+            req["messages"][0]["content"][1]["source"]["media_type"],
+            "image/png"
+        );
+
+        input.model = Model::Gemini20Flash;
+        let gemini = get_provider(AIProvider::Gemini).unwrap();
+        let req = gemini.build_request(&input, 100);
+        assert_eq!(
+            req["contents"][0]["parts"][1]["inlineData"]["data"],
+            "iVBOR"
+        );
+
+        input.model = Model::DeepSeekV3;
+        let deepseek = get_provider(AIProvider::DeepSeek).unwrap();
+        let err = input.check_content(deepseek).unwrap_err();
+        assert!(err.contains("does not accept images"));
+
+        // Gemini can't fetch URLs
+        input.model = Model::Gemini20Flash;
+        input.messages[0].content = serde_json::from_value(json!([
+            {"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}}
+        ]))
+        .unwrap();
+        assert!(input.check_content(gemini).is_err());
+    }
+
+    #[test]
+    fn errors_map_to_statuses_and_codes() {
+        use axum::{http::StatusCode, response::IntoResponse};
+
+        let provider = get_provider(AIProvider::OpenAI).unwrap();
+        let err: OneLlmError = provider
+            .map_error(400, r#"{"error": {"message": "bad schema"}}"#)
+            .into();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.code(), "provider_error");
+
+        // The provider refusing our own key is not the caller's fault
+        let err: OneLlmError = provider.map_error(401, "nope").into();
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+
+        let res = OneLlmError::InsufficientBalance.into_response();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+        let limited = OneLlmError::RateLimited(RateLimit {
+            limits: Config::default().limits.plan("free"),
+            requests: 40,
+            tokens: 1_000,
+            reset: 12,
+            exceeded: Some(Limit::Requests),
+        });
+        assert_eq!(limited.code(), "rate_limit_exceeded");
+        let res = limited.into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "12");
+        assert_eq!(res.headers()["x-ratelimit-remaining-requests"], "0");
+        assert_eq!(res.headers()["x-ratelimit-remaining-tokens"], "199000");
+        assert_eq!(
+            OneLlmError::Forbidden("no".to_string()).status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(OneLlmError::BudgetExceeded.code(), "budget_exceeded");
+
+        // Not reaching the provider is a gateway failure too
+        let unreachable = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert_eq!(
+            OneLlmError::from(unreachable).status(),
+            StatusCode::BAD_GATEWAY
+        );
+        // Database errors don't leak into the response
+        let err = OneLlmError::from(sqlx::Error::RowNotFound);
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.to_string(), "Database error, please try again later.");
+    }
+
+    #[test]
+    fn config_reports_every_missing_value() {
+        let err = Config::default().validate().unwrap_err().to_string();
+        assert!(err.contains("database.postgres is not set (or set POSTGRES)"));
+        assert!(err.contains("auth.jwt_secret"));
+        assert!(err.contains("providers.claude.key is not set (or set CLAUDE)"));
+
+        let mut config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        config.auth.jwt_secret = "secret".to_string();
+        for provider in config.providers.values_mut() {
+            provider.key = "key".to_string();
+        }
+        config.validate().unwrap();
+
+        assert_eq!(config.limits.max_api_keys, 10);
+        assert_eq!(config.smtp.password, None);
+        assert_eq!(config.auth.totp_key, None);
+        config.auth.totp_key = Some("abcd".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("auth.totp_key must be 64 hex characters"));
+        config.auth.totp_key = None;
+        config.mail.transport = "pigeon".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("mail.transport must be smtp, file or stdout"));
+        config.mail.transport = "smtp".to_string();
+        assert_eq!(config.limits.plan("pro").requests_per_minute, 600);
+        // Plans that aren't configured get the free limits
+        assert_eq!(config.limits.plan("gold"), config.limits.plan("free"));
+        config.limits.plans.remove("free");
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("limits.plans.free is not set"));
+        config.limits = Default::default();
+        assert_eq!(
+            config
+                .provider(AIProvider::Anthropic)
+                .unwrap()
+                .base_url
+                .as_deref(),
+            Some("https://api.anthropic.com/v1")
+        );
+    }
+
+    #[test]
+    fn provider_urls_follow_the_configured_base_url() {
+        let mut config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        config.auth.jwt_secret = "secret".to_string();
+        for provider in config.providers.values_mut() {
+            provider.key = "key".to_string();
+        }
+        config.providers.get_mut("gemini").unwrap().base_url =
+            Some("http://localhost:9999/gemini//".to_string());
+        config.providers.get_mut("openai").unwrap().base_url = None;
+        config.validate().unwrap();
+
+        let gpt = Model::from_name("GPT-4o").unwrap();
+        assert_eq!(
+            provider_url(&config, &gpt, false).unwrap(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+
+        let flash = Model::from_name("2.0-Flash").unwrap();
+        assert_eq!(
+            provider_url(&config, &flash, false).unwrap(),
+            format!(
+                "http://localhost:9999/gemini/models/{}:generateContent",
+                flash.name()
+            )
+        );
+        assert_eq!(
+            provider_url(&config, &flash, true).unwrap(),
+            format!(
+                "http://localhost:9999/gemini/models/{}:streamGenerateContent?alt=sse",
+                flash.name()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn app_state_clones_share_connections() {
+        let state = test_state().await;
+        let max = state.pool.options().get_max_connections();
+
+        // Far more handlers than the pool has connections, each with its own clone
+        let handlers = (0..max * 4).map(|i| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                sqlx::query("SELECT pg_sleep(0.05)")
+                    .execute(&state.pool)
+                    .await
+                    .unwrap();
+                let mut redis = state.redis.clone();
+                let _: () = redis
+                    .set_ex(format!("test:state:{}", i), i, 10)
+                    .await
+                    .unwrap();
+                state
+            })
+        });
+        for handler in futures_util::future::join_all(handlers).await {
+            let clone = handler.unwrap();
+            assert!(Arc::ptr_eq(&clone.config, &state.config));
+        }
+
+        assert!(state.pool.size() <= max);
+    }
+
+    #[tokio::test]
+    async fn ledger_applies_transactions_once() {
+        let (pool, user) = test_user("ledger@email.com").await;
+        let email = user.email.clone();
+
+        let topup = Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: 5_000_000_000,
+            kind: TransactionKind::Topup,
+            request_id: Some("cs_test_ledger"),
+        };
+        assert_eq!(topup.record(&pool).await.unwrap(), Some(5_000_000_000));
+        // A replayed webhook changes nothing
+        assert_eq!(topup.record(&pool).await.unwrap(), None);
+
+        let charge = Transaction {
+            amount: -1_250,
+            kind: TransactionKind::Charge,
+            request_id: None,
+            ..topup
+        };
+        assert_eq!(charge.record(&pool).await.unwrap(), Some(4_999_998_750));
+
+        assert_eq!(user.personal_balance(&pool).await.unwrap(), 4_999_998_750);
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn holds_reserve_and_settle_balance() {
+        let (pool, user) = test_user("holds@email.com").await;
+        let email = user.email.clone();
+        Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: 10_000,
+            kind: TransactionKind::Topup,
+            request_id: None,
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+
+        let first = Hold {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            request_id: "req_test_hold_1".to_string(),
+            amount: 6_000,
+        };
+        let second = Hold {
+            request_id: "req_test_hold_2".to_string(),
+            ..first.clone()
+        };
+        let limits = KeyLimits::default();
+        assert_eq!(
+            first.place(&pool, &limits).await.unwrap(),
+            Placement::Placed
+        );
+        // Only 4_000 is left unheld, a parallel request can't take the same balance
+        assert_eq!(
+            second.place(&pool, &limits).await.unwrap(),
+            Placement::InsufficientBalance
+        );
+
+        first.release(&pool).await.unwrap();
+        assert_eq!(
+            second.place(&pool, &limits).await.unwrap(),
+            Placement::Placed
+        );
+        assert_eq!(second.settle(&pool, 2_500).await.unwrap(), Some(7_500));
+
+        let held: i64 = sqlx::query_scalar("SELECT held FROM organizations WHERE id = $1")
+            .bind(user.personal_organization_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(held, 0);
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_without_usage_release_the_hold() {
+        let redis = test_redis().await;
+        let (pool, user) = test_user("streamhold@email.com").await;
+        Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: 10_000,
+            kind: TransactionKind::Topup,
+            request_id: None,
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+
+        let content = r#"data: {"id":"c1","model":"gpt-4o","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":"stop"}]}"#;
+        let usage = r#"data: {"id":"c1","model":"gpt-4o","choices":[],"usage":{"prompt_tokens":2,"completion_tokens":1,"total_tokens":3}}"#;
+        let limits = Config::default().limits.plan("free");
+        // Negative ids never belong to a real key
+        let key_id = -(std::process::id() as i32) - (2 << 22);
+
+        let run = async |id: &str, events: Vec<&str>| {
+            let hold = Hold {
+                organization_id: user.personal_organization_id,
+                user_id: user.id,
+                api_key_id: None,
+                request_id: format!("req_test_stream_{}", id),
+                amount: 6_000,
+            };
+            assert_eq!(
+                hold.place(&pool, &KeyLimits::default()).await.unwrap(),
+                Placement::Placed
+            );
+            let body: Vec<Result<String, std::io::Error>> = events
+                .into_iter()
+                .map(|event| Ok(format!("{}\n\n", event)))
+                .collect();
+            let response = http::Response::builder()
+                .header("content-type", "text/event-stream")
+                .body(reqwest::Body::wrap_stream(futures_util::stream::iter(body)))
+                .unwrap();
+            let permit = ratelimit::acquire(&redis, key_id, &Model::Gpt4o, limits)
+                .await
+                .unwrap();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+
+            drive_stream(
+                response.into(),
+                get_provider(AIProvider::OpenAI).unwrap(),
+                Model::Gpt4o,
+                pool.clone(),
+                hold.clone(),
+                permit,
+                UsageEvent::start(&hold, &Model::Gpt4o),
+                tx,
+            )
+            .await;
+            while rx.recv().await.is_some() {}
+            let held: i64 = sqlx::query_scalar("SELECT held FROM organizations WHERE id = $1")
+                .bind(user.personal_organization_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(held, 0);
+            let (status, cost): (i16, i64) =
+                sqlx::query_as("SELECT status, cost FROM usage_events WHERE request_id = $1")
+                    .bind(&hold.request_id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            (user.personal_balance(&pool).await.unwrap(), status, cost)
+        };
+
+        assert_eq!(
+            run("reported", vec![content, usage, "data: [DONE]"]).await,
+            (8_440, 200, 1_560)
+        );
+        // Neither a stream that ends without a report nor one the provider breaks off with an
+        // error is charged
+        assert_eq!(
+            run("unreported", vec![content, "data: [DONE]"]).await,
+            (8_440, 200, 0)
+        );
+        assert_eq!(
+            run(
+                "failed",
+                vec![content, r#"data: {"error":{"message":"overloaded"}}"#]
+            )
+            .await,
+            (8_440, 502, 0)
+        );
+
+        User::delete_user(&pool, &user.email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn usage_events_aggregate_per_model() {
+        let (pool, user) = test_user("usage@email.com").await;
+        let email = user.email.clone();
+
+        let hold = |id: &str| Hold {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            request_id: format!("req_test_usage_{}", id),
+            amount: 0,
+        };
+        for (id, model, cost) in [
+            ("1", Model::Gpt4oMini, 100),
+            ("2", Model::Gpt4oMini, 50),
+            ("3", Model::ClaudeHaiku3, 70),
+        ] {
+            let mut event = UsageEvent::start(&hold(id), &model);
+            event.input_tokens = 10;
+            event.cost = cost;
+            event.record(&pool).await.unwrap();
+        }
+        let mut failed = UsageEvent::start(&hold("4"), &Model::ClaudeHaiku3);
+        failed.failed(&OneLlmError::Provider {
+            status: 529,
+            message: "Overloaded".to_string(),
+        });
+        failed.record(&pool).await.unwrap();
+
+        let today = chrono::Utc::now().date_naive();
+        let rows = usage::aggregate(&pool, user.id, &[UsageGroup::Model], Some(today), None)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].model.as_deref(), Some("GPT-4o-mini"));
+        assert_eq!(
+            (rows[0].requests, rows[0].input_tokens, rows[0].cost),
+            (2, 20, 150)
+        );
+        assert_eq!(rows[0].day, None);
+        assert_eq!((rows[1].requests, rows[1].errors, rows[1].cost), (2, 1, 70));
+
+        let csv = usage::to_csv(&rows);
+        assert_eq!(csv.lines().nth(1), Some(",GPT-4o-mini,,2,0,20,0,150"));
+
+        let yesterday = today.pred_opt().unwrap();
+        let rows = usage::aggregate(&pool, user.id, &[], None, Some(yesterday))
+            .await
+            .unwrap();
+        assert_eq!(rows[0].requests, 0);
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn key_limits_cap_spending() {
+        let (pool, user) = test_user("keylimits@email.com").await;
+        let email = user.email.clone();
+        User::verify_user(&pool, &email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+            .await
+            .unwrap();
+
+        let limits = KeyLimits {
+            daily_limit: Some(1_000),
+            monthly_limit: Some(500),
+            allowed_models: Some(vec![Model::Gpt4oMini]),
+        };
+        user.set_key_limits(&pool, "ci", &limits).await.unwrap();
+        assert!(
+            user.set_key_limits(&pool, "missing", &limits)
+                .await
+                .is_err()
+        );
+
+        let (_, key) = User::get_row_api(&pool, apikey).await.unwrap();
+        assert_eq!(key.limits, limits);
+
+        Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: Some(key.id),
+            amount: -200,
+            kind: TransactionKind::Charge,
+            request_id: Some("req_test_limits"),
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+        // The organization has no balance for `Hold::place`, so the hold row is inserted directly
+        sqlx::query(
+            "INSERT INTO balance_holds (request_id, organization_id, user_id, api_key_id, amount) \
+             VALUES ('req_test_limits_held', $1, $2, $3, 100)",
+        )
+        .bind(user.personal_organization_id)
+        .bind(user.id)
+        .bind(key.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The monthly cap is the tighter one, in-flight requests count against it
+        assert_eq!(
+            usage::key_budget_left(&pool, &key).await.unwrap(),
+            Some(200)
+        );
+        let budget = user.get_key_budget(&pool, "ci").await.unwrap();
+        assert_eq!((budget.spent_today, budget.spent_this_month), (300, 300));
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn holds_outlive_their_key() {
+        let (pool, user) = test_user("deletedkey@email.com").await;
+        let email = user.email.clone();
+        User::verify_user(&pool, &email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "gone", 10)
+            .await
+            .unwrap();
+        let (_, key) = User::get_row_api(&pool, apikey).await.unwrap();
+        Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: 10_000,
+            kind: TransactionKind::Topup,
+            request_id: None,
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+
+        let hold = Hold {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: Some(key.id),
+            request_id: "req_test_deleted_key".to_string(),
+            amount: 6_000,
+        };
+        assert_eq!(
+            hold.place(&pool, &key.limits).await.unwrap(),
+            Placement::Placed
+        );
+        // The key is deleted while its request is still running
+        sqlx::query("DELETE FROM api_keys WHERE id = $1")
+            .bind(key.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        hold.release(&pool).await.unwrap();
+
+        let held: i64 = sqlx::query_scalar("SELECT held FROM organizations WHERE id = $1")
+            .bind(user.personal_organization_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(held, 0);
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_holds_stay_within_key_budget() {
+        let (pool, user) = test_user("budgetrace@email.com").await;
+        let email = user.email.clone();
+        User::verify_user(&pool, &email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "race", 10)
+            .await
+            .unwrap();
+        let limits = KeyLimits {
+            monthly_limit: Some(1_000),
+            ..KeyLimits::default()
+        };
+        user.set_key_limits(&pool, "race", &limits).await.unwrap();
+        let (_, key) = User::get_row_api(&pool, apikey).await.unwrap();
+        Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: 10_000,
+            kind: TransactionKind::Topup,
+            request_id: None,
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+
+        // Every request saw the whole budget left before placing its hold
+        let holds: Vec<Hold> = (0..8)
+            .map(|i| Hold {
+                organization_id: user.personal_organization_id,
+                user_id: user.id,
+                api_key_id: Some(key.id),
+                request_id: format!("req_test_budget_race_{}", i),
+                amount: 300,
+            })
+            .collect();
+        let placements =
+            futures_util::future::join_all(holds.iter().map(|hold| hold.place(&pool, &key.limits)))
+                .await;
+
+        let placed = placements
+            .iter()
+            .filter(|p| *p.as_ref().unwrap() == Placement::Placed)
+            .count();
+        assert_eq!(placed, 3);
+        assert!(
+            placements
+                .iter()
+                .all(|p| matches!(p, Ok(Placement::Placed | Placement::BudgetExceeded)))
+        );
+        assert_eq!(
+            usage::key_budget_left(&pool, &key).await.unwrap(),
+            Some(100)
+        );
+
+        for hold in &holds {
+            hold.release(&pool).await.unwrap();
+        }
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn api_keys_are_stored_hashed() {
+        let (pool, user) = test_user("hashedkeys@email.com").await;
+        let email = user.email.clone();
+        User::verify_user(&pool, &email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "hashed", 10)
+            .await
+            .unwrap();
+
+        let public_id = api_public_id(&apikey).unwrap();
+        assert!(apikey.starts_with(&format!("oa-live-{}-", public_id)));
+        assert_eq!(apikey.len(), "oa-live-".len() + 12 + 1 + 32);
+        assert_eq!(api_public_id("oa-12345678912345678912"), None);
+
+        let (stored_id, stored_hash): (String, String) =
+            sqlx::query_as("SELECT public_id, key_hash FROM api_keys WHERE user_id = $1")
+                .bind(user.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(stored_id, public_id);
+        assert_eq!(stored_hash, hash_api(&apikey));
+
+        assert!(User::get_row_api(&pool, apikey.clone()).await.is_ok());
+        // Right identifier, wrong secret
+        let last = if apikey.ends_with('x') { 'y' } else { 'x' };
+        let forged = format!("{}{}", &apikey[..apikey.len() - 1], last);
+        assert!(User::get_row_api(&pool, forged).await.is_err());
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[test]
+    fn key_scopes_parse_and_restrict() {
+        let scopes: Vec<Scope> =
+            serde_json::from_value(json!(["chat", "provider:openai", "usage:read"])).unwrap();
+        assert_eq!(scopes[1], Scope::Provider(AIProvider::OpenAI));
+        assert_eq!(json!(scopes[1]), json!("provider:openai"));
+        assert!(serde_json::from_value::<Scope>(json!("admin")).is_err());
+
+        let key = |scopes: Option<Vec<Scope>>| ApiKey {
+            id: 0,
+            organization_id: 0,
+            balance: 0,
+            limits: KeyLimits::default(),
+            access: KeyAccess {
+                scopes,
+                expires_at: None,
+            },
+            plan: "free".to_string(),
+        };
+        assert!(key(None).allows(Scope::UsageRead));
+
+        let restricted = key(Some(scopes[..2].to_vec()));
+        assert!(restricted.allows(Scope::Chat));
+        assert!(!restricted.allows(Scope::UsageRead));
+        assert!(!restricted.allows(Scope::Provider(AIProvider::Anthropic)));
+
+        // Without provider scopes every provider is allowed
+        assert!(key(Some(vec![Scope::Chat])).allows(Scope::Provider(AIProvider::Anthropic)));
+    }
+
+    #[tokio::test]
+    async fn rotated_and_expired_keys() {
+        let (pool, user) = test_user("rotation@email.com").await;
+        let email = user.email.clone();
+        User::verify_user(&pool, &email).await.unwrap();
+        let old = user
+            .generate_apikey(&pool, user.personal_organization_id, "rotating", 10)
+            .await
+            .unwrap();
+
+        let new = user.rotate_apikey(&pool, "rotating", 3600).await.unwrap();
+        let (_, old_key) = User::get_row_api(&pool, old.clone()).await.unwrap();
+        let (_, new_key) = User::get_row_api(&pool, new.clone()).await.unwrap();
+        assert_eq!(old_key.id, new_key.id);
+
+        // Rotating again ends the grace period of the first key
+        let newest = user.rotate_apikey(&pool, "rotating", 0).await.unwrap();
+        assert!(User::get_row_api(&pool, old).await.is_err());
+        assert!(User::get_row_api(&pool, new).await.is_err());
+
+        let access = KeyAccess {
+            scopes: Some(vec![Scope::UsageRead]),
+            expires_at: Some(chrono::Utc::now() - chrono::Duration::minutes(1)),
+        };
+        user.set_key_access(&pool, "rotating", &access)
+            .await
+            .unwrap();
+        let err = User::get_row_api(&pool, newest).await.unwrap_err();
+        assert_eq!(err.to_string(), "This API key has expired");
+
+        let keys = user.list_apikeys(&pool).await.unwrap();
+        assert_eq!(keys[0].access.scopes, access.scopes);
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn organizations_share_balance_and_keys() {
+        let (pool, owner) = test_user("orgowner@email.com").await;
+        let (_, member) = test_user("orgmember@email.com").await;
+        for user in [&owner, &member] {
+            User::verify_user(&pool, &user.email).await.unwrap();
+        }
+
+        let org = organization::create(&pool, "Team", owner.id).await.unwrap();
+        // Personal organizations stay personal
+        assert!(
+            organization::invite(
+                &pool,
+                owner.personal_organization_id,
+                owner.id,
+                &member.email,
+                Role::Member
+            )
+            .await
+            .is_err()
+        );
+        assert!(
+            organization::invite(&pool, org, owner.id, &member.email, Role::Owner)
+                .await
+                .is_err()
+        );
+        organization::invite(&pool, org, owner.id, &member.email, Role::Member)
+            .await
+            .unwrap();
+        let orgs = organization::memberships(&pool, member.id, &member.email)
+            .await
+            .unwrap();
+        assert!(orgs[0].personal);
+        assert!(orgs[1].pending);
+        organization::accept_invite(&pool, org, member.id, &member.email)
+            .await
+            .unwrap();
+
+        Transaction {
+            organization_id: org,
+            user_id: owner.id,
+            api_key_id: None,
+            amount: 10_000,
+            kind: TransactionKind::Topup,
+            request_id: None,
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+        // Members can't manage the team, but their keys spend its balance
+        assert!(
+            organization::invite(&pool, org, member.id, "other@email.com", Role::Member)
+                .await
+                .is_err()
+        );
+        let apikey = member
+            .generate_apikey(&pool, org, "team", 10)
+            .await
+            .unwrap();
+        let (_, key) = User::get_row_api(&pool, apikey.clone()).await.unwrap();
+        assert_eq!((key.organization_id, key.balance), (org, 10_000));
+
+        assert!(
+            organization::remove_member(&pool, org, owner.id, &owner.email)
+                .await
+                .is_err()
+        );
+        organization::remove_member(&pool, org, owner.id, &member.email)
+            .await
+            .unwrap();
+        assert!(User::get_row_api(&pool, apikey).await.is_err());
+        let members = organization::members(&pool, org).await.unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].role, Role::Owner);
+
+        for user in [&owner, &member] {
+            User::delete_user(&pool, &user.email).await.unwrap();
+        }
+        sqlx::query("DELETE FROM organizations WHERE id = $1")
+            .bind(org)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn checkouts_credit_only_organizations_of_the_payer() {
+        use axum::extract::{FromRequest, State};
+        use hmac::{Hmac, Mac};
+
+        let mut state = test_state().await;
+        let (pool, payer) = test_user("payer@email.com").await;
+        let (_, other) = test_user("notpayer@email.com").await;
+        let mut config = (*state.config).clone();
+        config.stripe.webhook_secret = Some("whsec_test".to_string());
+        state.config = Arc::new(config);
+        let team = organization::create(&pool, "Paying team", payer.id)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM balance_transactions WHERE request_id LIKE 'cs_test_webhook_%'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Posts a completed $50 checkout paid by `email`, signed the way Stripe signs it
+        let checkout = |session: &str, email: &str, reference: i32| {
+            let now = chrono::Utc::now().timestamp();
+            let payload = json!({
+                "id": "evt_test_webhook",
+                "object": "event",
+                "api_version": "2023-10-16",
+                "created": now,
+                "livemode": false,
+                "pending_webhooks": 1,
+                "type": "checkout.session.completed",
+                "data": {"object": {
+                    "id": session,
+                    "object": "checkout.session",
+                    "amount_total": 5_000,
+                    "automatic_tax": {"enabled": false},
+                    "client_reference_id": reference.to_string(),
+                    "created": now,
+                    "custom_fields": [],
+                    "custom_text": {},
+                    "customer_details": {"email": email},
+                    "expires_at": now,
+                    "livemode": false,
+                    "mode": "payment",
+                    "payment_method_types": ["card"],
+                    "payment_status": "paid",
+                    "shipping_options": []
+                }}
+            })
+            .to_string();
+            let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"whsec_test").unwrap();
+            mac.update(format!("{}.{}", now, payload).as_bytes());
+            let signature = format!("t={},v1={}", now, hex::encode(mac.finalize().into_bytes()));
+            let request = axum::http::Request::post("/webhook")
+                .header("stripe-signature", signature)
+                .body(axum::body::Body::from(payload))
+                .unwrap();
+
+            let state = state.clone();
+            async move {
+                let event = StripeEvent::from_request(request, &state).await.unwrap();
+                payment::handle_webhook(State(state), event).await
+            }
+        };
+        let balance = |organization_id: i32| {
+            let pool = &pool;
+            async move {
+                sqlx::query_scalar::<_, i64>("SELECT balance FROM organizations WHERE id = $1")
+                    .bind(organization_id)
+                    .fetch_one(pool)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Acknowledged so Stripe doesn't retry it, but nobody is credited
+        checkout("cs_test_webhook_unknown", "nobody@email.com", team)
+            .await
+            .unwrap();
+        assert_eq!(balance(team).await, 0);
+        let recorded: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM balance_transactions \
+             WHERE request_id = 'cs_test_webhook_unknown')",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(!recorded);
+
+        checkout("cs_test_webhook_team", &payer.email, team)
+            .await
+            .unwrap();
+        assert_eq!(balance(team).await, 50_000_000);
+
+        // The payer isn't a member of the other user's organization, their own is credited
+        checkout(
+            "cs_test_webhook_other",
+            &payer.email,
+            other.personal_organization_id,
+        )
+        .await
+        .unwrap();
+        assert_eq!(balance(other.personal_organization_id).await, 0);
+        assert_eq!(balance(payer.personal_organization_id).await, 50_000_000);
+
+        for user in [&payer, &other] {
+            User::delete_user(&pool, &user.email).await.unwrap();
+        }
+        sqlx::query("DELETE FROM organizations WHERE id = $1")
+            .bind(team)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn password_reset_is_single_use() {
+        let (pool, user) = test_user("reset@email.com").await;
+        let mut redis = test_redis().await;
+        let email = user.email.clone();
+        let session = session::create(&pool, "secret", user.id, &Client::default())
+            .await
+            .unwrap()
+            .token;
+
+        assert_eq!(
+            User::create_password_reset(&pool, "nobody@email.com")
+                .await
+                .unwrap(),
+            None
+        );
+        let first = User::create_password_reset(&pool, &email)
+            .await
+            .unwrap()
+            .unwrap();
+        let token = User::create_password_reset(&pool, &email)
+            .await
+            .unwrap()
+            .unwrap();
+        // Asking again invalidates the earlier link
+        assert!(
+            User::reset_password(&pool, &first, "newPass99")
+                .await
+                .is_err()
+        );
+
+        User::reset_password(&pool, &token, "newPass99")
+            .await
+            .unwrap();
+        assert!(User::reset_password(&pool, &token, "other").await.is_err());
+        assert!(
+            login(
+                &pool,
+                &mut redis,
+                email.clone(),
+                "wedFF1234".to_string(),
+                None,
+                None
+            )
+            .await
+            .is_err()
+        );
+        assert!(
+            login(
+                &pool,
+                &mut redis,
+                email.clone(),
+                "newPass99".to_string(),
+                None,
+                None
+            )
+            .await
+            .is_ok()
+        );
+        assert!(User::from_token(&pool, session).await.is_err());
+
+        let expired = User::create_password_reset(&pool, &email)
+            .await
+            .unwrap()
+            .unwrap();
+        sqlx::query("UPDATE password_resets SET expires_at = NOW() WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(
+            User::reset_password(&pool, &expired, "other")
+                .await
+                .is_err()
+        );
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_refresh_and_revoke() {
+        let (pool, user) = test_user("sessions@email.com").await;
+        let email = user.email.clone();
+        User::verify_user(&pool, &email).await.unwrap();
+        user.generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+            .await
+            .unwrap();
+
+        let laptop = Client {
+            user_agent: Some("laptop".to_string()),
+            ip: Some("127.0.0.1".to_string()),
+        };
+        let first = session::create(&pool, "secret", user.id, &laptop)
+            .await
+            .unwrap();
+        let second = session::create(&pool, "secret", user.id, &Client::default())
+            .await
+            .unwrap();
+        assert_ne!(first.token, second.token);
+
+        let sessions = session::list(&pool, user.id, &second.token).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        let current: Vec<_> = sessions.iter().filter(|s| s.current).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].user_agent, None);
+
+        let refreshed = session::refresh(&pool, "secret", &first.refresh_token, &Client::default())
+            .await
+            .unwrap();
+        assert!(User::from_token(&pool, first.token.clone()).await.is_err());
+        assert!(
+            User::from_token(&pool, refreshed.token.clone())
+                .await
+                .is_ok()
+        );
+        // Presenting a replaced refresh token again ends the session
+        let err = session::refresh(&pool, "secret", &first.refresh_token, &laptop)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already used"));
+        assert!(User::from_token(&pool, refreshed.token).await.is_err());
+
+        session::revoke_token(&pool, &second.token).await.unwrap();
+        // The signature is still valid, the session isn't
+        assert!(
+            User::delete_apikey(&pool, "secret", &second.token, Some("ci"), false)
+                .await
+                .is_err()
+        );
+
+        let third = session::create(&pool, "secret", user.id, &laptop)
+            .await
+            .unwrap();
+        User::delete_apikey(&pool, "secret", &third.token, Some("ci"), false)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE sessions SET refresh_expires_at = NOW() WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(session::sweep_expired(&pool).await.unwrap() >= 1);
+        assert_eq!(session::revoke_all(&pool, user.id).await.unwrap(), 0);
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn totp_login_and_recovery_codes() {
+        let (pool, user) = test_user("totp@email.com").await;
+        let mut redis = test_redis().await;
+        let email = user.email.clone();
+        let key = Some("00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff");
+        let attempts_key = format!("totp:attempts:{}", user.id);
+        let _: () = redis.del(&attempts_key).await.unwrap();
+
+        let login_redis = redis.clone();
+        let login = |code: Option<String>| {
+            let (pool, email, mut redis) = (&pool, email.clone(), login_redis.clone());
+            async move {
+                let password = "wedFF1234".to_string();
+                login(pool, &mut redis, email, password, code.as_deref(), key).await
+            }
+        };
+
+        assert!(twofa::start_totp(&pool, None, &user).await.is_err());
+        let enrollment = twofa::start_totp(&pool, key, &user).await.unwrap();
+        assert!(enrollment.otpauth_url.starts_with("otpauth://totp/OneLLM:"));
+        assert!(!enrollment.qr_png.is_empty());
+        // Not required before the enrolment is confirmed
+        login(None).await.unwrap();
+
+        let app = totp_rs::TOTP::from_url(&enrollment.otpauth_url).unwrap();
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT totp_secret FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!stored.windows(app.secret.len()).any(|w| w == app.secret));
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        let code = app.generate(now);
+        let recovery = twofa::confirm_totp(&pool, key, &user, &code).await.unwrap();
+        assert_eq!(recovery.len(), 10);
+
+        let err = login(None).await.unwrap_err();
+        assert_eq!(err.to_string(), "A two-factor code is required");
+        let next = app.generate(now + 30);
+        login(Some(next.clone())).await.unwrap();
+        // Each code works once
+        assert!(login(Some(next)).await.is_err());
+
+        let recovery_code = recovery[0].to_uppercase();
+        login(Some(recovery_code.clone())).await.unwrap();
+        assert!(login(Some(recovery_code)).await.is_err());
+
+        // Guessing stops after five wrong codes, even a right one is refused then
+        for _ in 0..4 {
+            assert!(login(Some("000000".to_string())).await.is_err());
+        }
+        let err = login(Some(app.generate(now + 60))).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Too many wrong codes, please try again later."
+        );
+        let _: () = redis.del(&attempts_key).await.unwrap();
+
+        twofa::disable_totp(&pool, &mut redis, key, &user, &recovery[1])
+            .await
+            .unwrap();
+        login(None).await.unwrap();
+
+        User::delete_user(&pool, &email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn emails_render_and_spool_to_files() {
+        let html = mail::render("<p>{{ NAME }}, {{ YEAR }}</p>", &[("NAME", "<Ada & Co>")]);
+        let year = chrono::Utc::now().format("%Y").to_string();
+        assert_eq!(html, format!("<p>&lt;Ada &amp; Co&gt;, {}</p>", year));
+
+        let dir = std::env::temp_dir().join(format!("onellm-mail-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mailer = FileMailer {
+            from: "OneLLM <noreply@onellm.dev>".parse().unwrap(),
+            dir: dir.clone(),
+        };
+        twofa::send_password_reset(
+            &mailer,
+            "user@email.com",
+            "https://onellm.dev/r?token=a&b=1",
+        )
+        .await
+        .unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let eml = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(eml.contains("To: user@email.com"));
+        assert!(eml.contains("Subject: Reset your OneLLM password"));
+        assert!(!eml.contains("{{ "));
+
+        assert!(
+            twofa::send_password_reset(&mailer, "not an email", "x")
+                .await
+                .is_err()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn password_resets_have_a_cooldown_per_email_and_address() {
+        let mut redis = test_redis().await;
+        let emails = ["reset1@email.com", "reset2@email.com", "reset3@email.com"];
+        let ips: [std::net::IpAddr; 3] = [
+            "203.0.113.1".parse().unwrap(),
+            "203.0.113.2".parse().unwrap(),
+            "203.0.113.3".parse().unwrap(),
+        ];
+        let keys: Vec<String> = emails
+            .iter()
+            .map(|e| format!("reset:cooldown:email:{}", e))
+            .chain(ips.iter().map(|ip| format!("reset:cooldown:ip:{}", ip)))
+            .collect();
+        let _: () = redis.del(&keys).await.unwrap();
+
+        assert!(
+            twofa::reset_allowed(&mut redis, emails[0], ips[0])
+                .await
+                .unwrap()
+        );
+        // The same email from elsewhere, and another email from the same address
+        assert!(
+            !twofa::reset_allowed(&mut redis, "RESET1@email.com", ips[1])
+                .await
+                .unwrap()
+        );
+        assert!(
+            !twofa::reset_allowed(&mut redis, emails[1], ips[0])
+                .await
+                .unwrap()
+        );
+        assert!(
+            twofa::reset_allowed(&mut redis, emails[2], ips[2])
+                .await
+                .unwrap()
+        );
+
+        let _: () = redis.del(&keys).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn email_verification_codes_are_single_use_and_lock_out() {
+        let mut redis = test_redis().await;
+        let (pool, user) = test_user("verify@email.com").await;
+        let email = user.email.clone();
+        let dir = std::env::temp_dir().join(format!("onellm-verify-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mailer = FileMailer {
+            from: "OneLLM <noreply@onellm.dev>".parse().unwrap(),
+            dir: dir.clone(),
+        };
+
+        let keys = ["verify:code:", "verify:attempts:", "verify:cooldown:"]
+            .map(|k| k.to_string() + &email);
+        let _: () = redis.del(&keys).await.unwrap();
+        assert!(
+            user.generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+                .await
+                .is_err()
+        );
+
+        // The code of the latest email, the body is quoted-printable
+        let latest_code = || {
+            let mut files: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|f| f.unwrap().path())
+                .collect();
+            files.sort();
+            let eml = std::fs::read_to_string(files.last().unwrap()).unwrap();
+            let html = eml.replace("=\r\n", "").replace("=3D", "=");
+            let start = html.find("class=\"code\">").unwrap() + "class=\"code\">".len();
+            html[start..start + 6].to_string()
+        };
+        let wrong = |code: &str| if code == "123456" { "654321" } else { "123456" };
+
+        twofa::send_verify(&mut redis, &mailer, &email)
+            .await
+            .unwrap();
+        // Asking again right away is refused
+        assert!(
+            twofa::send_verify(&mut redis, &mailer, &email)
+                .await
+                .is_err()
+        );
+        let code = latest_code();
+
+        assert!(
+            !twofa::verify_code(&mut redis, &email, wrong(&code))
+                .await
+                .unwrap()
+        );
+        assert!(twofa::verify_code(&mut redis, &email, &code).await.unwrap());
+        // Used up
+        assert!(twofa::verify_code(&mut redis, &email, &code).await.is_err());
+
+        User::verify_user(&pool, &email).await.unwrap();
+        user.generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+            .await
+            .unwrap();
+
+        let _: () = redis.del(&keys[2]).await.unwrap();
+        twofa::send_verify(&mut redis, &mailer, &email)
+            .await
+            .unwrap();
+        let code = latest_code();
+        // Guessing at the used up code counted as the first attempt
+        for _ in 0..4 {
+            assert!(
+                !twofa::verify_code(&mut redis, &email, wrong(&code))
+                    .await
+                    .unwrap()
+            );
+        }
+        // Locked out, even with the right code
+        assert!(twofa::verify_code(&mut redis, &email, &code).await.is_err());
+
+        let _: () = redis.del(&keys).await.unwrap();
+        User::delete_user(&pool, &email).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limits_count_requests_tokens_and_concurrency() {
+        let redis = test_redis().await;
+        let mut limits = Config::default().limits.plan("free");
+        limits.requests_per_minute = 4;
+        limits.tokens_per_minute = 100;
+        limits.concurrent_per_model = 2;
+        // Negative ids never belong to a real key
+        let key_id = -(std::process::id() as i32);
+        let released = || tokio::time::sleep(std::time::Duration::from_millis(100));
+        // Every request is made at the start of one window, however long the test takes
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 60
+            * 60;
+
+        let first = ratelimit::acquire_at(&redis, key_id, &Model::Gpt4o, limits, now)
+            .await
+            .unwrap();
+        assert_eq!(first.status.requests, 1);
+        assert_eq!(
+            first.status.headers()["x-ratelimit-remaining-requests"],
+            "3"
+        );
+        let mut second = ratelimit::acquire_at(&redis, key_id, &Model::Gpt4o, limits, now)
+            .await
+            .unwrap();
+
+        // Both slots of the model are taken, other models have their own
+        let err = ratelimit::acquire_at(&redis, key_id, &Model::Gpt4o, limits, now)
+            .await
+            .err()
+            .unwrap();
+        let OneLlmError::RateLimited(status) = &err else {
+            panic!("{}", err)
+        };
+        assert_eq!(status.exceeded, Some(Limit::Concurrency));
+        assert_eq!(status.retry_after(), Some(1));
+        let other = ratelimit::acquire_at(&redis, key_id, &Model::DeepSeekR1, limits, now)
+            .await
+            .unwrap();
+        assert_eq!(other.status.requests, 3);
+        drop(other);
+
+        // Once the tokens of the window are used up nothing else gets through
+        second.used(150);
+        drop(second);
+        released().await;
+        let err = ratelimit::acquire_at(&redis, key_id, &Model::Gpt4o, limits, now)
+            .await
+            .err()
+            .unwrap();
+        let OneLlmError::RateLimited(status) = &err else {
+            panic!("{}", err)
+        };
+        assert_eq!(status.exceeded, Some(Limit::Tokens));
+        assert_eq!(status.retry_after(), Some(status.reset));
+        assert_eq!(err.headers()["x-ratelimit-remaining-tokens"], "0");
+        drop(first);
+
+        // Requests of another key, refused ones aren't counted
+        let other_key = key_id - (1 << 22);
+        limits.requests_per_minute = 1;
+        ratelimit::acquire_at(&redis, other_key, &Model::Gpt4o, limits, now)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let err = ratelimit::acquire_at(&redis, other_key, &Model::Gpt4o, limits, now)
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err,
+                OneLlmError::RateLimited(RateLimit {
+                    requests: 1,
+                    exceeded: Some(Limit::Requests),
+                    ..
+                })
+            ));
+        }
+        released().await;
+    }
+
+    #[tokio::test]
+    async fn low_balance_requests_are_refused_before_rate_limiting() {
+        let state = test_state().await;
+        let (pool, user) = test_user("lowbalance@email.com").await;
+        User::verify_user(&pool, &user.email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+            .await
+            .unwrap();
+        let (user, key) = User::get_row_api(&pool, apikey).await.unwrap();
+
+        let input = APIInput::new(Model::Gpt4o, vec![Message::new("user", "hi")], 100);
+        let err = input.get(&state, &user, &key).await.err().unwrap();
+        assert!(matches!(err, OneLlmError::InsufficientBalance));
+
+        let window = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 60;
+        let mut redis = state.redis.clone();
+        for window in [window - 1, window] {
+            let requests: Option<u32> = redis
+                .get(format!("ratelimit:{{{}}}:requests:{}", key.id, window))
+                .await
+                .unwrap();
+            assert_eq!(requests, None);
+        }
+
+        User::delete_user(&pool, &user.email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_requests_keep_neither_hold_nor_rate_limit() {
+        let state = test_state().await;
+        let (pool, user) = test_user("refusedhold@email.com").await;
+        User::verify_user(&pool, &user.email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+            .await
+            .unwrap();
+        Transaction {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            amount: 10_000_000,
+            kind: TransactionKind::Topup,
+            request_id: None,
+        }
+        .record(&pool)
+        .await
+        .unwrap();
+        let (user, key) = User::get_row_api(&pool, apikey).await.unwrap();
+        let held = || {
+            let pool = &pool;
+            async move {
+                sqlx::query_scalar::<_, i64>("SELECT held FROM organizations WHERE id = $1")
+                    .bind(user.personal_organization_id)
+                    .fetch_one(pool)
+                    .await
+                    .unwrap()
+            }
+        };
+        let input = APIInput::new(Model::Gpt4o, vec![Message::new("user", "hi")], 100);
+
+        // Another request holds nearly all of the balance after the key was loaded
+        let other = Hold {
+            organization_id: user.personal_organization_id,
+            user_id: user.id,
+            api_key_id: None,
+            request_id: "req_test_refused_hold".to_string(),
+            amount: 9_999_999,
+        };
+        assert_eq!(
+            other.place(&pool, &key.limits).await.unwrap(),
+            Placement::Placed
+        );
+        let err = input.get(&state, &user, &key).await.err().unwrap();
+        assert!(matches!(err, OneLlmError::InsufficientBalance));
+        let window = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 60;
+        let mut redis = state.redis.clone();
+        for window in [window - 1, window] {
+            let requests: Option<u32> = redis
+                .get(format!("ratelimit:{{{}}}:requests:{}", key.id, window))
+                .await
+                .unwrap();
+            assert_eq!(requests, None);
+        }
+        other.release(&pool).await.unwrap();
+
+        // Every concurrent slot of the model is taken
+        let limits = state.config.limits.plan(&key.plan);
+        let mut permits = Vec::new();
+        while let Ok(permit) = ratelimit::acquire(&state.redis, key.id, &Model::Gpt4o, limits).await
+        {
+            permits.push(permit);
+        }
+        let err = input.get(&state, &user, &key).await.err().unwrap();
+        assert!(matches!(err, OneLlmError::RateLimited(_)));
+        assert_eq!(held().await, 0);
+
+        drop(permits);
+        User::delete_user(&pool, &user.email).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_requests_are_recorded() {
+        let state = test_state().await;
+        let (pool, user) = test_user("refused@email.com").await;
+        User::verify_user(&pool, &user.email).await.unwrap();
+        let apikey = user
+            .generate_apikey(&pool, user.personal_organization_id, "ci", 10)
+            .await
+            .unwrap();
+        let (user, key) = User::get_row_api(&pool, apikey).await.unwrap();
+        let input = APIInput::new(Model::Gpt4o, vec![Message::new("user", "hi")], 100);
+
+        assert!(input.get(&state, &user, &key).await.is_err());
+        let (api_key_id, status, cost): (Option<i32>, i16, i64) =
+            sqlx::query_as("SELECT api_key_id, status, cost FROM usage_events WHERE user_id = $1")
+                .bind(user.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((api_key_id, status, cost), (Some(key.id), 402, 0));
+
+        User::delete_user(&pool, &user.email).await.unwrap();
+    }
 }
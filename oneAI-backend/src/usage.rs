@@ -0,0 +1,300 @@
+use std::{fmt::Write, time::Instant};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+
+use crate::{
+    compat::response_id,
+    error::OneLlmError,
+    ledger::Hold,
+    pricing::Model,
+    requests::responseparser::common::LlmUsage,
+    utils::{ApiKey, KeyLimits},
+};
+
+/// One API request as stored in `usage_events`, written once the request has finished or was
+/// refused.
+#[derive(Debug, Clone)]
+pub struct UsageEvent {
+    pub request_id: String,
+    pub user_id: i32,
+    pub api_key_id: Option<i32>,
+    pub model: Model,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    /// Micro-dollars charged for the request.
+    pub cost: i64,
+    pub finish_reason: Option<String>,
+    /// HTTP status the caller got.
+    pub status: u16,
+    pub error: Option<String>,
+    started: Instant,
+}
+
+impl UsageEvent {
+    /// Starts timing a request that was just sent to the provider.
+    pub fn start(hold: &Hold, model: &Model) -> Self {
+        Self::new(
+            hold.request_id.clone(),
+            hold.user_id,
+            hold.api_key_id,
+            model,
+        )
+    }
+
+    /// A request with a valid key refused with `error` before it reached the provider, e.g. by
+    /// the rate limits.
+    pub fn rejected(
+        user_id: i32,
+        api_key_id: Option<i32>,
+        model: &Model,
+        error: &OneLlmError,
+    ) -> Self {
+        let mut event = Self::new(response_id("req_"), user_id, api_key_id, model);
+        event.failed(error);
+        event
+    }
+
+    fn new(request_id: String, user_id: i32, api_key_id: Option<i32>, model: &Model) -> Self {
+        Self {
+            request_id,
+            user_id,
+            api_key_id,
+            model: model.clone(),
+            input_tokens: 0,
+            output_tokens: 0,
+            cost: 0,
+            finish_reason: None,
+            status: 200,
+            error: None,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn succeeded(&mut self, usage: Option<&LlmUsage>, cost: i64, finish_reason: Option<&str>) {
+        if let Some(usage) = usage {
+            self.input_tokens = usage.input_tokens.unwrap_or(0);
+            self.output_tokens = usage.output_tokens.unwrap_or(0);
+        }
+        self.cost = cost;
+        self.finish_reason = finish_reason.map(str::to_string);
+    }
+
+    pub fn failed(&mut self, error: &OneLlmError) {
+        self.status = error.status().as_u16();
+        self.error = Some(error.to_string());
+    }
+
+    pub async fn record(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO usage_events (request_id, user_id, api_key_id, model, provider, \
+             input_tokens, output_tokens, cost, latency_ms, finish_reason, status, error) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+        )
+        .bind(&self.request_id)
+        .bind(self.user_id)
+        .bind(self.api_key_id)
+        .bind(self.model.to_string())
+        .bind(self.model.provider().to_string())
+        .bind(self.input_tokens as i32)
+        .bind(self.output_tokens as i32)
+        .bind(self.cost)
+        .bind(self.started.elapsed().as_millis().min(i32::MAX as u128) as i32)
+        .bind(&self.finish_reason)
+        .bind(self.status as i16)
+        .bind(&self.error)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// What the key spent today and this month (UTC), counting requests still in flight at their
+/// held amount. Spend comes from the ledger, whose charges are written with the settled hold.
+pub async fn key_spend(
+    conn: impl PgExecutor<'_>,
+    api_key_id: i32,
+) -> Result<(i64, i64), sqlx::Error> {
+    sqlx::query_as(
+        "SELECT \
+         COALESCE(SUM(cost) FILTER (WHERE created_at >= \
+             date_trunc('day', NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'), 0)::BIGINT, \
+         COALESCE(SUM(cost), 0)::BIGINT \
+         FROM ( \
+             SELECT -amount AS cost, created_at FROM balance_transactions \
+             WHERE api_key_id = $1 AND kind = 'charge' \
+             AND created_at >= date_trunc('month', NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' \
+             UNION ALL \
+             SELECT amount, NOW() FROM balance_holds WHERE api_key_id = $1 \
+         ) spend",
+    )
+    .bind(api_key_id)
+    .fetch_one(conn)
+    .await
+}
+
+/// How much more the key may spend before reaching one of its caps, `None` if it has none.
+pub async fn key_budget_left(pool: &PgPool, key: &ApiKey) -> Result<Option<i64>, sqlx::Error> {
+    budget_left(pool, key.id, &key.limits).await
+}
+
+/// `key_budget_left` for the key `api_key_id` with caps `limits`.
+pub async fn budget_left(
+    conn: impl PgExecutor<'_>,
+    api_key_id: i32,
+    limits: &KeyLimits,
+) -> Result<Option<i64>, sqlx::Error> {
+    if limits.daily_limit.is_none() && limits.monthly_limit.is_none() {
+        return Ok(None);
+    }
+
+    let (today, month) = key_spend(conn, api_key_id).await?;
+    let left = [
+        limits.daily_limit.map(|l| l - today),
+        limits.monthly_limit.map(|l| l - month),
+    ];
+
+    Ok(left.into_iter().flatten().min())
+}
+
+/// Body of `/usage`. Without `group_by` the whole range is summed into one row.
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// Session token of the dashboard. Without it the request must carry an API key.
+    pub token: Option<String>,
+    #[serde(default)]
+    pub group_by: Vec<UsageGroup>,
+    /// First day included, in UTC.
+    pub from: Option<NaiveDate>,
+    /// Last day included, in UTC.
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub format: UsageFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageGroup {
+    Day,
+    Model,
+    Key,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Usage summed over one group. Fields that aren't grouped on are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UsageRow {
+    pub day: Option<NaiveDate>,
+    pub model: Option<String>,
+    /// Name of the key, `None` as well for keys that were deleted since.
+    pub api_key: Option<String>,
+    pub requests: i64,
+    pub errors: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    /// Micro-dollars.
+    pub cost: i64,
+}
+
+impl UsageGroup {
+    fn column(&self) -> &'static str {
+        match self {
+            UsageGroup::Day => "(e.created_at AT TIME ZONE 'UTC')::date",
+            UsageGroup::Model => "e.model",
+            UsageGroup::Key => "k.name",
+        }
+    }
+}
+
+/// Sums the user's usage between `from` and `to` (both optional and inclusive), grouped and
+/// ordered by `group_by`.
+pub async fn aggregate(
+    pool: &PgPool,
+    user_id: i32,
+    group_by: &[UsageGroup],
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<Vec<UsageRow>, sqlx::Error> {
+    let select = |group: UsageGroup, cast: &str| {
+        if group_by.contains(&group) {
+            group.column().to_string()
+        } else {
+            format!("NULL::{}", cast)
+        }
+    };
+    // Positions of the grouped columns in the select list below
+    let positions: Vec<String> = [UsageGroup::Day, UsageGroup::Model, UsageGroup::Key]
+        .iter()
+        .enumerate()
+        .filter(|(_, g)| group_by.contains(g))
+        .map(|(i, _)| (i + 1).to_string())
+        .collect();
+    let grouping = if positions.is_empty() {
+        String::new()
+    } else {
+        format!("GROUP BY {0} ORDER BY {0}", positions.join(", "))
+    };
+
+    let sql = format!(
+        "SELECT {} AS day, {} AS model, {} AS api_key, \
+         COUNT(*) AS requests, \
+         COUNT(*) FILTER (WHERE e.error IS NOT NULL) AS errors, \
+         COALESCE(SUM(e.input_tokens), 0)::BIGINT AS input_tokens, \
+         COALESCE(SUM(e.output_tokens), 0)::BIGINT AS output_tokens, \
+         COALESCE(SUM(e.cost), 0)::BIGINT AS cost \
+         FROM usage_events e LEFT JOIN api_keys k ON k.id = e.api_key_id \
+         WHERE e.user_id = $1 \
+         AND ($2::date IS NULL OR e.created_at >= $2::date AT TIME ZONE 'UTC') \
+         AND ($3::date IS NULL OR e.created_at < ($3::date + 1) AT TIME ZONE 'UTC') \
+         {}",
+        select(UsageGroup::Day, "date"),
+        select(UsageGroup::Model, "varchar"),
+        select(UsageGroup::Key, "varchar"),
+        grouping,
+    );
+
+    sqlx::query_as(&sql)
+        .bind(user_id)
+        .bind(from)
+        .bind(to)
+        .fetch_all(pool)
+        .await
+}
+
+/// Renders `rows` as CSV with a header line. Columns that weren't grouped on are left empty.
+pub fn to_csv(rows: &[UsageRow]) -> String {
+    let mut csv =
+        String::from("day,model,api_key,requests,errors,input_tokens,output_tokens,cost\n");
+    for row in rows {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{}",
+            row.day.map(|d| d.to_string()).unwrap_or_default(),
+            csv_field(row.model.as_deref().unwrap_or_default()),
+            csv_field(row.api_key.as_deref().unwrap_or_default()),
+            row.requests,
+            row.errors,
+            row.input_tokens,
+            row.output_tokens,
+            row.cost,
+        );
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -1,19 +1,63 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    auth::twofa::TotpEnrollment,
+    organization::{Member, Membership, Role},
+    pricing::Model,
+    requests::requests::AIProvider,
+    session::{NewSession, SessionInfo},
+    usage::UsageRow,
+};
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct VerifyInput {
     pub email: String,
     pub code: Option<String>,
 }
 
+/// Body of `/forgot-password`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ForgotPasswordInput {
+    pub email: String,
+}
+
+/// Body of `/refresh`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RefreshInput {
+    pub refresh_token: String,
+}
+
+/// Body of `/logout`. With `everywhere` every session of the user ends, not just this one.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogoutInput {
+    pub token: String,
+    #[serde(default)]
+    pub everywhere: bool,
+}
+
+/// Body of `/reset-password`, `token` comes from the emailed link.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResetPasswordInput {
+    pub token: String,
+    pub password: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Claims {
     pub sub: i32, // user_id
     pub exp: usize,
     pub iat: usize,
+    /// Random, missing from tokens issued before sessions could be refreshed.
+    #[serde(default)]
+    pub jti: String,
 }
 
+#[allow(unused)]
 #[derive(Debug, Deserialize, Serialize)]
 pub enum VerificationOption {
     Token,
@@ -31,6 +75,23 @@ pub enum WebQuery {
     APICount,
     ChangePwd,
     VerifyToken,
+    SetKeyLimits,
+    GetKeyLimits,
+    SetKeyAccess,
+    RotateAPI,
+    ListAPI,
+    CreateOrg,
+    ListOrgs,
+    InviteMember,
+    AcceptInvite,
+    RemoveMember,
+    ListMembers,
+    ListSessions,
+    RevokeSession,
+    EnableTotp,
+    ConfirmTotp,
+    DisableTotp,
+    NewRecoveryCodes,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -39,6 +100,20 @@ pub struct TokenInput {
     pub token: String,
     pub email: Option<String>,
     pub name: Option<String>,
+    /// Replaces all limits of the key named `name` with `SetKeyLimits`.
+    pub limits: Option<KeyLimits>,
+    /// Replaces the scopes and expiry of the key named `name` with `SetKeyAccess`.
+    pub access: Option<KeyAccess>,
+    /// Seconds the old key keeps working after `RotateAPI`, a day by default.
+    pub grace_period: Option<u64>,
+    /// The organization a command is about, the user's personal one if missing.
+    pub organization_id: Option<i32>,
+    /// The role `InviteMember` invites `email` with.
+    pub role: Option<Role>,
+    /// The session `RevokeSession` ends, from `ListSessions`.
+    pub session_id: Option<i32>,
+    /// Authenticator or recovery code for the TOTP commands.
+    pub code: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -48,12 +123,16 @@ pub struct WebInput {
     pub password: String,
     pub name: Option<String>,
     pub token: Option<String>,
+    /// Authenticator or recovery code, for users with two-factor authentication.
+    pub code: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
 pub struct WebOutput {
     pub user: HiddenUser,
     pub token: String,
+    /// Only sent when logging in.
+    pub refresh_token: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -66,7 +145,6 @@ pub struct Output {
 pub enum TableFields {
     Email,
     Password,
-    Balance,
 }
 
 #[derive(PartialEq, Debug, Clone, Deserialize, Serialize)]
@@ -74,14 +152,80 @@ pub struct User {
     pub id: i32,
     pub email: String,
     pub password: String,
-    pub balance: u32,
     pub verified: bool,
+    /// Where the user's own balance and keys live.
+    pub personal_organization_id: i32,
+}
+
+/// Restrictions on a single API key, `None` fields don't restrict anything. Limits are in
+/// micro-dollars and apply per UTC day and calendar month.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct KeyLimits {
+    pub daily_limit: Option<i64>,
+    pub monthly_limit: Option<i64>,
+    pub allowed_models: Option<Vec<Model>>,
+}
+
+/// Something an API key can be allowed to do. Written as `chat`, `usage:read` or
+/// `provider:<name>`, e.g. `provider:openai`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Scope {
+    /// Completions through `/api` and the compatible endpoints.
+    Chat,
+    /// Reading the owner's usage from `/usage`.
+    UsageRead,
+    /// Completions from this provider. Keys with no provider scope may use all of them.
+    Provider(AIProvider),
+}
+
+/// Who may use an API key for what, and until when. `None` fields don't restrict anything.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct KeyAccess {
+    pub scopes: Option<Vec<Scope>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// An API key as loaded to authorize a request.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i32,
+    /// The organization whose balance the key spends.
+    pub organization_id: i32,
+    /// Balance of that organization when the key was loaded.
+    pub balance: i64,
+    pub limits: KeyLimits,
+    pub access: KeyAccess,
+    /// Plan of the key's owner, which picks its rate limits.
+    pub plan: String,
+}
+
+/// What the dashboard lists about a key. The secret itself is never shown again.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KeyInfo {
+    pub name: String,
+    /// `None` for keys issued before keys had identifiers.
+    pub public_id: Option<String>,
+    #[serde(flatten)]
+    pub access: KeyAccess,
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Until when the key replaced by the last rotation still works.
+    pub previous_expires_at: Option<DateTime<Utc>>,
+}
+
+/// A key's limits with what it spent so far, including requests still in flight.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KeyBudget {
+    #[serde(flatten)]
+    pub limits: KeyLimits,
+    pub spent_today: i64,
+    pub spent_this_month: i64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct HiddenUser {
     pub email: String,
-    pub balance: u32,
+    pub balance: i64,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -90,5 +234,59 @@ pub enum FailOrSucc {
     Successful(String),
     SuccessData(String),
     SuccessVecData(Vec<String>),
+    SuccessUsage(Vec<UsageRow>),
+    SuccessKeyBudget(KeyBudget),
+    SuccessKeys(Vec<KeyInfo>),
+    SuccessOrgs(Vec<Membership>),
+    SuccessMembers(Vec<Member>),
+    SuccessSessions(Vec<SessionInfo>),
+    SuccessSession(NewSession),
+    SuccessTotp(TotpEnrollment),
     User(WebOutput),
 }
+
+impl TryFrom<String> for Scope {
+    type Error = String;
+
+    fn try_from(scope: String) -> Result<Self, Self::Error> {
+        const PROVIDERS: [AIProvider; 5] = [
+            AIProvider::OpenAI,
+            AIProvider::Anthropic,
+            AIProvider::Gemini,
+            AIProvider::DeepSeek,
+            AIProvider::Mistral,
+        ];
+
+        match scope.as_str() {
+            "chat" => Ok(Scope::Chat),
+            "usage:read" => Ok(Scope::UsageRead),
+            _ => scope
+                .strip_prefix("provider:")
+                .and_then(|name| {
+                    PROVIDERS
+                        .into_iter()
+                        .find(|p| p.to_string().eq_ignore_ascii_case(name))
+                })
+                .map(Scope::Provider)
+                .ok_or_else(|| format!("Unknown scope '{}'", scope)),
+        }
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Chat => f.write_str("chat"),
+            Scope::UsageRead => f.write_str("usage:read"),
+            Scope::Provider(provider) => {
+                write!(f, "provider:{}", provider.to_string().to_lowercase())
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! The request body of `/api`.
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::{
+        Content, GenerationConfig, Message, ResponseFormat, SafetySetting, Tool, ToolChoice,
+    },
+    model::Model,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct APIInput {
+    /// Ignored, requests always go to the server's own endpoint for the model's provider.
+    /// Kept so older clients that still send it are accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    // Common fields
+    pub model: Model,
+    pub temperature: Option<f64>,
+    pub stream: Option<bool>,
+    pub messages: Vec<Message>,
+    pub max_tokens: u32,
+    pub top_p: f64,
+    pub stop_sequences: Option<Vec<String>>,
+    pub tools: Option<Vec<Tool>>,
+    /// `auto`, `none`, `required`, or `{"name": ...}` for the tool the model has to call.
+    #[serde(rename = "tool_choice")]
+    pub tool_choice: Option<ToolChoice>,
+
+    // Gemini
+    #[serde(rename = "contents")]
+    pub contents: Option<Vec<Content>>,
+    #[serde(rename = "safety_settings")]
+    pub safety_settings: Option<Vec<SafetySetting>>,
+    #[serde(rename = "generation_config")]
+    pub generation_config: Option<GenerationConfig>,
+
+    // OpenAI, DeepSeek
+    #[serde(rename = "frequency_penalty")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(rename = "presence_penalty")]
+    pub presence_penalty: Option<f64>,
+
+    // OpenAI
+    pub n: Option<u32>,
+    #[serde(rename = "response_format")]
+    pub response_format: Option<ResponseFormat>,
+    pub seed: Option<u32>,
+    pub user: Option<String>,
+
+    // DeepSeek
+    pub logprobs: Option<bool>,
+    #[serde(rename = "top_logprobs")]
+    pub top_logprobs: Option<u32>,
+
+    // Claude
+    pub system: Option<String>,
+    #[serde(rename = "top_k")]
+    pub top_k: Option<u32>,
+}
+
+impl APIInput {
+    pub fn new(model: Model, messages: Vec<Message>, max_tokens: u32) -> Self {
+        Self {
+            endpoint: None,
+            model,
+            messages,
+            max_tokens,
+            temperature: Some(1.0),
+            stream: Some(false),
+            top_p: 1.0,
+            stop_sequences: None,
+            tools: None,
+            contents: None,
+            safety_settings: None,
+            generation_config: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            n: None,
+            response_format: None,
+            seed: None,
+            tool_choice: None,
+            user: None,
+            logprobs: None,
+            top_logprobs: None,
+            system: None,
+            top_k: None,
+        }
+    }
+    //    pub fn temperature(&mut self, temp: f64) {
+    //        self.temperature = Some(temp);
+    //    }
+    //
+    //    pub fn stop_sequences(&mut self, stop_sequences: Vec<String>) {
+    //        self.stop_sequences = Some(stop_sequences);
+    //    }
+}
@@ -0,0 +1,19 @@
+//! Types making up the OneLLM wire format, shared by the server and the `onellm` client so the
+//! two always agree on it.
+pub mod input;
+pub mod message;
+pub mod model;
+pub mod response;
+pub mod sse;
+mod testing;
+
+pub use input::APIInput;
+pub use message::{
+    Content, ContentPart, Function, GenerationConfig, MediaSource, Message, MessageContent, Part,
+    ResponseFormat, SafetySetting, Tool, ToolChoice, ToolMode,
+};
+pub use model::{AIProvider, Model};
+pub use response::{
+    ApiResponse, LlmStreamChunk, LlmUnifiedResponse, LlmUsage, ToolCall, ToolCallDelta,
+};
+pub use sse::{SseDecoder, SseEvent};
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+
+use crate::response::{LlmUnifiedResponse, ToolCall};
+
+/// One turn of the conversation. Besides `system`, `user` and `assistant`, the role can be
+/// `tool` for the result of a call the assistant made, identified by `tool_call_id`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub role: String,
+    #[serde(default)]
+    pub content: MessageContent,
+    /// Calls the assistant made in this turn, sent back as part of the history.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: MessageContent::Text(content.into()),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// A message mixing text with images or documents. The server rejects media the model
+    /// can't read.
+    pub fn with_parts(role: impl Into<String>, parts: Vec<ContentPart>) -> Self {
+        Self {
+            role: role.into(),
+            content: MessageContent::Parts(parts),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// The assistant turn of `response`, including any tool calls it made, to append to the
+    /// conversation before sending the calls' results.
+    pub fn assistant(response: &LlmUnifiedResponse) -> Self {
+        Self {
+            role: "assistant".to_string(),
+            content: MessageContent::Text(response.content.clone()),
+            tool_calls: response.tool_calls.clone(),
+            tool_call_id: None,
+        }
+    }
+
+    /// The result of running the tool call with id `tool_call_id`.
+    pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: "tool".to_string(),
+            content: MessageContent::Text(content.into()),
+            tool_calls: Vec::new(),
+            tool_call_id: Some(tool_call_id.into()),
+        }
+    }
+}
+
+/// Either plain text or a list of parts mixing text with images and documents.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    Image {
+        source: MediaSource,
+    },
+    /// A PDF document.
+    Document {
+        source: MediaSource,
+    },
+}
+
+/// Where the data of an image or document comes from. Not every provider can fetch URLs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MediaSource {
+    Url { url: String },
+    Base64 { media_type: String, data: String },
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        ContentPart::Text { text: text.into() }
+    }
+
+    pub fn image_url(url: impl Into<String>) -> Self {
+        ContentPart::Image {
+            source: MediaSource::Url { url: url.into() },
+        }
+    }
+
+    /// An image from base64 encoded bytes, `media_type` being e.g. `image/png`.
+    pub fn image_base64(media_type: impl Into<String>, data: impl Into<String>) -> Self {
+        ContentPart::Image {
+            source: MediaSource::Base64 {
+                media_type: media_type.into(),
+                data: data.into(),
+            },
+        }
+    }
+
+    /// A PDF from base64 encoded bytes.
+    pub fn pdf_base64(data: impl Into<String>) -> Self {
+        ContentPart::Document {
+            source: MediaSource::Base64 {
+                media_type: "application/pdf".to_string(),
+                data: data.into(),
+            },
+        }
+    }
+}
+
+impl MessageContent {
+    /// The text of the message, text parts joined by newlines and media left out.
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            MessageContent::Text(text) => text.is_empty(),
+            MessageContent::Parts(parts) => parts.is_empty(),
+        }
+    }
+
+    /// The parts of the message, plain text counts as no parts.
+    pub fn parts(&self) -> &[ContentPart] {
+        match self {
+            MessageContent::Text(_) => &[],
+            MessageContent::Parts(parts) => parts,
+        }
+    }
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        MessageContent::Text(String::new())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+impl MediaSource {
+    /// Reads a `data:<media type>;base64,<data>` URL back into base64 data, any other URL is
+    /// kept as is.
+    pub fn from_url(url: String) -> Self {
+        if let Some(rest) = url.strip_prefix("data:")
+            && let Some((media_type, data)) = rest.split_once(";base64,")
+        {
+            return MediaSource::Base64 {
+                media_type: media_type.to_string(),
+                data: data.to_string(),
+            };
+        }
+        MediaSource::Url { url }
+    }
+
+    /// The source as a URL, inlining base64 data as a `data:` URL.
+    pub fn to_url(&self) -> String {
+        match self {
+            MediaSource::Url { url } => url.clone(),
+            MediaSource::Base64 { media_type, data } => {
+                format!("data:{};base64,{}", media_type, data)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Part {
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Content {
+    pub role: String,
+    pub parts: Vec<Part>,
+}
+
+/// A function the model may call, in the OpenAI shape, `r#type` being `"function"`. Each
+/// provider translates it to its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tool {
+    pub r#type: String,
+    pub function: Function,
+}
+
+/// Whether the model may call `tools`, sent as `"auto"`, `"none"` or `"required"`, or as
+/// `{"name": ...}` to make it call that tool. A bare tool name is still accepted from older
+/// clients, as long as it isn't one of the modes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged, from = "ToolChoiceRepr")]
+pub enum ToolChoice {
+    Mode(ToolMode),
+    Tool { name: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolMode {
+    Auto,
+    None,
+    Required,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ToolChoiceRepr {
+    Mode(ToolMode),
+    Tool { name: String },
+    Name(String),
+}
+
+impl From<ToolChoiceRepr> for ToolChoice {
+    fn from(repr: ToolChoiceRepr) -> Self {
+        match repr {
+            ToolChoiceRepr::Mode(mode) => ToolChoice::Mode(mode),
+            ToolChoiceRepr::Tool { name } | ToolChoiceRepr::Name(name) => ToolChoice::Tool { name },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Function {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON schema of the arguments.
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SafetySetting {
+    pub category: String,
+    pub threshold: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GenerationConfig {
+    pub temperature: f64,
+    pub top_p: f64,
+    pub top_k: u32,
+    pub candidate_count: u32,
+    pub max_output_tokens: u32,
+    pub stop_sequences: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResponseFormat {
+    pub r#type: String,
+}
@@ -0,0 +1,340 @@
+#![allow(non_camel_case_types)]
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AIProvider {
+    OpenAI,
+    Anthropic,
+    Gemini,
+    DeepSeek,
+    Mistral,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum Model {
+    // ==== OpenAI ====
+    #[serde(rename = "GPT-4.1")]
+    Gpt4_1,
+    #[serde(rename = "GPT-4.1-Mini")]
+    Gpt4_1Mini,
+    #[serde(rename = "GPT-4.1-Nano")]
+    Gpt4_1Nano,
+    #[serde(rename = "GPT-o3")]
+    GptO3,
+    #[serde(rename = "GPT-o4-mini")]
+    GptO4Mini,
+    #[serde(rename = "GPT-o3-pro")]
+    GptO3Pro,
+    #[serde(rename = "GPT-4o")]
+    Gpt4o,
+    #[serde(rename = "GPT-4o-mini")]
+    Gpt4oMini,
+    #[serde(rename = "GPT-o1")]
+    GptO1,
+    #[serde(rename = "GPT-o3-DeepResearch")]
+    GptO3DeepResearch,
+    #[serde(rename = "GPT-o3-Mini")]
+    GptO3Mini,
+    #[serde(rename = "GPT-o1-Mini")]
+    GptO1Mini,
+    #[serde(rename = "GPT-5")]
+    GPT5,
+    #[serde(rename = "GPT-5-mini")]
+    GPT5_Mini,
+    #[serde(rename = "GPT-5-Nano")]
+    GPT5_Nano,
+    #[serde(rename = "GPT-5-Chat-Latest")]
+    GPT5_Chat_Latest,
+
+    // ==== Anthropic ====
+    #[serde(rename = "Opus-4")]
+    ClaudeOpus4,
+    #[serde(rename = "Sonnet-4")]
+    ClaudeSonnet4,
+    #[serde(rename = "Haiku-3.5")]
+    ClaudeHaiku3_5,
+    #[serde(rename = "Opus-3")]
+    ClaudeOpus3,
+    #[serde(rename = "Sonnet-3.7")]
+    ClaudeSonnet3_7,
+    #[serde(rename = "Haiku-3")]
+    ClaudeHaiku3,
+
+    // ==== DeepSeek ====
+    #[serde(rename = "DeepSeek-Reasoner")]
+    DeepSeekR1,
+    #[serde(rename = "DeepSeek-Chat")]
+    DeepSeekV3,
+
+    // ==== Gemini (Google) ====
+    #[serde(rename = "2.5-Flash-preview")]
+    Gemini25FlashPreview,
+    #[serde(rename = "2.5-Pro-preview")]
+    Gemini25ProPreview,
+    #[serde(rename = "2.0-Flash")]
+    Gemini20Flash,
+    #[serde(rename = "2.0-Flash-lite")]
+    Gemini20FlashLite,
+    #[serde(rename = "1.5-Flash")]
+    Gemini15Flash,
+    #[serde(rename = "1.5-Flash-8B")]
+    Gemini15Flash8B,
+    #[serde(rename = "1.5-Pro")]
+    Gemini15Pro,
+
+    // ==== Mistral ====
+    #[serde(rename = "Mistral-Medium-3")]
+    MistralMedium3,
+    #[serde(rename = "Magistral-Medium")]
+    MagistralMedium,
+    #[serde(rename = "Codestral")]
+    Codestral,
+    #[serde(rename = "Devstral-Medium")]
+    DevstralMedium,
+    #[serde(rename = "Mistral-Large")]
+    MistralLarge,
+    #[serde(rename = "Pixtral-Large")]
+    PixtralLarge,
+    #[serde(rename = "Ministral-8B-24.10")]
+    Ministral8B_24_10,
+    #[serde(rename = "Ministral-3B-24.10")]
+    Ministral3B_24_10,
+    #[serde(rename = "Mistral-Small-3.2")]
+    MistralSmall3_2,
+    #[serde(rename = "Magistral-Small")]
+    MagistralSmall,
+    #[serde(rename = "Devstral-Small")]
+    DevstralSmall,
+    #[serde(rename = "Pixtral-12B")]
+    Pixtral12B,
+    #[serde(rename = "Mistral-NeMo")]
+    MistralNemo,
+}
+
+impl Model {
+    pub const ALL: [Model; 44] = [
+        // ==== OpenAI ====
+        Model::Gpt4_1,
+        Model::Gpt4_1Mini,
+        Model::Gpt4_1Nano,
+        Model::GptO3,
+        Model::GptO4Mini,
+        Model::GptO3Pro,
+        Model::Gpt4o,
+        Model::Gpt4oMini,
+        Model::GptO1,
+        Model::GptO3DeepResearch,
+        Model::GptO3Mini,
+        Model::GptO1Mini,
+        Model::GPT5,
+        Model::GPT5_Mini,
+        Model::GPT5_Nano,
+        Model::GPT5_Chat_Latest,
+        // ==== Anthropic ====
+        Model::ClaudeOpus4,
+        Model::ClaudeSonnet4,
+        Model::ClaudeHaiku3_5,
+        Model::ClaudeOpus3,
+        Model::ClaudeSonnet3_7,
+        Model::ClaudeHaiku3,
+        // ==== DeepSeek ====
+        Model::DeepSeekR1,
+        Model::DeepSeekV3,
+        // ==== Gemini (Google) ====
+        Model::Gemini25FlashPreview,
+        Model::Gemini25ProPreview,
+        Model::Gemini20Flash,
+        Model::Gemini20FlashLite,
+        Model::Gemini15Flash,
+        Model::Gemini15Flash8B,
+        Model::Gemini15Pro,
+        // ==== Mistral ====
+        Model::MistralMedium3,
+        Model::MagistralMedium,
+        Model::Codestral,
+        Model::DevstralMedium,
+        Model::MistralLarge,
+        Model::PixtralLarge,
+        Model::Ministral8B_24_10,
+        Model::Ministral3B_24_10,
+        Model::MistralSmall3_2,
+        Model::MagistralSmall,
+        Model::DevstralSmall,
+        Model::Pixtral12B,
+        Model::MistralNemo,
+    ];
+
+    /// Looks a model up by its OneLLM name (`"GPT-4o"`) or by the provider's own id
+    /// (`"gpt-4o"`), so clients written against a provider's API can keep their model strings.
+    pub fn from_name(name: &str) -> Option<Model> {
+        if let Ok(model) = serde_json::from_value(serde_json::Value::from(name)) {
+            return Some(model);
+        }
+
+        Model::ALL
+            .iter()
+            .find(|m| m.name().eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            // ==== OpenAI ====
+            Model::Gpt4_1 => "gpt-4.1",
+            Model::Gpt4_1Mini => "gpt-4.1-mini",
+            Model::Gpt4_1Nano => "gpt-4.1-nano",
+            Model::GptO3 => "o3",
+            Model::GptO4Mini => "o4-mini",
+            Model::GptO3Pro => "o3-pro",
+            Model::Gpt4o => "gpt-4o",
+            Model::Gpt4oMini => "gpt-4o-mini",
+            Model::GptO1 => "o1",
+            Model::GptO3DeepResearch => "o3-deep-research",
+            Model::GptO3Mini => "o3-mini",
+            Model::GptO1Mini => "o1-mini",
+            Model::GPT5 => "gpt-5",
+            Model::GPT5_Mini => "gpt-5-mini",
+            Model::GPT5_Nano => "gpt-5-nano",
+            Model::GPT5_Chat_Latest => "gpt-5-chat-latest",
+
+            // ==== Anthropic ====
+            Model::ClaudeOpus4 => "claude-opus-4-20250514",
+            Model::ClaudeSonnet4 => "claude-sonnet-4-20250514",
+            Model::ClaudeHaiku3_5 => "claude-3-5-haiku-latest",
+            Model::ClaudeOpus3 => "claude-3-opus-20240229",
+            Model::ClaudeSonnet3_7 => "claude-3-7-sonnet-latest",
+            Model::ClaudeHaiku3 => "claude-3-haiku-20240307",
+
+            // ==== DeepSeek ====
+            Model::DeepSeekR1 => "DeepSeek-Reasoner",
+            Model::DeepSeekV3 => "DeepSeek-Chat",
+
+            // ==== Gemini (Google) ====
+            Model::Gemini25FlashPreview => "gemini-2.5-flash-preview-05-20",
+            Model::Gemini25ProPreview => "gemini-2.5-pro-preview-06-05",
+            Model::Gemini20Flash => "gemini-2.0-flash",
+            Model::Gemini20FlashLite => "gemini-2.0-flash-lite",
+            Model::Gemini15Flash => "gemini-1.5-flash",
+            Model::Gemini15Flash8B => "gemini-1.5-flash-8b",
+            Model::Gemini15Pro => "gemini-1.5-pro",
+
+            // ==== Mistral ====
+            Model::MistralMedium3 => "Mistral-Medium-2505",
+            Model::MagistralMedium => "Magistral-Medium-2506",
+            Model::Codestral => "Codestral-2501",
+            Model::DevstralMedium => "Devstral-Medium-2507",
+            Model::MistralLarge => "Mistral-Large-2411",
+            Model::PixtralLarge => "Pixtral-Large-2411",
+            Model::Ministral8B_24_10 => "Ministral-8B-2410",
+            Model::Ministral3B_24_10 => "Ministral-3B-2410",
+            Model::MistralSmall3_2 => "Mistral-Small-2506",
+            Model::MagistralSmall => "Magistral-Small-2506",
+            Model::DevstralSmall => "Devstral-Small-2507",
+            Model::Pixtral12B => "Pixtral-12B-2409",
+            Model::MistralNemo => "open-Mistral-NeMo",
+        }
+    }
+
+    pub fn provider(&self) -> AIProvider {
+        match self {
+            // ==== OpenAI ====
+            Model::Gpt4_1
+            | Model::Gpt4_1Mini
+            | Model::Gpt4_1Nano
+            | Model::GptO3
+            | Model::GptO4Mini
+            | Model::GptO3Pro
+            | Model::Gpt4o
+            | Model::Gpt4oMini
+            | Model::GptO1
+            | Model::GptO3DeepResearch
+            | Model::GptO3Mini
+            | Model::GPT5
+            | Model::GPT5_Mini
+            | Model::GPT5_Nano
+            | Model::GPT5_Chat_Latest
+            | Model::GptO1Mini => AIProvider::OpenAI,
+
+            // ==== Anthropic ====
+            Model::ClaudeOpus4
+            | Model::ClaudeSonnet4
+            | Model::ClaudeHaiku3_5
+            | Model::ClaudeOpus3
+            | Model::ClaudeSonnet3_7
+            | Model::ClaudeHaiku3 => AIProvider::Anthropic,
+
+            // ==== Gemini ====
+            Model::Gemini25FlashPreview
+            | Model::Gemini25ProPreview
+            | Model::Gemini20Flash
+            | Model::Gemini20FlashLite
+            | Model::Gemini15Flash
+            | Model::Gemini15Flash8B
+            | Model::Gemini15Pro => AIProvider::Gemini,
+
+            // ==== DeepSeek ====
+            Model::DeepSeekR1 | Model::DeepSeekV3 => AIProvider::DeepSeek,
+
+            // ==== Mistral ====
+            Model::MistralMedium3
+            | Model::MagistralMedium
+            | Model::Codestral
+            | Model::DevstralMedium
+            | Model::MistralLarge
+            | Model::PixtralLarge
+            | Model::Ministral8B_24_10
+            | Model::Ministral3B_24_10
+            | Model::MagistralSmall
+            | Model::DevstralSmall
+            | Model::Pixtral12B
+            | Model::MistralNemo
+            | Model::MistralSmall3_2 => AIProvider::Mistral,
+        }
+    }
+
+    /// Whether the model accepts images in messages.
+    pub fn supports_images(&self) -> bool {
+        match self {
+            Model::GptO3Mini | Model::GptO1Mini => false,
+            Model::DeepSeekR1 | Model::DeepSeekV3 => false,
+            Model::MistralMedium3
+            | Model::MistralSmall3_2
+            | Model::PixtralLarge
+            | Model::Pixtral12B => true,
+            _ => !matches!(self.provider(), AIProvider::Mistral),
+        }
+    }
+
+    /// Whether the model accepts PDF documents in messages.
+    pub fn supports_documents(&self) -> bool {
+        match self.provider() {
+            // Claude 3 Opus and Haiku predate PDF support
+            AIProvider::Anthropic => !matches!(self, Model::ClaudeOpus3 | Model::ClaudeHaiku3),
+            AIProvider::OpenAI => self.supports_images(),
+            AIProvider::Gemini => true,
+            AIProvider::DeepSeek | AIProvider::Mistral => false,
+        }
+    }
+}
+
+impl fmt::Display for AIProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    // The OneLLM name, exactly as serde writes it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => Err(fmt::Error),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The body of a successful `/api` response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiResponse {
+    pub code: u16,
+    pub output: LlmUnifiedResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlmUnifiedResponse {
+    pub provider: String,
+    pub model: String,
+    pub role: Option<String>,
+    #[serde(alias = "output")]
+    pub content: String,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    pub usage: Option<LlmUsage>,
+    pub finish_reason: Option<String>,
+}
+
+/// A function call requested by the model. The caller runs it and sends the result back in a
+/// `role: "tool"` message with the same `id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+/// Part of a tool call in a streamed response. The first delta of a call carries its `id` and
+/// `name`, later ones append JSON text to `arguments`. Deltas of the same call share `index`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    pub index: u32,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LlmUsage {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+}
+
+/// A single incremental piece of a streamed response. Text arrives in `delta`, the last content
+/// chunk carries `finish_reason`, and the final chunk of every stream carries `usage`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LlmStreamChunk {
+    pub provider: String,
+    pub model: String,
+    pub delta: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCallDelta>,
+    pub finish_reason: Option<String>,
+    pub usage: Option<LlmUsage>,
+}
+
+impl ToolCall {
+    /// Builds a call from arguments the provider sent as a JSON string. Arguments the model
+    /// produced that are not valid JSON are kept as a plain string.
+    pub fn from_json_arguments(id: String, name: String, arguments: &str) -> Self {
+        let arguments = serde_json::from_str(arguments)
+            .unwrap_or_else(|_| Value::String(arguments.to_string()));
+        ToolCall {
+            id,
+            name,
+            arguments,
+        }
+    }
+
+    /// The arguments as a JSON string, for providers that send them that way.
+    pub fn arguments_json(&self) -> String {
+        match &self.arguments {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
+impl LlmUsage {
+    /// Overwrites every field that `other` reports, keeping the ones it leaves out.
+    pub fn merge(&mut self, other: LlmUsage) {
+        if other.input_tokens.is_some() {
+            self.input_tokens = other.input_tokens;
+        }
+        if other.output_tokens.is_some() {
+            self.output_tokens = other.output_tokens;
+        }
+        if other.total_tokens.is_some() {
+            self.total_tokens = other.total_tokens;
+        }
+    }
+}
+
+impl LlmUnifiedResponse {
+    /// Reads a stream of chunks to the end and joins it into the same response a request
+    /// without streaming would have returned.
+    pub async fn from_stream<S, E>(stream: S) -> Result<Self, E>
+    where
+        S: Stream<Item = Result<LlmStreamChunk, E>>,
+    {
+        let mut stream = std::pin::pin!(stream);
+        let mut response = LlmUnifiedResponse {
+            provider: String::new(),
+            model: String::new(),
+            role: Some("assistant".to_string()),
+            content: String::new(),
+            tool_calls: Vec::new(),
+            usage: None,
+            finish_reason: None,
+        };
+        // (index, id, name, arguments so far) of every tool call in the stream
+        let mut calls: Vec<(u32, String, String, String)> = Vec::new();
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            response.provider = chunk.provider;
+            response.model = chunk.model;
+            if let Some(delta) = chunk.delta {
+                response.content.push_str(&delta);
+            }
+            for delta in chunk.tool_calls {
+                let position = match delta.id {
+                    Some(id) => {
+                        calls.push((
+                            delta.index,
+                            id,
+                            delta.name.unwrap_or_default(),
+                            String::new(),
+                        ));
+                        Some(calls.len() - 1)
+                    }
+                    None => calls.iter().rposition(|c| c.0 == delta.index),
+                };
+                if let (Some(i), Some(arguments)) = (position, delta.arguments) {
+                    calls[i].3.push_str(&arguments);
+                }
+            }
+            if chunk.finish_reason.is_some() {
+                response.finish_reason = chunk.finish_reason;
+            }
+            if chunk.usage.is_some() {
+                response.usage = chunk.usage;
+            }
+        }
+
+        response.tool_calls = calls
+            .into_iter()
+            .map(|(_, id, name, arguments)| ToolCall {
+                // Calls without parameters may stream no argument text at all
+                arguments: if arguments.is_empty() {
+                    Value::Object(Default::default())
+                } else {
+                    serde_json::from_str(&arguments).unwrap_or(Value::String(arguments))
+                },
+                id,
+                name,
+            })
+            .collect();
+
+        Ok(response)
+    }
+}
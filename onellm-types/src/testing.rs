@@ -0,0 +1,174 @@
+#[cfg(test)]
+mod tests {
+    use crate::{
+        APIInput, ContentPart, LlmStreamChunk, LlmUnifiedResponse, Message, MessageContent, Model,
+        SseDecoder, ToolCall, ToolCallDelta, ToolChoice, ToolMode,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn every_model_round_trips_through_its_name() {
+        for model in Model::ALL {
+            let json = serde_json::to_string(&model).unwrap();
+            assert_eq!(json, format!("\"{}\"", model), "{model:?}");
+
+            let back: Model = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, model);
+            assert_eq!(Model::from_name(&model.to_string()), Some(model.clone()));
+            assert_eq!(Model::from_name(model.name()), Some(model.clone()));
+        }
+    }
+
+    #[test]
+    fn messages_accept_text_and_parts() {
+        let text: Message =
+            serde_json::from_value(json!({"role": "user", "content": "hi"})).unwrap();
+        assert_eq!(text.content.text(), "hi");
+        assert_eq!(
+            serde_json::to_value(&text).unwrap(),
+            json!({"role": "user", "content": "hi"})
+        );
+
+        let parts = Message::with_parts(
+            "user",
+            vec![
+                ContentPart::text("look"),
+                ContentPart::image_base64("image/png", "iVBOR"),
+            ],
+        );
+        let value = serde_json::to_value(&parts).unwrap();
+        assert_eq!(value["content"][1]["source"]["type"], "base64");
+        let back: Message = serde_json::from_value(value).unwrap();
+        assert!(matches!(back.content, MessageContent::Parts(ref p) if p.len() == 2));
+
+        let call = ToolCall {
+            id: "call_1".into(),
+            name: "f".into(),
+            arguments: json!({"a": 1}),
+        };
+        let result = serde_json::to_value(Message::tool_result(&call.id, "ok")).unwrap();
+        assert_eq!(result["tool_call_id"], "call_1");
+        assert!(result.get("tool_calls").is_none());
+    }
+
+    #[test]
+    fn api_input_skips_the_endpoint_but_accepts_it() {
+        let input = APIInput::new(Model::Gpt4o, vec![Message::new("user", "hi")], 100);
+        let value = serde_json::to_value(&input).unwrap();
+        assert!(value.get("endpoint").is_none());
+        assert_eq!(value["model"], "GPT-4o");
+
+        let mut legacy = value.clone();
+        legacy["endpoint"] = json!("https://api.openai.com/v1/chat/completions");
+        let back: APIInput = serde_json::from_value(legacy).unwrap();
+        assert_eq!(back.model, Model::Gpt4o);
+        assert_eq!(back.messages[0].content.text(), "hi");
+    }
+
+    #[test]
+    fn tool_choice_keeps_modes_and_tool_names_apart() {
+        let auto: ToolChoice = serde_json::from_value(json!("auto")).unwrap();
+        assert_eq!(auto, ToolChoice::Mode(ToolMode::Auto));
+        assert_eq!(serde_json::to_value(&auto).unwrap(), json!("auto"));
+
+        // A tool may be called "auto" too, the object form says which is meant
+        let tool = ToolChoice::Tool {
+            name: "auto".into(),
+        };
+        let value = serde_json::to_value(&tool).unwrap();
+        assert_eq!(value, json!({"name": "auto"}));
+        assert_eq!(serde_json::from_value::<ToolChoice>(value).unwrap(), tool);
+
+        // Older clients send the bare name
+        let legacy: ToolChoice = serde_json::from_value(json!("get_weather")).unwrap();
+        assert_eq!(
+            legacy,
+            ToolChoice::Tool {
+                name: "get_weather".into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_collect_into_a_response() {
+        let chunk = |delta: Option<&str>, tool_calls: Vec<ToolCallDelta>| LlmStreamChunk {
+            provider: "Claude".into(),
+            model: "claude-sonnet-4-20250514".into(),
+            delta: delta.map(str::to_string),
+            tool_calls,
+            ..Default::default()
+        };
+        let chunks = vec![
+            chunk(Some("Hel"), vec![]),
+            chunk(Some("lo"), vec![]),
+            chunk(
+                None,
+                vec![ToolCallDelta {
+                    index: 1,
+                    id: Some("toolu_1".into()),
+                    name: Some("get_weather".into()),
+                    arguments: None,
+                }],
+            ),
+            chunk(
+                None,
+                vec![ToolCallDelta {
+                    index: 1,
+                    arguments: Some("{\"city\": \"Paris\"}".into()),
+                    ..Default::default()
+                }],
+            ),
+        ];
+
+        let stream = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, ()>));
+        let res = LlmUnifiedResponse::from_stream(stream).await.unwrap();
+        assert_eq!(res.content, "Hello");
+        assert_eq!(res.provider, "Claude");
+        assert_eq!(res.tool_calls[0].arguments, json!({"city": "Paris"}));
+    }
+
+    #[test]
+    fn sse_decoder_buffers_lines_split_across_chunks() {
+        let mut decoder = SseDecoder::default();
+
+        assert!(
+            decoder
+                .push(b"event: message_delta\ndata: {\"a\":")
+                .is_empty()
+        );
+        assert!(decoder.push(b"1}").is_empty());
+        let events = decoder.push(b"\n\n: ping\n\ndata: [DONE]\n\n");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event.as_deref(), Some("message_delta"));
+        assert_eq!(events[0].data, "{\"a\":1}");
+        // The comment alone isn't an event
+        assert_eq!(events[1].event, None);
+        assert_eq!(events[1].data, "[DONE]");
+    }
+
+    #[test]
+    fn sse_decoder_accepts_crlf_line_endings() {
+        let mut decoder = SseDecoder::default();
+
+        // Split between the \r and the \n as well
+        assert!(decoder.push(b"event: ping\r\ndata: 1\r").is_empty());
+        let events = decoder.push(b"\n\r\ndata:2\r\n\r\n");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event.as_deref(), Some("ping"));
+        assert_eq!(events[0].data, "1");
+        assert_eq!(events[1].data, "2");
+    }
+
+    #[test]
+    fn sse_decoder_joins_multi_line_data() {
+        let mut decoder = SseDecoder::default();
+
+        let events = decoder.push(b"data: first\ndata: second\ndata:\n\nevent: empty\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "first\nsecond\n");
+        // An event without data is dropped, and doesn't leak into the next one
+        let events = decoder.push(b"data: next\n\n");
+        assert_eq!(events[0].event, None);
+        assert_eq!(events[0].data, "next");
+    }
+}
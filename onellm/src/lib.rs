@@ -1,3 +1,5 @@
 pub mod input;
 pub mod output;
+mod stream;
+mod testing;
 pub use anyhow;
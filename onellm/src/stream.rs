@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+
+use futures_util::{
+    Stream, StreamExt,
+    stream::{self, BoxStream},
+};
+
+use onellm_types::sse::{SseDecoder, SseEvent};
+
+use crate::output::LlmStreamChunk;
+
+struct State {
+    body: BoxStream<'static, reqwest::Result<Vec<u8>>>,
+    decoder: SseDecoder,
+    pending: VecDeque<SseEvent>,
+    done: bool,
+}
+
+/// Parses the backend's SSE output into typed chunks. The stream ends at `[DONE]`, and an
+/// `error` event is surfaced as an `Err` item after which the stream stops.
+pub(crate) fn chunks(
+    response: reqwest::Response,
+) -> impl Stream<Item = anyhow::Result<LlmStreamChunk>> {
+    let state = State {
+        body: response
+            .bytes_stream()
+            .map(|b| b.map(|b| b.to_vec()))
+            .boxed(),
+        decoder: SseDecoder::default(),
+        pending: VecDeque::new(),
+        done: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return None;
+            }
+
+            if let Some(event) = state.pending.pop_front() {
+                if event.data == "[DONE]" {
+                    return None;
+                }
+
+                if event.event.as_deref() == Some("error") {
+                    state.done = true;
+                    return Some((Err(anyhow::anyhow!(event.data)), state));
+                }
+
+                let chunk = serde_json::from_str::<LlmStreamChunk>(&event.data)
+                    .map_err(anyhow::Error::from);
+                return Some((chunk, state));
+            }
+
+            match state.body.next().await {
+                Some(Ok(bytes)) => {
+                    let events = state.decoder.push(&bytes);
+                    state.pending.extend(events);
+                }
+                Some(Err(e)) => {
+                    state.done = true;
+                    return Some((Err(e.into()), state));
+                }
+                None => return None,
+            }
+        }
+    })
+}
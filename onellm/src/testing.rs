@@ -0,0 +1,87 @@
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use serde_json::json;
+
+    use crate::{input::error_of, output::LlmUnifiedResponse, stream::chunks};
+
+    /// A response whose SSE body arrives in `parts`, as if split by the network.
+    fn sse_response(parts: &[&str]) -> reqwest::Response {
+        let parts: Vec<_> = parts
+            .iter()
+            .map(|part| Ok::<_, std::io::Error>(part.as_bytes().to_vec()))
+            .collect();
+        let body = futures_util::stream::iter(parts);
+        http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(reqwest::Body::wrap_stream(body))
+            .unwrap()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn streamed_chunks_collect_into_a_response() {
+        let chunk = |value: serde_json::Value| format!("data: {}\r\n\r\n", value);
+        let first = chunk(json!({"provider": "OpenAI", "model": "gpt-4o", "delta": "Hel"}));
+        let last = chunk(json!({
+            "provider": "OpenAI", "model": "gpt-4o", "delta": "lo", "finish_reason": "stop",
+            "usage": {"input_tokens": 3, "output_tokens": 2, "total_tokens": 5}
+        }));
+        let (a, b) = first.split_at(10);
+        let body = [a, b, &last, "data: [DONE]\n\n", "data: ignored\n\n"];
+
+        let res = LlmUnifiedResponse::from_stream(chunks(sse_response(&body)))
+            .await
+            .unwrap();
+        assert_eq!(res.content, "Hello");
+        assert_eq!(res.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(res.usage.unwrap().total_tokens, Some(5));
+    }
+
+    #[tokio::test]
+    async fn error_events_end_the_stream() {
+        let stream = chunks(sse_response(&[
+            "data: {\"provider\": \"OpenAI\", \"model\": \"gpt-4o\", \"delta\": \"Hi\"}\n\n",
+            "event: error\ndata: upstream closed\n\n",
+            "data: {\"provider\": \"OpenAI\", \"model\": \"gpt-4o\", \"delta\": \"!\"}\n\n",
+        ]));
+        let items: Vec<_> = stream.collect().await;
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap().delta.as_deref(), Some("Hi"));
+        assert_eq!(
+            items[1].as_ref().unwrap_err().to_string(),
+            "upstream closed"
+        );
+    }
+
+    #[tokio::test]
+    async fn error_responses_return_the_servers_error() {
+        let body = json!({
+            "code": 402,
+            "output": {
+                "error": "This API key has reached its spending limit.",
+                "error_code": "budget_exceeded"
+            }
+        });
+        let response: reqwest::Response = http::Response::builder()
+            .status(402)
+            .body(body.to_string())
+            .unwrap()
+            .into();
+        assert_eq!(
+            error_of(response).await.to_string(),
+            "This API key has reached its spending limit. (budget_exceeded)"
+        );
+
+        let response: reqwest::Response = http::Response::builder()
+            .status(502)
+            .body("Bad Gateway")
+            .unwrap()
+            .into();
+        assert_eq!(
+            error_of(response).await.to_string(),
+            "OneLLM returned 502 Bad Gateway: Bad Gateway"
+        );
+    }
+}